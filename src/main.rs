@@ -5,8 +5,11 @@
 // except according to those terms.
 
 use anyhow::{Context, Result};
+use base64::Engine;
 use chrono::DateTime;
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use futures::StreamExt;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
 use indicatif::{ProgressBar, ProgressStyle};
 use log::{debug, error, info, trace, warn};
 use regex::Regex;
@@ -14,8 +17,13 @@ use reqwest::Client;
 use rusqlite::{Connection, OpenFlags};
 use scraper::{Html, Selector};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use sha1::Digest;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use url::Url;
 
 #[derive(Parser, Debug)]
@@ -27,37 +35,69 @@ use url::Url;
 )]
 struct Args {
     /// Phabricator URL (e.g., https://phabricator.services.mozilla.com/D12345)
-    #[arg(long, help = "Full Phabricator review URL")]
-    url: Option<String>,
+    #[arg(long, global = true, help = "Full Phabricator review URL (repeatable)")]
+    url: Vec<String>,
 
     /// Differential revision ID (with or without 'D' prefix)
     #[arg(
         long,
-        help = "Differential revision ID (with or without 'D' prefix, use with --base-url or PHABRICATOR_BASE_URL)"
+        global = true,
+        value_delimiter = ',',
+        help = "Differential revision ID (with or without 'D' prefix, use with --base-url or PHABRICATOR_BASE_URL; repeatable or comma-separated)"
     )]
-    diff_id: Option<String>,
+    diff_id: Vec<String>,
+
+    /// File listing revisions to extract, one ID or URL per line
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        help = "Read revision IDs or URLs from FILE, one per line ('#' starts a comment)"
+    )]
+    input_file: Option<String>,
 
     /// Base Phabricator URL (can also be set via PHABRICATOR_BASE_URL env var)
     #[arg(
         long,
+        global = true,
         help = "Base Phabricator URL (defaults to Mozilla's Phabricator, or set PHABRICATOR_BASE_URL env var)"
     )]
     base_url: Option<String>,
 
-    /// Phabricator API token (can also be set via PHABRICATOR_TOKEN env var)
+    /// Phabricator API token (can also be set via PHABRICATOR_TOKEN env var or ~/.arcrc)
     #[arg(
         long,
-        help = "Phabricator API token (or set PHABRICATOR_TOKEN env var)"
+        global = true,
+        help = "Phabricator API token (or set PHABRICATOR_TOKEN env var; falls back to ~/.arcrc)"
     )]
     token: Option<String>,
 
     /// Output file path (optional, defaults to stdout)
-    #[arg(long, help = "Output file path (defaults to stdout)")]
+    #[arg(long, global = true, help = "Output file path (defaults to stdout)")]
     output: Option<String>,
 
+    /// Extract the whole stack of parent/child revisions
+    #[arg(
+        long,
+        global = true,
+        help = "Also extract every parent and child revision in the stack, parents first"
+    )]
+    stack: bool,
+
+    /// Directory to write one file per revision into
+    #[arg(
+        long,
+        global = true,
+        value_name = "DIR",
+        conflicts_with = "output",
+        help = "Write one file per revision (D<id>.md or D<id>.json) into DIR instead of a combined document"
+    )]
+    output_dir: Option<String>,
+
     /// Include comments marked as "done" (marked as [DONE] in output)
     #[arg(
         long,
+        global = true,
         help = "Include comments marked as 'done' (useful for LLM verification of addressed feedback)"
     )]
     include_done: bool,
@@ -65,909 +105,4088 @@ struct Args {
     /// Dump raw web payloads to ./_phab_debug for debugging
     #[arg(
         long,
+        global = true,
         help = "Dump raw web payloads (AJAX/HTML/JSON) to ./_phab_debug for debugging"
     )]
     dump_web: bool,
-}
 
-#[derive(Debug, Serialize, Deserialize)]
-struct UserSearchResult {
-    #[serde(rename = "error_code")]
-    error_code: Option<String>,
-    #[serde(rename = "error_info")]
-    error_info: Option<String>,
-    result: Option<UserSearchData>,
-}
+    /// Output format
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = OutputFormat::Markdown,
+        help = "Output format: Markdown for reading, JSON for structured consumption"
+    )]
+    format: OutputFormat,
 
-#[derive(Debug, Serialize, Deserialize)]
-struct UserSearchData {
-    data: Vec<UserData>,
-}
+    /// Merge review actions into the general comments, chronologically
+    #[arg(
+        long,
+        global = true,
+        help = "Interleave accept/request-changes actions chronologically with general comments in one Review Timeline"
+    )]
+    interleave_actions: bool,
 
-#[derive(Debug, Serialize, Deserialize)]
-struct UserData {
-    fields: UserFields,
-}
+    /// Expand D123, T456, {F789} etc. into links with the object's title
+    #[arg(
+        long,
+        global = true,
+        help = "Resolve object references such as D123, T456 or {F789} in comments to links and titles"
+    )]
+    expand_refs: bool,
 
-#[derive(Debug, Serialize, Deserialize)]
-struct UserFields {
-    #[serde(rename = "realName")]
-    real_name: Option<String>,
-    username: Option<String>,
-}
+    /// Save files embedded with {F123} into this directory
+    #[arg(
+        long,
+        global = true,
+        value_name = "DIR",
+        help = "Download files embedded in comments ({F123}) into DIR and link to them from the output"
+    )]
+    download_attachments: Option<PathBuf>,
 
-#[derive(Debug, Serialize, Deserialize)]
-struct RevisionSearchResult {
-    #[serde(rename = "error_code")]
-    error_code: Option<String>,
-    #[serde(rename = "error_info")]
-    error_info: Option<String>,
-    result: Option<RevisionSearchData>,
-}
+    /// Write every extracted code suggestion as a unified diff
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        help = "Write all extracted code suggestions to FILE as a unified diff for git apply / hg import"
+    )]
+    suggestions_patch: Option<String>,
 
-#[derive(Debug, Serialize, Deserialize)]
-struct RevisionSearchData {
-    data: Vec<RevisionData>,
-}
+    /// Lines of source context to show around each inline comment
+    #[arg(
+        long,
+        global = true,
+        value_name = "LINES",
+        default_value_t = 0,
+        help = "Show N lines of source code around each inline comment (read from the raw diff by default, see --context-source)"
+    )]
+    context: u32,
 
-#[derive(Debug, Serialize, Deserialize)]
-struct RevisionData {
-    phid: String,
+    /// Where to read source lines for --context from
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = ContextSource::Auto,
+        help = "Source for --context lines: conduit (raw diff, token only), web (changeset HTML, needs cookies) or auto (conduit, then web)"
+    )]
+    context_source: ContextSource,
+
+    /// Browser to read Phabricator session cookies from
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = BrowserChoice::Auto,
+        help = "Browser to read session cookies from (auto picks the most recent session)"
+    )]
+    browser: BrowserChoice,
+
+    /// Local checkout to resolve inline comment locations against
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        help = "Resolve inline comments against a local git or Mercurial checkout and emit path:line references"
+    )]
+    repo: Option<PathBuf>,
+
+    /// Only keep comments by these users
+    #[arg(
+        long,
+        global = true,
+        value_name = "USERNAME",
+        help = "Only show comments by USERNAME (repeatable)"
+    )]
+    author: Vec<String>,
+
+    /// Drop comments by these users
+    #[arg(
+        long,
+        global = true,
+        value_name = "USERNAME",
+        help = "Hide comments by USERNAME, e.g. bots such as phab-bot (repeatable; unknown users are skipped)"
+    )]
+    exclude_author: Vec<String>,
+
+    /// Only keep comments by reviewers
+    #[arg(long, global = true, help = "Hide comments made by the revision's own author")]
+    reviewers_only: bool,
+
+    /// Only keep inline comments made on the latest diff
+    #[arg(
+        long,
+        global = true,
+        help = "Hide inline comments made on older diffs, whose lines may have moved"
+    )]
+    latest_diff_only: bool,
+
+    /// Only keep inline comments on matching files
+    #[arg(
+        long,
+        global = true,
+        value_name = "GLOB",
+        help = "Only show inline comments on files matching GLOB, e.g. 'dom/media/**' (repeatable)"
+    )]
+    path: Vec<String>,
+
+    /// Drop inline comments on matching files
+    #[arg(
+        long,
+        global = true,
+        value_name = "GLOB",
+        help = "Hide inline comments on files matching GLOB (repeatable)"
+    )]
+    exclude_path: Vec<String>,
+
+    /// Only keep comments made after this point
+    #[arg(
+        long,
+        global = true,
+        value_name = "WHEN",
+        help = "Only show comments made after WHEN: a Unix timestamp, an RFC 3339 date/time, YYYY-MM-DD, or diff:<ID> for when that diff was uploaded"
+    )]
+    since: Option<String>,
+
+    /// Only keep comments made since the previous --new-only run
+    #[arg(
+        long,
+        global = true,
+        conflicts_with = "since",
+        help = "Only show comments made since the previous --new-only run on each revision"
+    )]
+    new_only: bool,
+
+    /// Ignore cached responses and fetch everything again
+    #[arg(long, global = true, help = "Ignore the on-disk response cache and fetch everything again")]
+    refresh: bool,
+
+    /// Maximum number of concurrent web requests
+    #[arg(
+        long,
+        global = true,
+        value_name = "N",
+        default_value_t = 4,
+        value_parser = clap::value_parser!(u16).range(1..),
+        help = "Maximum number of changeset requests to run concurrently"
+    )]
+    concurrency: u16,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct TransactionSearchResult {
-    #[serde(rename = "error_code")]
-    error_code: Option<String>,
-    #[serde(rename = "error_info")]
-    error_info: Option<String>,
-    result: Option<TransactionSearchData>,
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Apply reviewers' code suggestions to the local checkout given by --repo
+    ApplySuggestions {
+        /// Report what would be applied without touching any file
+        #[arg(long, help = "Only report what would be applied, without writing any file")]
+        dry_run: bool,
+    },
+    /// Post a reply to a comment; inline replies are threaded under their parent
+    Reply {
+        /// Comment to reply to
+        #[arg(long, value_name = "ID", help = "Comment to reply to (comment_id in the JSON output)")]
+        comment_id: String,
+
+        /// Reply text, in Remarkup
+        #[arg(long, value_name = "TEXT", help = "Reply text (Remarkup)")]
+        message: String,
+
+        /// Allow publishing the user's other draft inline comments
+        #[arg(
+            long,
+            help = "Allow publishing your other draft inline comments on the revision along with this change"
+        )]
+        publish_drafts: bool,
+
+        #[command(flatten)]
+        mode: WriteMode,
+    },
+    /// Mark inline comments as done, or as not done with --undo
+    MarkDone {
+        /// Inline comments to mark
+        #[arg(
+            long,
+            value_name = "ID",
+            required = true,
+            value_delimiter = ',',
+            help = "Inline comment to mark as done (comment_id in the JSON output; repeatable or comma-separated)"
+        )]
+        comment_id: Vec<String>,
+
+        /// Mark the comments as not done instead
+        #[arg(long, help = "Mark the comments as not done instead")]
+        undo: bool,
+
+        /// Comment submitted along with the done marks
+        #[arg(
+            long,
+            value_name = "TEXT",
+            help = "Comment to submit along with the done marks (Remarkup)"
+        )]
+        message: Option<String>,
+
+        /// Allow publishing the user's other draft inline comments
+        #[arg(
+            long,
+            help = "Allow publishing your other draft inline comments on the revision along with this change"
+        )]
+        publish_drafts: bool,
+
+        #[command(flatten)]
+        mode: WriteMode,
+    },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct TransactionSearchData {
-    data: Vec<TransactionData>,
+/// Commands that write to Phabricator only run with an explicit choice between a preview and the real thing.
+#[derive(clap::Args, Debug)]
+#[group(required = true, multiple = false)]
+struct WriteMode {
+    /// Show what would be posted without changing anything
+    #[arg(long, help = "Show what would be posted, without changing anything on Phabricator")]
+    dry_run: bool,
+
+    /// Actually post to Phabricator
+    #[arg(long, help = "Post to Phabricator")]
+    confirm: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct TransactionData {
-    #[serde(rename = "type")]
-    transaction_type: Option<String>,
-    #[serde(rename = "authorPHID")]
-    author_phid: Option<String>,
-    #[serde(rename = "dateCreated")]
-    date_created: u64,
-    comments: Vec<CommentData>,
-    fields: Option<serde_json::Value>,
-    id: serde_json::Value,
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Markdown,
+    Json,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct CommentData {
-    content: CommentContent,
-    id: serde_json::Value,
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ContextSource {
+    Auto,
+    Conduit,
+    Web,
 }
 
+const EMPTY_INLINE_PLACEHOLDER: &str =
+    "*[Empty inline comment - likely contains a code suggestion that cannot be extracted via API]*";
+
+/// Version of the `--format json` document layout. Bump this whenever a field
+/// is renamed, removed or changes meaning so downstream tools can detect it.
+const JSON_SCHEMA_VERSION: u32 = 2;
+
 #[derive(Debug, Serialize, Deserialize)]
-struct CommentContent {
-    raw: Option<String>,
+struct RawDiffResult {
+    #[serde(rename = "error_code")]
+    error_code: Option<String>,
+    #[serde(rename = "error_info")]
+    error_info: Option<String>,
+    result: Option<String>,
 }
 
+/// One file section of a raw unified diff as returned by `differential.getrawdiff`.
 #[derive(Debug, Clone)]
-#[allow(dead_code)]
-struct Comment {
-    author: String,
-    author_phid: String,
-    date: String,
-    date_timestamp: u64,
-    content: String,
-    transaction_id: String,
-    comment_id: String,
+struct RawDiffFile {
+    old_path: Option<String>,
+    new_path: Option<String>,
+    hunks: Vec<RawDiffHunk>,
 }
 
 #[derive(Debug, Clone)]
-#[allow(dead_code)]
-struct InlineComment {
-    author: String,
-    author_phid: String,
-    date: String,
-    date_timestamp: u64,
-    content: String,
-    file_path: String,
-    line_number: u32,
-    line_length: u32,
-    diff_id: String,
-    is_done: bool,
-    transaction_id: String,
-    comment_id: String,
+struct RawDiffHunk {
+    old_start: u32,
+    old_len: u32,
+    new_start: u32,
+    new_len: u32,
+    /// Hunk body lines, each still carrying its ' ', '-' or '+' prefix
+    lines: Vec<String>,
 }
 
-#[derive(Debug, Clone)]
-#[allow(dead_code)]
-struct ReviewAction {
-    author: String,
-    author_phid: String,
-    date: String,
-    action: String,
-    comments: Vec<String>,
-    transaction_id: String,
+impl RawDiffFile {
+    fn matches_path(&self, path: &str) -> bool {
+        self.new_path.as_deref() == Some(path) || self.old_path.as_deref() == Some(path)
+    }
+
+    /// Returns (old side, new side) line maps for every line covered by a hunk.
+    fn line_maps(&self) -> (HashMap<u32, String>, HashMap<u32, String>) {
+        let mut old_lines = HashMap::new();
+        let mut new_lines = HashMap::new();
+        for hunk in &self.hunks {
+            let mut old_n = hunk.old_start;
+            let mut new_n = hunk.new_start;
+            for line in &hunk.lines {
+                let (prefix, text) = line.split_at(line.len().min(1));
+                match prefix {
+                    "-" => {
+                        old_lines.insert(old_n, text.to_string());
+                        old_n += 1;
+                    }
+                    "+" => {
+                        new_lines.insert(new_n, text.to_string());
+                        new_n += 1;
+                    }
+                    _ => {
+                        old_lines.insert(old_n, text.to_string());
+                        new_lines.insert(new_n, text.to_string());
+                        old_n += 1;
+                        new_n += 1;
+                    }
+                }
+            }
+        }
+        (old_lines, new_lines)
+    }
 }
 
-#[derive(Debug)]
-struct CommentsData {
-    general_comments: Vec<Comment>,
-    inline_comments: Vec<InlineComment>,
-    review_actions: Vec<ReviewAction>,
+/// Where a line range of one file version ended up in another.
+#[derive(Debug, PartialEq, Eq)]
+enum PortedRange {
+    Moved { line_number: u32, line_length: u32 },
+    /// Every line of the range was removed
+    Deleted,
+    /// The diffs do not cover enough of the file to tell
+    Unknown,
 }
 
-struct PhabricatorCommentExtractor {
-    base_url: String,
-    api_token: String,
-    client: Client,
-    user_cache: HashMap<String, String>,
-    current_revision_id: Option<u32>,
-    // Cache mapping for faster ref selection per comment/file
-    ref_cache_by_comment: HashMap<String, String>,
-    ref_cache_by_path: HashMap<String, String>,
-    dump_web: bool,
+/// How the known lines of one version of a file correspond to those of another,
+/// aligned on their text.
+struct LineAlignment {
+    from_lines: HashSet<u32>,
+    to_lines: BTreeSet<u32>,
+    /// Unchanged lines: line in `from` -> line in `to`
+    mapping: BTreeMap<u32, u32>,
 }
 
-#[allow(dead_code)]
-impl PhabricatorCommentExtractor {
-    fn new(base_url: String, api_token: String, dump_web: bool) -> Self {
-        let client = Client::builder()
-            .user_agent(
-                "phab-comments-to-md/0.1.0 (https://github.com/padenot/phab-comments-to-md)",
-            )
-            .build()
-            .expect("Failed to build HTTP client");
+impl LineAlignment {
+    fn new(from: &HashMap<u32, String>, to: &HashMap<u32, String>) -> Self {
+        let mut from_sorted: Vec<(&u32, &String)> = from.iter().collect();
+        from_sorted.sort_unstable_by_key(|(n, _)| **n);
+        let mut to_sorted: Vec<(&u32, &String)> = to.iter().collect();
+        to_sorted.sort_unstable_by_key(|(n, _)| **n);
+
+        let from_text: Vec<&str> = from_sorted.iter().map(|(_, t)| t.as_str()).collect();
+        let to_text: Vec<&str> = to_sorted.iter().map(|(_, t)| t.as_str()).collect();
+
+        let mut mapping = BTreeMap::new();
+        for op in similar::capture_diff_slices(similar::Algorithm::Myers, &from_text, &to_text) {
+            if let similar::DiffOp::Equal { old_index, new_index, len } = op {
+                for i in 0..len {
+                    mapping.insert(*from_sorted[old_index + i].0, *to_sorted[new_index + i].0);
+                }
+            }
+        }
 
         Self {
-            base_url: base_url.trim_end_matches('/').to_string(),
-            api_token,
-            client,
-            user_cache: HashMap::new(),
-            current_revision_id: None,
-            ref_cache_by_comment: HashMap::new(),
-            ref_cache_by_path: HashMap::new(),
-            dump_web,
+            from_lines: from.keys().copied().collect(),
+            to_lines: to.keys().copied().collect(),
+            mapping,
         }
     }
 
-    fn maybe_dump(&self, filename: &str, content: &str) {
-        if !self.dump_web { return; }
-        let dir = std::path::Path::new("./_phab_debug");
-        if let Err(e) = std::fs::create_dir_all(dir) {
-            debug!("dump: mkdir failed: {}", e);
-            return;
+    fn port(&self, line_number: u32, line_length: u32) -> PortedRange {
+        let end = line_number.saturating_add(line_length.max(1));
+        if !(line_number..end).any(|n| self.from_lines.contains(&n)) {
+            return PortedRange::Unknown;
         }
-        let sanitized = filename.replace('/', "_");
-        let path = dir.join(sanitized);
-        if let Err(e) = std::fs::write(&path, content) {
-            debug!("dump: write failed: {}", e);
+
+        let mapped: Vec<u32> = (line_number..end)
+            .filter_map(|n| self.mapping.get(&n).copied())
+            .collect();
+        if let (Some(first), Some(last)) = (mapped.iter().min(), mapped.iter().max()) {
+            return PortedRange::Moved {
+                line_number: *first,
+                line_length: last - first + 1,
+            };
+        }
+
+        // Nothing survived: it was deleted only if the code between the surrounding
+        // unchanged lines is fully known in the target, rather than outside any hunk
+        let before = self.mapping.range(..line_number).next_back().map(|(_, to)| *to);
+        let after = self.mapping.range(end..).next().map(|(_, to)| *to);
+        if before.is_none() && after.is_none() {
+            return PortedRange::Unknown;
+        }
+        let last_known = self.to_lines.last().copied().unwrap_or(0);
+        let low = before.map_or(1, |n| n + 1);
+        let high = after.unwrap_or(last_known + 1);
+        if (low..high).all(|n| self.to_lines.contains(&n)) {
+            PortedRange::Deleted
+        } else {
+            PortedRange::Unknown
         }
     }
+}
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Vcs {
+    Git,
+    Mercurial,
+}
 
-    async fn extract_firefox_cookies(&self, domain: &str) -> Result<HashMap<String, String>> {
-        // Try environment variable first for manual cookie specification
-        if let Ok(cookie_env) = std::env::var("PHABRICATOR_COOKIES") {
-            let mut cookies = HashMap::new();
-            for cookie_pair in cookie_env.split(';') {
-                let cookie_pair = cookie_pair.trim();
-                if let Some((name, value)) = cookie_pair.split_once('=') {
-                    cookies.insert(name.trim().to_string(), value.trim().to_string());
-                }
-            }
-            if cookies.contains_key("phsid") && cookies.contains_key("phusr") {
-                return Ok(cookies);
-            }
-        }
+/// A local git or Mercurial checkout that inline comments are resolved against.
+struct LocalRepo {
+    root: PathBuf,
+    vcs: Vcs,
+    /// Renamed files, old path -> new path, from recent history and the working tree
+    renames: HashMap<String, String>,
+}
+
+impl LocalRepo {
+    fn open(path: &std::path::Path) -> Result<Self> {
+        let (vcs, root) = if let Ok(root) = Self::run_in(path, "git", &["rev-parse", "--show-toplevel"]) {
+            (Vcs::Git, root)
+        } else if let Ok(root) = Self::run_in(path, "hg", &["root"]) {
+            (Vcs::Mercurial, root)
+        } else {
+            anyhow::bail!("{} is not inside a git or Mercurial checkout", path.display());
+        };
 
-        // Extract cookies directly from Firefox SQLite database
-        self.extract_cookies_from_firefox_db(domain).await
+        let mut repo = Self {
+            root: PathBuf::from(root.trim()),
+            vcs,
+            renames: HashMap::new(),
+        };
+        repo.load_renames();
+        debug!(
+            "local {:?} checkout at {} ({} renames)",
+            repo.vcs,
+            repo.root.display(),
+            repo.renames.len()
+        );
+        Ok(repo)
     }
 
-    async fn extract_cookies_from_firefox_db(
-        &self,
-        domain: &str,
-    ) -> Result<HashMap<String, String>> {
-        let profile_dir = self.find_firefox_profile_dir(domain).await?;
-        let cookies_db_path = profile_dir.join("cookies.sqlite");
-
-        if !cookies_db_path.exists() {
+    fn run_in(dir: &std::path::Path, program: &str, args: &[&str]) -> Result<String> {
+        let output = std::process::Command::new(program)
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .context(format!("Failed to run {}", program))?;
+        if !output.status.success() {
             anyhow::bail!(
-                "Firefox cookies database not found at: {}",
-                cookies_db_path.display()
+                "{} {} failed: {}",
+                program,
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
             );
         }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
 
-        // Open database in immutable mode (handle locked databases)
-        let (conn, temp_db) =
-            match Connection::open_with_flags(&cookies_db_path, OpenFlags::SQLITE_OPEN_READ_ONLY) {
-                Ok(conn) => (conn, None),
-                Err(e) if e.to_string().contains("database is locked") => {
-                    let temp_db = std::env::temp_dir()
-                        .join(format!("cookies_extract_{}.sqlite", std::process::id()));
-                    std::fs::copy(&cookies_db_path, &temp_db)?;
-                    let conn =
-                        Connection::open_with_flags(&temp_db, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
-                    (conn, Some(temp_db))
+    fn load_renames(&mut self) {
+        let commands: &[&[&str]] = match self.vcs {
+            Vcs::Git => &[
+                &["log", "-M", "--diff-filter=R", "--name-status", "--format=", "-n", "500"],
+                &["status", "--porcelain"],
+            ],
+            Vcs::Mercurial => &[
+                &["log", "-l", "500", "--template", "{file_copies % '{source}\t{name}\n'}"],
+                &["status", "-a", "-C"],
+            ],
+        };
+        let program = match self.vcs {
+            Vcs::Git => "git",
+            Vcs::Mercurial => "hg",
+        };
+        // History first, then the working tree; within each, oldest first so recent renames win
+        for args in commands.iter() {
+            let output = match Self::run_in(&self.root, program, args) {
+                Ok(output) => output,
+                Err(e) => {
+                    debug!("rename detection: {:#}", e);
+                    continue;
                 }
-                Err(e) => return Err(e.into()),
             };
-
-        let mut stmt =
-            conn.prepare("SELECT host, name, value FROM moz_cookies WHERE host LIKE ?1")?;
-
-        let domain_pattern = format!("%{}%", domain);
-        let cookie_iter = stmt.query_map([&domain_pattern], |row| {
-            Ok((
-                row.get::<_, String>(1)?, // name
-                row.get::<_, String>(2)?, // value
-            ))
-        })?;
-
-        let mut cookies = HashMap::new();
-        for cookie_result in cookie_iter {
-            let (name, value) = cookie_result?;
-            cookies.insert(name, value);
+            let mut lines: Vec<&str> = output.lines().collect();
+            lines.reverse();
+            let mut added: Option<&str> = None;
+            for line in lines {
+                match self.vcs {
+                    // "R100\told\tnew" from log, "R  old -> new" from status
+                    Vcs::Git => {
+                        let pair = if line.starts_with('R') && line.contains('\t') {
+                            line.split('\t').nth(1).zip(line.split('\t').nth(2))
+                        } else if line.get(..2).is_some_and(|xy| xy.contains('R')) {
+                            line.get(3..).and_then(|paths| paths.split_once(" -> "))
+                        } else {
+                            None
+                        };
+                        if let Some((old, new)) = pair {
+                            self.renames.insert(old.to_string(), new.to_string());
+                        }
+                    }
+                    // "source\tname" from log; "A name" followed by "  source" from status
+                    Vcs::Mercurial => {
+                        if let Some((source, name)) = line.split_once('\t') {
+                            self.renames.insert(source.to_string(), name.to_string());
+                        } else if let Some(source) = line.strip_prefix("  ") {
+                            added = Some(source);
+                        } else if let (Some(name), Some(source)) = (line.strip_prefix("A "), added.take()) {
+                            self.renames.insert(source.to_string(), name.to_string());
+                        }
+                    }
+                }
+            }
         }
+    }
 
-        // Ensure we have the required cookies
-        if !cookies.contains_key("phsid") || !cookies.contains_key("phusr") {
-            anyhow::bail!(
-                "Required cookies (phsid, phusr) not found for domain: {}. Found cookies: {:?}",
-                domain,
-                cookies.keys().collect::<Vec<_>>()
-            );
+    /// Finds `expected` (the commented lines) in the local copy of `file_path`,
+    /// preferring the occurrence closest to `line`.
+    fn locate(&self, file_path: &str, line: u32, expected: &[String]) -> LocalLocation {
+        let mut path = file_path.to_string();
+        let mut seen = HashSet::new();
+        while let Some(next) = self.renames.get(&path) {
+            if !seen.insert(path.clone()) {
+                break;
+            }
+            path = next.clone();
         }
-
-        // Clean up temporary database if used
-        if let Some(temp_path) = temp_db {
-            let _ = std::fs::remove_file(temp_path);
+        let file_moved = path != file_path;
+
+        let content = match fs::read_to_string(self.root.join(&path)) {
+            Ok(content) => content,
+            Err(_) => {
+                return LocalLocation {
+                    path,
+                    line,
+                    status: LocalStatus::Missing,
+                    file_moved,
+                }
+            }
+        };
+        if expected.is_empty() {
+            return LocalLocation {
+                path,
+                line,
+                status: LocalStatus::Unverified,
+                file_moved,
+            };
         }
 
-        Ok(cookies)
-    }
-
-    async fn find_firefox_profile_dir(&self, domain: &str) -> Result<std::path::PathBuf> {
-        let firefox_dir = if cfg!(target_os = "windows") {
-            dirs::config_dir()
-                .ok_or_else(|| anyhow::anyhow!("Could not find config directory"))?
-                .join("Mozilla")
-                .join("Firefox")
-                .join("Profiles")
-        } else if cfg!(target_os = "macos") {
-            dirs::home_dir()
-                .ok_or_else(|| anyhow::anyhow!("Could not find home directory"))?
-                .join("Library")
-                .join("Application Support")
-                .join("Firefox")
-                .join("Profiles")
-        } else {
-            // Linux and other Unix-like systems
-            dirs::home_dir()
-                .ok_or_else(|| anyhow::anyhow!("Could not find home directory"))?
-                .join(".mozilla")
-                .join("firefox")
+        let lines: Vec<&str> = content.lines().map(|l| l.trim_end()).collect();
+        let matches_at = |start: usize| {
+            expected
+                .iter()
+                .enumerate()
+                .all(|(i, e)| lines.get(start + i).is_some_and(|l| *l == e.trim_end()))
         };
-
-        if !firefox_dir.exists() {
-            anyhow::bail!("Firefox directory not found: {}", firefox_dir.display());
+        let wanted = line.saturating_sub(1) as usize;
+        let found = (0..lines.len())
+            .filter(|&start| matches_at(start))
+            .min_by_key(|&start| start.abs_diff(wanted));
+
+        let (line, status) = match found {
+            Some(start) if start == wanted => (line, LocalStatus::Matches),
+            Some(start) => (start as u32 + 1, LocalStatus::Shifted),
+            None => (line, LocalStatus::Changed),
+        };
+        LocalLocation {
+            path,
+            line,
+            status,
+            file_moved,
         }
+    }
 
-        // Find all profile directories
-        let mut profiles = Vec::new();
-        for entry in std::fs::read_dir(&firefox_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_dir() {
-                let cookies_db = path.join("cookies.sqlite");
-                if cookies_db.exists() {
-                    if let Ok(metadata) = cookies_db.metadata() {
-                        if let Ok(modified) = metadata.modified() {
-                            profiles.push((path, modified));
-                        }
-                    }
-                }
+    /// Applies every code suggestion in `extracted` to the working tree, each one
+    /// independently: a suggestion whose `-` lines do not match the file exactly is
+    /// left out without holding back the others.
+    fn apply_suggestions(&self, extracted: &[(u32, CommentsData)], dry_run: bool) -> Result<Vec<SuggestionReport>> {
+        let mut files: BTreeMap<String, LocalFile> = BTreeMap::new();
+        let mut reports = Vec::new();
+        for (diff_id, comments_data) in extracted {
+            for comment in comments_data.inline_comments.iter().filter(|c| c.is_suggestion) {
+                let outcome = self.plan_suggestion(comment, &mut files);
+                let location = match &comment.local {
+                    Some(local) => format!("{}:{}", local.path, local.line),
+                    None => format!("{}:{}", comment.file_path, comment.line_number),
+                };
+                reports.push(SuggestionReport {
+                    diff_id: *diff_id,
+                    comment_id: comment.comment_id.clone(),
+                    location,
+                    outcome,
+                });
             }
         }
 
-        if profiles.is_empty() {
-            anyhow::bail!(
-                "No Firefox profiles with cookies.sqlite found in: {}",
-                firefox_dir.display()
-            );
+        for (path, file) in files.iter_mut().filter(|(_, file)| !file.edits.is_empty()) {
+            // Bottom-up, so earlier line numbers stay valid
+            file.edits.sort_by_key(|edit| std::cmp::Reverse(edit.start));
+            for edit in &file.edits {
+                let start = edit.start as usize - 1;
+                file.lines
+                    .splice(start..start + edit.len as usize, edit.new_lines.iter().cloned());
+            }
+            if dry_run {
+                continue;
+            }
+            let mut content = file.lines.join(file.line_ending);
+            if file.trailing_newline && !file.lines.is_empty() {
+                content.push_str(file.line_ending);
+            }
+            fs::write(self.root.join(path), content).context(format!("Failed to write {}", path))?;
         }
+        Ok(reports)
+    }
 
-        // Sort by modification time (most recent first)
-        profiles.sort_by(|a, b| b.1.cmp(&a.1));
-
-        // Take the most recent profile that has the required cookies
-        let domain_pattern = format!("%{}%", domain);
+    /// Checks one suggestion against the local file and queues its edit if it still fits.
+    fn plan_suggestion(&self, comment: &InlineComment, files: &mut BTreeMap<String, LocalFile>) -> SuggestionOutcome {
+        let Some((old_lines, new_lines)) = parse_suggestion(&comment.content) else {
+            return SuggestionOutcome::Skipped("not a line-based suggestion".to_string());
+        };
+        let Some(location) = &comment.local else {
+            return SuggestionOutcome::Skipped("no location in the local checkout".to_string());
+        };
+        let len = match location.status {
+            LocalStatus::Missing => {
+                return SuggestionOutcome::Skipped(format!("{} does not exist locally", location.path))
+            }
+            LocalStatus::Changed => {
+                return SuggestionOutcome::Conflicted("the commented code has changed".to_string())
+            }
+            // `suggestionText` suggestions carry no `-` lines to check against
+            LocalStatus::Unverified if old_lines.is_empty() => {
+                return SuggestionOutcome::Skipped("the commented code could not be verified".to_string())
+            }
+            LocalStatus::Unverified if comment.is_stale => {
+                comment.ported_line_length.unwrap_or(comment.line_length).max(1)
+            }
+            _ => comment.line_length.max(1),
+        };
 
-        for (profile_path, _modified) in profiles {
-            let cookies_db = profile_path.join("cookies.sqlite");
+        let file = match files.entry(location.path.clone()) {
+            std::collections::btree_map::Entry::Occupied(entry) => entry.into_mut(),
+            std::collections::btree_map::Entry::Vacant(entry) => {
+                match fs::read_to_string(self.root.join(&location.path)) {
+                    Ok(content) => entry.insert(LocalFile::new(&content)),
+                    Err(e) => return SuggestionOutcome::Skipped(format!("cannot read {}: {}", location.path, e)),
+                }
+            }
+        };
 
-            // Try to read cookies from this profile
-            match Connection::open_with_flags(&cookies_db, OpenFlags::SQLITE_OPEN_READ_ONLY) {
-                Ok(conn) => {
-                    // Check if we can find the required cookies
-                    if let Ok(mut stmt) = conn.prepare(
-                        "SELECT COUNT(*) FROM moz_cookies WHERE host LIKE ?1 AND (name = 'phsid' OR name = 'phusr')"
-                    ) {
-                        if let Ok(count) = stmt.query_row([&domain_pattern], |row| row.get::<_, i32>(0)) {
-                            if count >= 2 {
-                                return Ok(profile_path);
-                            }
-                        }
-                    }
+        let start = location.line;
+        let Some(current) = file
+            .lines
+            .get((start as usize).saturating_sub(1)..(start + len - 1) as usize)
+            .filter(|_| start > 0)
+        else {
+            return SuggestionOutcome::Conflicted("the commented lines are past the end of the file".to_string());
+        };
+        if !old_lines.is_empty() {
+            let current: Vec<&str> = current.iter().map(|l| l.as_str()).collect();
+            match compare_old_lines(&current, &old_lines) {
+                OldLinesMatch::Exact => {}
+                OldLinesMatch::WhitespaceOnly => {
+                    return SuggestionOutcome::Unverified("its indentation or blank lines could not be recovered".to_string())
                 }
-                Err(e) => {
-                    // Check if it's a database lock error
-                    if e.to_string().contains("database is locked")
-                        || e.to_string().contains("database disk image is malformed")
-                    {
-                        // Try to copy the database and read from the copy
-                        let temp_db = std::env::temp_dir()
-                            .join(format!("cookies_temp_{}.sqlite", std::process::id()));
-                        if let Ok(_) = std::fs::copy(&cookies_db, &temp_db) {
-                            if let Ok(conn) = Connection::open_with_flags(
-                                &temp_db,
-                                OpenFlags::SQLITE_OPEN_READ_ONLY,
-                            ) {
-                                if let Ok(mut stmt) = conn.prepare(
-                                    "SELECT COUNT(*) FROM moz_cookies WHERE host LIKE ?1 AND (name = 'phsid' OR name = 'phusr')"
-                                ) {
-                                    if let Ok(count) = stmt.query_row([&domain_pattern], |row| row.get::<_, i32>(0)) {
-                                        if count >= 2 {
-                                            // Clean up temp file
-                                            let _ = std::fs::remove_file(&temp_db);
-                                            return Ok(profile_path);
-                                        }
-                                    }
-                                }
-                            }
-                            // Clean up temp file
-                            let _ = std::fs::remove_file(&temp_db);
-                        }
-                    }
+                OldLinesMatch::Different => {
+                    return SuggestionOutcome::Conflicted("the `-` lines no longer match the file".to_string())
                 }
             }
         }
+        if let Some(other) = file
+            .edits
+            .iter()
+            .find(|edit| start < edit.start + edit.len && edit.start < start + len)
+        {
+            return SuggestionOutcome::Conflicted(format!(
+                "overlaps the suggestion at line {}",
+                other.start
+            ));
+        }
 
-        anyhow::bail!("No Firefox profile found with required Phabricator cookies")
+        file.edits.push(LineEdit { start, len, new_lines });
+        SuggestionOutcome::Applied
     }
+}
 
-    async fn get_csrf_token_with_cookies(&self, revision_id: u32, domain: &str) -> Option<String> {
-        let url = format!("{}/D{}", self.base_url, revision_id);
-        let mut request_builder = self.client.get(&url);
+/// A local file being patched by `apply-suggestions`.
+struct LocalFile {
+    lines: Vec<String>,
+    line_ending: &'static str,
+    trailing_newline: bool,
+    edits: Vec<LineEdit>,
+}
 
-        // Add Firefox cookies for authentication
-        if let Ok(cookies) = self.extract_firefox_cookies(domain).await {
-            let mut cookie_string = String::new();
-            for (name, value) in cookies {
-                if !cookie_string.is_empty() {
-                    cookie_string.push_str("; ");
-                }
-                cookie_string.push_str(&format!("{}={}", name, value));
-            }
-            if !cookie_string.is_empty() {
-                request_builder = request_builder.header("Cookie", cookie_string);
-            }
+impl LocalFile {
+    fn new(content: &str) -> Self {
+        Self {
+            lines: content.lines().map(|l| l.to_string()).collect(),
+            line_ending: if content.contains("\r\n") { "\r\n" } else { "\n" },
+            trailing_newline: content.ends_with('\n'),
+            edits: Vec::new(),
         }
+    }
+}
 
-        if let Ok(response) = request_builder.send().await {
-            if let Ok(html) = response.text().await {
-                // Look for CSRF token in the HTML
-                let csrf_re = regex::Regex::new(r#"__csrf__.*?value="([^"]+)""#).unwrap();
-                if let Some(captures) = csrf_re.captures(&html) {
-                    return Some(captures.get(1)?.as_str().to_string());
-                }
+/// What `apply-suggestions` did with one suggestion.
+enum SuggestionOutcome {
+    Applied,
+    /// Not written because its exact text is unknown
+    Unverified(String),
+    Skipped(String),
+    Conflicted(String),
+}
 
-                // Alternative pattern
-                let csrf_re2 = regex::Regex::new(r#""current":"([^"]+)""#).unwrap();
-                if let Some(captures) = csrf_re2.captures(&html) {
-                    return Some(captures.get(1)?.as_str().to_string());
+struct SuggestionReport {
+    diff_id: u32,
+    comment_id: String,
+    location: String,
+    outcome: SuggestionOutcome,
+}
+
+/// Text of a rendered diff cell as it appears in the file. Phabricator displays tabs
+/// as spaces and keeps the original characters in `data-copy-text`.
+fn cell_source_text(cell: &scraper::ElementRef) -> String {
+    fn collect(element: scraper::ElementRef, text: &mut String) {
+        for child in element.children() {
+            if let Some(t) = child.value().as_text() {
+                text.push_str(t);
+            } else if let Some(child) = scraper::ElementRef::wrap(child) {
+                match child.value().attr("data-copy-text") {
+                    Some(copy) => text.push_str(copy),
+                    None => collect(child, text),
                 }
             }
         }
-        None
     }
+    let mut text = String::new();
+    collect(*cell, &mut text);
+    text.trim_end_matches(['\n', '\r']).to_string()
+}
 
-    /// Fetches JavaScript-rendered suggestions from Phabricator web interface
-    /// using authenticated AJAX requests with extracted ref parameters
-    /// Requires gzip decompression for some Phabricator instances
-    async fn fetch_suggestion_from_web(
-        &mut self,
-        revision_id: u32,
-        line_number: u32,
-        line_length: u32,
-        file_path: &str,
-        include_done: bool,
-        comment_id: &str,
-    ) -> Option<String> {
-        // Prefer fetching the changeset response that contains this comment's anchor
-        if let Some(changeset_data) = self
-            .fetch_changeset_data_for_comment(revision_id, file_path, comment_id, include_done)
-            .await
-        {
-            if let Some(s) = self.extract_suggestion_for_comment_id_from_ajax(&changeset_data, comment_id, include_done) {
-                return Some(s);
-            }
-        }
-        // Fallback to general changeset fetching + heuristics
-        if let Some(changeset_data) = self
-            .fetch_changeset_data(revision_id, Some(line_number), include_done)
-            .await
-        {
-            if let Some(s) = self.extract_suggestion_for_comment_id_from_ajax(&changeset_data, comment_id, include_done) {
-                return Some(s);
-            }
-            if let Some(suggestions) = self
-                .parse_suggestions_from_ajax(&changeset_data, line_number, line_length, file_path, include_done)
-                .await
-            {
-                return Some(suggestions);
-            }
+/// Lines with indentation and blank lines ignored, to tell a suggestion whose
+/// whitespace was lost from one whose code has changed.
+fn significant_lines<'a>(lines: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+    lines.map(|l| l.trim()).filter(|l| !l.is_empty()).collect()
+}
+
+/// How the `-` lines of a suggestion compare with the code they replace.
+#[derive(Debug, PartialEq)]
+enum OldLinesMatch {
+    Exact,
+    /// Same code, but indentation or blank lines differ: the suggestion's own
+    /// whitespace cannot be trusted either
+    WhitespaceOnly,
+    Different,
+}
+
+fn compare_old_lines(current: &[&str], old_lines: &[String]) -> OldLinesMatch {
+    if current.len() == old_lines.len() && current.iter().zip(old_lines).all(|(c, o)| c == o) {
+        OldLinesMatch::Exact
+    } else if significant_lines(current.iter().copied())
+        == significant_lines(old_lines.iter().map(|l| l.as_str()))
+    {
+        OldLinesMatch::WhitespaceOnly
+    } else {
+        OldLinesMatch::Different
+    }
+}
+
+/// Splits an extracted suggestion into the lines it replaces and their replacement.
+/// Table-based suggestions are `- old` / `+ new` lines; `suggestionText` only carries
+/// the new text, inside a "Suggested changes" block, so the old lines come back empty.
+fn parse_suggestion(content: &str) -> Option<(Vec<String>, Vec<String>)> {
+    if let Some(rest) = content.strip_prefix("**Suggested changes:**\n\n```diff\n") {
+        let text = rest.strip_suffix("\n```").unwrap_or(rest);
+        return Some((Vec::new(), text.lines().map(|l| l.to_string()).collect()));
+    }
+
+    let mut old_lines = Vec::new();
+    let mut new_lines = Vec::new();
+    for line in content.lines() {
+        // A blank line may have lost the space after its marker
+        if let Some(old) = line.strip_prefix("- ").or((line == "-").then_some("")) {
+            old_lines.push(old.to_string());
+        } else if let Some(new) = line.strip_prefix("+ ").or((line == "+").then_some("")) {
+            new_lines.push(new.to_string());
+        } else {
+            return None;
         }
-        None
     }
+    if old_lines.is_empty() && new_lines.is_empty() {
+        return None;
+    }
+    Some((old_lines, new_lines))
+}
 
-    async fn fetch_changeset_data_for_comment(
-        &mut self,
-        revision_id: u32,
-        file_path: &str,
-        comment_id: &str,
-        _include_done: bool,
-    ) -> Option<String> {
-        // Check cache first
-        if let Some(cached_ref) = self.ref_cache_by_comment.get(comment_id) {
-            if let Some(text) = self.post_changeset_and_get_text(revision_id, cached_ref).await {
-                // Verify it still contains the anchor
-                if self.changeset_contains_inline_anchor(&text, comment_id) {
-                    return Some(text);
-                }
+/// Replaces `len` lines starting at `start` with `new_lines`.
+struct LineEdit {
+    start: u32,
+    len: u32,
+    new_lines: Vec<String>,
+}
+
+/// Lines of unchanged context around each hunk of a generated patch.
+const PATCH_CONTEXT: u32 = 3;
+
+/// Renders edits to one file as a unified diff, given the file's known lines.
+/// Overlapping edits are dropped (with a warning), nearby ones share a hunk.
+fn format_file_patch(path: &str, lines: &HashMap<u32, String>, mut edits: Vec<LineEdit>) -> String {
+    edits.sort_by_key(|e| e.start);
+    let mut kept: Vec<LineEdit> = Vec::new();
+    for edit in edits {
+        if let Some(last) = kept.last() {
+            if edit.start < last.start + last.len {
+                warn!("Skipping a suggestion on {}:{} that overlaps another one", path, edit.start);
+                continue;
             }
         }
+        kept.push(edit);
+    }
 
-        // Extract ref parameters from the revision page
-        let ref_params = self.extract_ref_parameters_from_page(revision_id).await;
-        if ref_params.is_empty() { return None; }
-
-        // Try each ref and pick the one that contains this comment's inline anchor
-        for r in &ref_params {
-            if let Some(text) = self.post_changeset_and_get_text(revision_id, r).await {
-                // Dump minimal diagnostics
-                self.maybe_dump(&format!("changeset_ref_{}_anchorcheck_inline{}.json", r, comment_id), &text);
-                if self.changeset_contains_inline_anchor(&text, comment_id) {
-                    // Cache by comment and optionally by path
-                    let _ = self.ref_cache_by_comment.insert(comment_id.to_string(), r.clone());
-                    let _ = self.ref_cache_by_path.insert(file_path.to_string(), r.clone());
-                    return Some(text);
-                }
+    // Group edits whose context windows touch: (first line, end line, edits)
+    let mut groups: Vec<(u32, u32, Vec<&LineEdit>)> = Vec::new();
+    for edit in &kept {
+        let mut low = edit.start;
+        while low > 1 && edit.start - low < PATCH_CONTEXT && lines.contains_key(&(low - 1)) {
+            low -= 1;
+        }
+        let end = edit.start + edit.len;
+        let mut high = end;
+        while high - end < PATCH_CONTEXT && lines.contains_key(&high) {
+            high += 1;
+        }
+        match groups.last_mut() {
+            Some(group) if low <= group.1 => {
+                group.1 = group.1.max(high);
+                group.2.push(edit);
             }
+            _ => groups.push((low, high, vec![edit])),
         }
-
-        None
     }
 
-    fn changeset_contains_inline_anchor(&self, ajax_text: &str, comment_id: &str) -> bool {
-        let mut s = ajax_text;
-        if s.starts_with("for (;;);") { s = &s[9..]; }
-        if let Ok(json) = serde_json::from_str::<serde_json::Value>(s) {
-            if let Some(payload) = json.get("payload") {
-                if let Some(changeset_html) = payload.get("changeset") {
-                    if let Some(h) = changeset_html.as_str() {
-                        let cid = comment_id.trim_matches('"');
-                        let anchor_id = format!("id=\"inline-{}\"", cid);
-                        let anchor_name = format!("name=\"inline-{}\"", cid);
-                        let cell_anchor = format!("id=\"anchor-inline-{}\"", cid);
-                        return h.contains(&anchor_id) || h.contains(&anchor_name) || h.contains(&cell_anchor);
+    let mut patch = vec![format!("--- a/{}", path), format!("+++ b/{}", path)];
+    let mut offset: i64 = 0;
+    for (low, high, group_edits) in groups {
+        let mut body = Vec::new();
+        let mut n = low;
+        let mut edits = group_edits.into_iter().peekable();
+        while n < high {
+            match edits.next_if(|e| e.start == n) {
+                Some(edit) => {
+                    for i in 0..edit.len {
+                        body.push(format!("-{}", lines.get(&(n + i)).map(|l| l.as_str()).unwrap_or_default()));
                     }
+                    body.extend(edit.new_lines.iter().map(|l| format!("+{}", l)));
+                    n += edit.len;
+                }
+                None => {
+                    body.push(format!(" {}", lines.get(&n).map(|l| l.as_str()).unwrap_or_default()));
+                    n += 1;
                 }
             }
         }
-        false
-    }
 
-    async fn post_changeset_and_get_text(&self, revision_id: u32, ref_param: &str) -> Option<String> {
-        // Get domain + CSRF (requires cookies)
-        let domain = if let Ok(parsed_url) = Url::parse(&self.base_url) {
-            parsed_url.host_str().unwrap_or("phabricator.services.mozilla.com").to_string()
-        } else { "phabricator.services.mozilla.com".to_string() };
-        let csrf_token = self.get_csrf_token_with_cookies(revision_id, &domain).await.unwrap_or_else(|| "dummy".to_string());
+        let old_len = body.iter().filter(|l| !l.starts_with('+')).count() as i64;
+        let new_len = body.iter().filter(|l| !l.starts_with('-')).count() as i64;
+        let new_start = low as i64 + offset;
+        // An empty side starts at the line before it, per the unified diff format
+        let old_start = if old_len == 0 { low as i64 - 1 } else { low as i64 };
+        let new_start = if new_len == 0 { new_start - 1 } else { new_start };
+        patch.push(format!("@@ -{},{} +{},{} @@", old_start, old_len, new_start, new_len));
+        patch.extend(body);
+        offset += new_len - old_len;
+    }
+    patch.join("\n")
+}
 
-        let changeset_url = format!("{}/differential/changeset/", self.base_url);
-        let headers = [
-            ("User-Agent", "Mozilla/5.0 (X11; Linux x86_64; rv:142.0) Gecko/20100101 Firefox/142.0"),
-            ("Accept", "*/*"),
-            ("Accept-Language", "en-US,en;q=0.5"),
-            ("Accept-Encoding", "gzip, deflate, br"),
-            ("X-Phabricator-Csrf", &csrf_token),
-            ("X-Phabricator-Via", &format!("/D{}", revision_id)),
-            ("Content-Type", "application/x-www-form-urlencoded"),
-            ("Origin", &self.base_url),
-            ("Connection", "keep-alive"),
-            ("Sec-Fetch-Dest", "empty"),
-            ("Sec-Fetch-Mode", "cors"),
-            ("Sec-Fetch-Site", "same-origin"),
-        ];
-        let form_data = [("ref", ref_param), ("device", "2up"), ("__wflow__", "true"), ("__ajax__", "true"), ("__metablock__", "2")];
+/// A file embedded in a comment (`{F123}`) and saved by `--download-attachments`.
+#[derive(Debug, Clone, Serialize)]
+struct Attachment {
+    /// File name on Phabricator
+    name: String,
+    /// Where the file was saved
+    path: PathBuf,
+    /// Link to the saved file, relative to the Markdown document
+    #[serde(skip)]
+    link: String,
+}
 
-        let mut request = self.client.post(&changeset_url);
-        for (k, v) in headers.iter() { request = request.header(*k, *v); }
-        match request.form(&form_data).send().await { Ok(resp) => match resp.text().await { Ok(t) => Some(t), Err(_) => None }, Err(_) => None }
+impl Attachment {
+    /// An image tag for pictures, so multimodal readers see them inline; a link otherwise.
+    fn to_markdown(&self, name: &str) -> String {
+        let is_image = self
+            .name
+            .rsplit_once('.')
+            .is_some_and(|(_, ext)| {
+                ["png", "jpg", "jpeg", "gif", "webp", "svg", "bmp"].contains(&ext.to_ascii_lowercase().as_str())
+            });
+        let link = if self.link.contains([' ', '(', ')']) {
+            format!("<{}>", self.link)
+        } else {
+            self.link.clone()
+        };
+        format!(
+            "{}[{}: {}]({})",
+            if is_image { "!" } else { "" },
+            name,
+            escape_link_text(&self.name),
+            link
+        )
     }
+}
 
-    async fn get_changeset_ids(&self, revision_id: u32) -> Vec<String> {
-        // First get the changeset IDs from the differential API
-        let revision_phid = match self.get_revision_phid(revision_id).await {
-            Ok(phid) => phid,
-            Err(_) => return Vec::new(),
-        };
-
-        // Get transactions to find diff information
-        let transactions = match self.get_transactions(&revision_phid).await {
-            Ok(trans) => trans,
-            Err(_) => return Vec::new(),
-        };
-
-        let mut changeset_ids = Vec::new();
+/// An object a comment mentions by monogram, resolved through `phid.lookup`.
+#[derive(Debug, Clone, Serialize)]
+struct ObjectReference {
+    uri: String,
+    /// e.g. "Differential Revision", "Maniphest Task" or "File"
+    type_name: String,
+    title: Option<String>,
+}
 
-        // Look for differential diff information in transactions
-        for transaction in transactions {
-            if let Some(fields) = transaction.fields {
-                // Check for diff field that might contain changeset information
-                if let Some(diff_field) = fields.get("diff") {
-                    if let Some(diff_obj) = diff_field.as_object() {
-                        if let Some(id) = diff_obj.get("id") {
-                            if let Some(id_str) = id.as_str() {
-                                changeset_ids.push(id_str.to_string());
-                            } else if let Some(id_num) = id.as_u64() {
-                                changeset_ids.push(id_num.to_string());
-                            }
-                        }
-                    }
-                }
-            }
+impl ObjectReference {
+    /// `[D123](uri) (title)`, keeping a `#comment` anchor on the link.
+    fn to_markdown(&self, name: &str, anchor: &str) -> String {
+        let link = format!("[{}{}]({}{})", name, anchor, self.uri, anchor);
+        match &self.title {
+            Some(title) => format!("{} ({})", link, escape_link_text(title)),
+            None => link,
         }
+    }
+}
 
-        // If we didn't find changeset IDs in transactions, try the direct diff API
-        if changeset_ids.is_empty() {
-            if let Some(diff_id) = self.get_latest_diff_id(revision_id).await {
-                changeset_ids.push(diff_id);
-            }
+/// Backslash-escapes the characters that would end a Markdown link label or a
+/// parenthesized title early.
+fn escape_link_text(text: &str) -> String {
+    text.chars().fold(String::new(), |mut escaped, c| {
+        if "\\[]()".contains(c) {
+            escaped.push('\\');
         }
+        escaped.push(c);
+        escaped
+    })
+}
 
-        changeset_ids
+/// Rewrites the matches of `re` in `text` with `on_match`, and the text between
+/// them with `on_gap`.
+fn replace_matches(
+    re: &Regex,
+    text: &str,
+    on_match: impl Fn(&regex::Captures) -> String,
+    on_gap: impl Fn(&str) -> String,
+) -> String {
+    let mut out = String::new();
+    let mut last = 0;
+    for caps in re.captures_iter(text) {
+        let m = caps.get(0).unwrap();
+        out.push_str(&on_gap(&text[last..m.start()]));
+        out.push_str(&on_match(&caps));
+        last = m.end();
     }
+    out.push_str(&on_gap(&text[last..]));
+    out
+}
 
-    async fn get_latest_diff_id(&self, revision_id: u32) -> Option<String> {
-        // Try to get the latest diff ID by searching for diffs of this revision
-        let url = format!("{}/api/differential.diff.search", self.base_url);
-        let params = [
-            ("api.token", self.api_token.as_str()),
-            ("constraints[revisionIDs][0]", &revision_id.to_string()),
-            ("order", "newest"),
-            ("limit", "1"),
-        ];
+/// Converts Phabricator Remarkup, the markup comment bodies are written in, to CommonMark.
+struct Remarkup {
+    base_url: String,
+    code_options_re: Regex,
+    header_re: Regex,
+    list_re: Regex,
+    notice_re: Regex,
+    code_span_re: Regex,
+    link_re: Regex,
+    embed_re: Regex,
+    italic_re: Regex,
+    highlight_re: Regex,
+    mention_re: Regex,
+    monogram_re: Regex,
+    // Markdown links already written out by an earlier pass
+    markdown_link_re: Regex,
+    /// Objects resolved by `--expand-refs`, keyed by monogram
+    references: HashMap<String, ObjectReference>,
+    /// Files saved by `--download-attachments`, keyed by monogram
+    attachments: HashMap<String, Attachment>,
+}
 
-        match self.client.post(&url).form(&params).send().await {
-            Ok(response) => {
-                if let Ok(result) = response.json::<serde_json::Value>().await {
-                    if let Some(data) = result
-                        .get("result")
-                        .and_then(|r| r.get("data"))
-                        .and_then(|d| d.as_array())
-                    {
-                        if let Some(first_diff) = data.first() {
-                            if let Some(diff_id) = first_diff.get("id") {
-                                if let Some(id_str) = diff_id.as_str() {
-                                    return Some(id_str.to_string());
-                                } else if let Some(id_num) = diff_id.as_u64() {
-                                    return Some(id_num.to_string());
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-            Err(_) => {}
+impl Remarkup {
+    fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            code_options_re: Regex::new(
+                r"^\s*(?:lang|name|lines|counterexample|highlight)(?:=[^,]*)?(?:\s*,\s*(?:lang|name|lines|counterexample|highlight)(?:=[^,]*)?)*\s*$",
+            )
+            .unwrap(),
+            header_re: Regex::new(r"^(={1,6})\s*(.*?)\s*=*\s*$").unwrap(),
+            list_re: Regex::new(r"^(\s*)(-+|\*+|#+)\s+(.*)$").unwrap(),
+            notice_re: Regex::new(r"^(?:(NOTE|WARNING|IMPORTANT):|\((NOTE|WARNING|IMPORTANT)\))\s*").unwrap(),
+            code_span_re: Regex::new(r"`[^`]+`|##([^#]+?)##").unwrap(),
+            link_re: Regex::new(r"\[\[\s*([^|\]]+?)\s*(?:\|\s*([^\]]*?)\s*)?\]\]").unwrap(),
+            embed_re: Regex::new(r"\{([DFMPT]\d+)(?:\s*,[^}]*)?\}").unwrap(),
+            italic_re: Regex::new(r"(^|[^:/\w])//([^/\s](?:[^/]*[^/\s])?)//").unwrap(),
+            highlight_re: Regex::new(r"!!([^!\s](?:[^!]*[^!\s])?)!!").unwrap(),
+            mention_re: Regex::new(r"(^|[^\w@.\[/])@([A-Za-z0-9_-](?:[A-Za-z0-9._-]*[A-Za-z0-9_-])?)").unwrap(),
+            monogram_re: Regex::new(r"(^|[^\w/#.\[-])([DFMPQT]\d+|r[A-Z]+[0-9a-f]*)(#\d+)?\b").unwrap(),
+            markdown_link_re: Regex::new(
+                r"!?\[(?:\\.|[^\]\\])*\]\((?:\\.|[^)\\])*\)|<[a-z][a-z0-9+.-]*:[^>\s]*>",
+            )
+            .unwrap(),
+            references: HashMap::new(),
+            attachments: HashMap::new(),
         }
-        None
     }
 
-    /// Extracts ref parameters from Phabricator revision page HTML for AJAX requests
-    async fn extract_ref_parameters_from_page(&self, revision_id: u32) -> Vec<String> {
-        let url = format!("{}/D{}", self.base_url, revision_id);
-
-        // Try to extract Firefox cookies for authentication
-        let domain = if let Ok(parsed_url) = Url::parse(&self.base_url) {
-            parsed_url
-                .host_str()
-                .unwrap_or("phabricator.services.mozilla.com")
-                .to_string()
-        } else {
-            "phabricator.services.mozilla.com".to_string()
-        };
-
-        let mut request_builder = self.client.get(&url);
-
-        // Add Firefox cookies if available
-        if let Ok(cookies) = self.extract_firefox_cookies(&domain).await {
-            if !cookies.is_empty() {
-                let mut cookie_string = String::new();
-                for (name, value) in cookies {
-                    if !cookie_string.is_empty() {
-                        cookie_string.push_str("; ");
+    fn to_markdown(&self, text: &str) -> String {
+        let mut out: Vec<String> = Vec::new();
+        let mut lines = text.lines().peekable();
+        let mut after_blank = true;
+        while let Some(line) = lines.next() {
+            let trimmed = line.trim_start();
+
+            // ```[lang=cpp, name=foo.cpp] ... ```, options possibly on the first line inside
+            if let Some(rest) = trimmed.strip_prefix("```") {
+                let mut code = Vec::new();
+                let mut lang = None;
+                let mut closed = false;
+                if let Some(one_line) = rest.strip_suffix("```") {
+                    code.push(one_line.to_string());
+                    closed = true;
+                } else if self.code_options_re.is_match(rest) {
+                    lang = self.code_lang(rest);
+                } else if !rest.trim().is_empty() {
+                    code.push(rest.to_string());
+                }
+                if !closed && code.is_empty() && lang.is_none() {
+                    if let Some(options) = lines.next_if(|l| self.code_options_re.is_match(l)) {
+                        lang = self.code_lang(options);
                     }
-                    cookie_string.push_str(&format!("{}={}", name, value));
                 }
-                request_builder = request_builder.header("Cookie", cookie_string);
-            }
-        }
-
-        match request_builder.send().await {
-            Ok(response) => {
-                if let Ok(html) = response.text().await {
-                    // Extract all ref parameters from the HTML using regex
-                    let re = regex::Regex::new(r#"ref=(\d+)"#).unwrap();
-                    let mut refs = Vec::new();
-
-                    for captures in re.captures_iter(&html) {
-                        if let Some(ref_match) = captures.get(1) {
-                            let ref_value = ref_match.as_str().to_string();
-                            if !refs.contains(&ref_value) {
-                                refs.push(ref_value);
+                while !closed {
+                    let Some(line) = lines.next() else { break };
+                    match line.trim_end().strip_suffix("```") {
+                        Some(last) => {
+                            if !last.trim().is_empty() {
+                                code.push(last.to_string());
                             }
+                            closed = true;
                         }
+                        None => code.push(line.to_string()),
                     }
+                }
+                self.push_code_block(&mut out, lang, &code);
+                after_blank = false;
+                continue;
+            }
 
-                    // If no refs found with simple pattern, try more comprehensive search
-                    if refs.is_empty() {
-                        // Try looking for refs in various JavaScript formats
-                        let patterns = vec![
-                            r#""ref":"(\d+)""#,        // JSON: "ref":"123456"
-                            r#"'ref':\s*'(\d+)'"#,     // JS: 'ref': '123456'
-                            r#"ref:\s*'(\d+)'"#,       // JS: ref: '123456'
-                            r#"ref:\s*(\d+)"#,         // JS: ref: 123456
-                            r#"\bC(\d{7,8})[ON]L\d+"#, // HTML IDs like C8450617OL1, C8450617NL1
-                        ];
-
-                        for pattern in patterns {
-                            let re = regex::Regex::new(pattern).unwrap();
-                            for captures in re.captures_iter(&html) {
-                                if let Some(ref_match) = captures.get(1) {
-                                    let ref_value = ref_match.as_str().to_string();
-                                    if !refs.contains(&ref_value) && ref_value.len() >= 7 {
-                                        refs.push(ref_value);
-                                    }
-                                }
-                            }
-                        }
-
-                        // Look for changeset IDs in JavaScript data or JSON (7-8 digit numbers)
-                        if refs.is_empty() {
-                            let js_re = regex::Regex::new(r"\b\d{7,8}\b").unwrap();
-                            for js_match in js_re.find_iter(&html) {
-                                let ref_value = js_match.as_str().to_string();
-                                if !refs.contains(&ref_value) {
-                                    refs.push(ref_value);
-                                }
-                            }
-                        }
-
-                        // Try to find them in differential/ URLs specifically
-                        let diff_re =
-                            regex::Regex::new(r"differential/changeset/[^?]*\?[^&]*ref=(\d+)")
-                                .unwrap();
-                        for captures in diff_re.captures_iter(&html) {
-                            if let Some(ref_match) = captures.get(1) {
-                                let ref_value = ref_match.as_str().to_string();
-                                if !refs.contains(&ref_value) {
-                                    refs.push(ref_value);
-                                }
-                            }
+            // %%%literal%%% text keeps its characters, escaped so Markdown shows them as-is
+            if let Some(rest) = trimmed.strip_prefix("%%%") {
+                let mut literal = vec![rest.to_string()];
+                if !rest.ends_with("%%%") || rest.len() < 3 {
+                    for line in lines.by_ref() {
+                        literal.push(line.to_string());
+                        if line.trim_end().ends_with("%%%") {
+                            break;
                         }
                     }
-
-                    debug!("found {} ref ids on revision page", refs.len());
-                    refs
-                } else {
-                    Vec::new()
                 }
-            }
-            Err(_) => Vec::new(),
-        }
-    }
-
-    async fn fetch_changeset_with_refs(
-        &self,
-        revision_id: u32,
-        ref_params: &[String],
-        target_line: Option<u32>,
-        _include_done: bool,
-    ) -> Option<String> {
-        // Get domain for Firefox cookies
-        let domain = if let Ok(parsed_url) = Url::parse(&self.base_url) {
-            parsed_url
-                .host_str()
-                .unwrap_or("phabricator.services.mozilla.com")
-                .to_string()
-        } else {
-            "phabricator.services.mozilla.com".to_string()
-        };
-
-        // Get CSRF token first (this also needs cookies)
-        let csrf_token = self
-            .get_csrf_token_with_cookies(revision_id, &domain)
-            .await
-            .unwrap_or_else(|| "dummy".to_string());
-
-        // Set up the AJAX request similar to the curl command
-        let changeset_url = format!("{}/differential/changeset/", self.base_url);
-
-        let headers = [
-            (
-                "User-Agent",
-                "Mozilla/5.0 (X11; Linux x86_64; rv:142.0) Gecko/20100101 Firefox/142.0",
-            ),
-            ("Accept", "*/*"),
-            ("Accept-Language", "en-US,en;q=0.5"),
-            ("Accept-Encoding", "gzip, deflate, br"),
-            ("X-Phabricator-Csrf", &csrf_token),
-            ("X-Phabricator-Via", &format!("/D{}", revision_id)),
-            ("Content-Type", "application/x-www-form-urlencoded"),
-            ("Origin", &self.base_url),
-            ("Connection", "keep-alive"),
-            ("Sec-Fetch-Dest", "empty"),
-            ("Sec-Fetch-Mode", "cors"),
-            ("Sec-Fetch-Site", "same-origin"),
-        ];
-
-        // Try each ref parameter and prioritize those with suggestionText
-        let mut best_response: Option<String> = None;
-        let mut best_score: i64 = -1; // higher is better in this scheme
-
-        for ref_param in ref_params {
-            let form_data = [
-                ("ref", ref_param.as_str()),
-                ("device", "1up"),
-                ("__wflow__", "true"),
-                ("__ajax__", "true"),
-                ("__metablock__", "7"),
-            ];
-
-            let mut request = self.client.post(&changeset_url);
-            for (key, value) in headers.iter() {
-                request = request.header(*key, *value);
+                let literal = literal.join("\n");
+                let literal = literal.trim_end().trim_end_matches("%%%");
+                out.push(literal.chars().fold(String::new(), |mut escaped, c| {
+                    if "\\`*_[]<>#|~!".contains(c) {
+                        escaped.push('\\');
+                    }
+                    escaped.push(c);
+                    escaped
+                }));
+                after_blank = false;
+                continue;
             }
 
-            // Add Firefox cookies for authentication
-            if let Ok(cookies) = self.extract_firefox_cookies(&domain).await {
-                let mut cookie_string = String::new();
-                for (name, value) in cookies {
-                    if !cookie_string.is_empty() {
-                        cookie_string.push_str("; ");
-                    }
-                    cookie_string.push_str(&format!("{}={}", name, value));
+            // Lines indented by two or more spaces after a blank line are a code block
+            if after_blank && line.starts_with("  ") && !self.list_re.is_match(line) {
+                let mut block = vec![line];
+                while let Some(next) = lines.next_if(|l| l.starts_with("  ") || l.trim().is_empty()) {
+                    block.push(next);
                 }
-                if !cookie_string.is_empty() {
-                    request = request.header("Cookie", cookie_string);
+                while block.last().is_some_and(|l| l.trim().is_empty()) {
+                    block.pop();
                 }
+                let indent = block
+                    .iter()
+                    .filter(|l| !l.trim().is_empty())
+                    .map(|l| l.len() - l.trim_start().len())
+                    .min()
+                    .unwrap_or(0);
+                let mut code: Vec<String> = block.iter().map(|l| l.get(indent..).unwrap_or("").to_string()).collect();
+                let lang = match code.first() {
+                    Some(first) if self.code_options_re.is_match(first) => {
+                        let lang = self.code_lang(first);
+                        code.remove(0);
+                        lang
+                    }
+                    _ => None,
+                };
+                self.push_code_block(&mut out, lang, &code);
+                out.push(String::new());
+                after_blank = true;
+                continue;
             }
 
-            match request.form(&form_data).send().await {
-                Ok(response) => {
-                    // Capture response details before consuming the response
-                    if let Ok(text) = response.text().await {
-                        // dump and log characteristics
-                        let has_suggestion_text = text.contains("suggestionText");
-                        let has_inline_view   = text.contains("inline-suggestion-view");
-                        let has_inline_comment= text.contains("differential-inline-comment");
-                        self.maybe_dump(&format!("changeset_ref_{}_sug{}_view{}_inline{}.json",
-                            ref_param, has_suggestion_text as u8, has_inline_view as u8, has_inline_comment as u8), &text);
-                        debug!("ref {}: suggestion_text={} inline_view={} inline_comment={}",
-                               ref_param, has_suggestion_text, has_inline_view, has_inline_comment);
-
-                        // Prefer responses whose nearest suggestion is closer to target_line
-                        let mut score: i64 = 0;
-                        if has_suggestion_text { score += 100; }
-                        if has_inline_view { score += 10; }
-                        if has_inline_comment { score += 1; }
-
-                        if let Some(tline) = target_line {
-                            // Try to compute distance to nearest inline suggestion anchor
-                            let mut response_html_opt: Option<String> = None;
-                            let mut s = text.as_str();
-                            if s.starts_with("for (;;);") { s = &s[9..]; }
-                            if let Ok(json) = serde_json::from_str::<serde_json::Value>(s) {
-                                if let Some(payload) = json.get("payload") {
-                                    if let Some(changeset_html) = payload.get("changeset") {
-                                        if let Some(h) = changeset_html.as_str() {
-                                            response_html_opt = Some(h.to_string());
-                                        }
-                                    }
-                                }
-                            }
-                            if let Some(html) = response_html_opt {
-                                // Extract candidates and compute nearest distance
-                                let mut best_dist: i64 = i64::MAX;
-                                let mut idx = 0usize;
-                                let anchor_re = regex::Regex::new(r"C\d{7,8}[ON]L(\d+)").ok();
-                                while let Some(pos) = html[idx..].find("inline-suggestion-view") {
-                                    let abs_pos = idx + pos;
-                                    // search backward for closest anchor
-                                    let back_start = abs_pos.saturating_sub(8000);
-                                    if let Some(re) = &anchor_re {
-                                        let back_slice = &html[back_start..abs_pos];
-                                        let mut last_line: Option<u32> = None;
-                                        for cap in re.captures_iter(back_slice) {
-                                            if let Some(m) = cap.get(1) {
-                                                if let Ok(n) = m.as_str().parse::<u32>() { last_line = Some(n); }
-                                            }
-                                        }
-                                        if let Some(n) = last_line {
-                                            let d = (n as i64 - tline as i64).abs();
-                                            if d < best_dist { best_dist = d; }
-                                        }
-                                    }
-                                    idx = abs_pos + 1;
-                                }
-                                if best_dist != i64::MAX { score += 1_000_000 - best_dist; }
-                            }
-                        }
+            after_blank = line.trim().is_empty();
+            out.push(self.convert_line(line));
+        }
+        out.join("\n")
+    }
 
-                        if score > best_score {
-                            best_score = score;
-                            best_response = Some(text);
-                        }
-                    }
-                }
-                Err(_) => continue,
-            }
+    /// Block-level constructs of a single line: headers, lists, quotes and notices.
+    fn convert_line(&self, line: &str) -> String {
+        // Comment bodies sit under the document's own headings, so theirs start lower
+        if let Some(caps) = self.header_re.captures(line).filter(|caps| !caps[2].is_empty()) {
+            let level = (caps[1].len() + 3).min(6);
+            return format!("{} {}", "#".repeat(level), self.convert_inline(&caps[2]));
+        }
+        // `-`/`*` bullets and `#` numbered items, nested by repeating the marker
+        if let Some(caps) = self.list_re.captures(line) {
+            let marker = &caps[2];
+            let depth = marker.len() - 1 + caps[1].len() / 2;
+            let text = self.convert_inline(&caps[3]);
+            return match marker.starts_with('#') {
+                true => format!("{}1. {}", "   ".repeat(depth), text),
+                false => format!("{}- {}", "  ".repeat(depth), text),
+            };
+        }
+        // `>>! In D123#456, @user wrote:` introduces a quoted reply
+        if let Some(rest) = line.strip_prefix(">>!") {
+            return format!("> *{}*", self.convert_inline(rest.trim()));
         }
+        if let Some(caps) = self.notice_re.captures(line) {
+            let kind = caps.get(1).or(caps.get(2)).map(|m| m.as_str()).unwrap_or_default();
+            let rest = &line[caps[0].len()..];
+            return format!("**{}:** {}", kind, self.convert_inline(rest));
+        }
+        self.convert_inline(line)
+    }
 
-        if let Some(response) = best_response {
-            return Some(response);
+    /// Inline markup, leaving code spans alone.
+    fn convert_inline(&self, text: &str) -> String {
+        let mut out = String::new();
+        let mut last = 0;
+        for caps in self.code_span_re.captures_iter(text) {
+            let span = caps.get(0).unwrap();
+            out.push_str(&self.convert_plain(&text[last..span.start()]));
+            match caps.get(1) {
+                Some(monospace) => out.push_str(&format!("`{}`", monospace.as_str())),
+                None => out.push_str(span.as_str()),
+            }
+            last = span.end();
         }
+        out.push_str(&self.convert_plain(&text[last..]));
+        out
+    }
 
-        None
+    fn convert_plain(&self, text: &str) -> String {
+        let text = self.italic_re.replace_all(text, "$1*$2*");
+        let text = self.highlight_re.replace_all(&text, "**$1**");
+        // Later passes only look outside the links of earlier ones: CommonMark has no
+        // nested links. Embeds and monograms share the last pass, so neither rescans
+        // the titles the other writes.
+        let text = replace_matches(&self.link_re, &text, |caps| self.convert_link(caps), str::to_string);
+        let text = self.outside_links(&text, |plain| {
+            self.mention_re
+                .replace_all(plain, |caps: &regex::Captures| {
+                    format!("{}[@{}]({}/p/{}/)", &caps[1], &caps[2], self.base_url, &caps[2])
+                })
+                .into_owned()
+        });
+        self.outside_links(&text, |plain| {
+            replace_matches(&self.embed_re, plain, |caps| self.convert_embed(caps), |rest| self.expand_monograms(rest))
+        })
     }
 
-    async fn fetch_changeset_data(&self, revision_id: u32, target_line: Option<u32>, include_done: bool) -> Option<String> {
-        // First try to extract ref parameters from the initial page
-        let ref_params = self.extract_ref_parameters_from_page(revision_id).await;
+    fn outside_links(&self, text: &str, convert: impl Fn(&str) -> String) -> String {
+        replace_matches(&self.markdown_link_re, text, |caps| caps[0].to_string(), convert)
+    }
 
-        if !ref_params.is_empty() {
-            // Use the extracted ref parameters directly
-            if let Some(result) = self
-                .fetch_changeset_with_refs(revision_id, &ref_params, target_line, include_done)
-                .await
-            {
-                return Some(result);
-            }
+    /// `[[target | label]]`
+    fn convert_link(&self, caps: &regex::Captures) -> String {
+        let target = &caps[1];
+        let url = if target.contains("://") || target.starts_with("mailto:") {
+            target.to_string()
+        } else if target.starts_with('/') {
+            format!("{}{}", self.base_url, target)
+        } else {
+            // Anything else names a Phriction wiki page
+            format!("{}/w/{}/", self.base_url, target.trim_matches('/'))
+        };
+        match caps.get(2).map(|m| m.as_str()).filter(|l| !l.is_empty()) {
+            Some(label) => format!("[{}]({})", label, url),
+            None if url == target => format!("<{}>", url),
+            None => format!("[{}]({})", target, url),
         }
+    }
 
-        // Fallback: Get the actual changeset IDs the old way
-        let changeset_ids = self.get_changeset_ids(revision_id).await;
+    /// `{F123}` and `{D456}`
+    fn convert_embed(&self, caps: &regex::Captures) -> String {
+        if let Some(attachment) = self.attachments.get(&caps[1]) {
+            return attachment.to_markdown(&caps[1]);
+        }
+        match self.references.get(&caps[1]) {
+            Some(reference) => reference.to_markdown(&caps[1], ""),
+            None => format!("[{}]({}/{})", &caps[1], self.base_url, &caps[1]),
+        }
+    }
 
-        if changeset_ids.is_empty() {
-            return None;
+    /// Bare `D123` or `T456#7`, linked only once `--expand-refs` resolved them.
+    fn expand_monograms(&self, text: &str) -> String {
+        if self.references.is_empty() {
+            return text.to_string();
         }
+        self.monogram_re
+            .replace_all(text, |caps: &regex::Captures| match self.references.get(&caps[2]) {
+                Some(reference) => format!(
+                    "{}{}",
+                    &caps[1],
+                    reference.to_markdown(&caps[2], caps.get(3).map(|m| m.as_str()).unwrap_or_default())
+                ),
+                None => caps[0].to_string(),
+            })
+            .into_owned()
+    }
 
-        // Get CSRF token first
-        let csrf_token = self
-            .get_csrf_token(revision_id)
-            .await
-            .unwrap_or_else(|| "dummy".to_string());
+    /// IDs of the files embedded in `text` with `{F123}`.
+    fn embedded_files(&self, text: &str) -> Vec<u64> {
+        self.embed_re
+            .captures_iter(text)
+            .filter_map(|caps| caps[1].strip_prefix('F')?.parse().ok())
+            .collect()
+    }
 
-        // Set up the AJAX request similar to the curl command
-        let changeset_url = format!("{}/differential/changeset/", self.base_url);
+    /// Monograms such as `D123` or `{F456}` mentioned in `text`, for `phid.lookup`.
+    fn monograms<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        self.monogram_re
+            .captures_iter(text)
+            .filter_map(|caps| caps.get(2).map(|m| m.as_str()))
+            .collect()
+    }
 
-        let headers = [
+    fn code_lang(&self, options: &str) -> Option<String> {
+        options
+            .split(',')
+            .filter_map(|option| option.trim().strip_prefix("lang="))
+            .map(|lang| lang.trim().to_string())
+            .find(|lang| !lang.is_empty())
+    }
+
+    fn push_code_block(&self, out: &mut Vec<String>, lang: Option<String>, code: &[String]) {
+        // A longer fence keeps backticks inside the code from closing the block
+        let fence = if code.iter().any(|l| l.trim_start().starts_with("```")) {
+            "````"
+        } else {
+            "```"
+        };
+        out.push(format!("{}{}", fence, lang.unwrap_or_default()));
+        out.extend(code.iter().cloned());
+        out.push(fence.to_string());
+    }
+}
+
+/// The parts of Arcanist's `~/.arcrc` we care about.
+#[derive(Debug, Deserialize)]
+struct ArcRc {
+    #[serde(default)]
+    hosts: HashMap<String, ArcRcHost>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ArcRcHost {
+    token: Option<String>,
+}
+
+impl ArcRc {
+    /// Hosts are keyed by their API endpoint, e.g. `https://example.com/api/`;
+    /// case, trailing slashes and the `/api` suffix are ignored.
+    fn token_for(self, base_url: &str) -> Option<String> {
+        let normalize = |url: &str| {
+            let url = url.trim().trim_end_matches('/');
+            url.strip_suffix("/api").unwrap_or(url).to_lowercase()
+        };
+        let wanted = normalize(base_url);
+        self.hosts
+            .into_iter()
+            .find(|(host, _)| normalize(host) == wanted)
+            .and_then(|(_, entry)| entry.token)
+            .filter(|token| !token.is_empty())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct UserSearchResult {
+    #[serde(rename = "error_code")]
+    error_code: Option<String>,
+    #[serde(rename = "error_info")]
+    error_info: Option<String>,
+    result: Option<UserSearchData>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct UserSearchData {
+    data: Vec<UserData>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct UserData {
+    phid: Option<String>,
+    fields: UserFields,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct UserFields {
+    #[serde(rename = "realName")]
+    real_name: Option<String>,
+    username: Option<String>,
+}
+
+impl UserFields {
+    /// "Real Name (username)", or whichever of the two is set.
+    fn display_name(&self) -> Option<String> {
+        let real_name = self.real_name.as_deref().unwrap_or("");
+        let username = self.username.as_deref().unwrap_or("");
+        match (real_name.is_empty(), username.is_empty()) {
+            (false, false) => Some(format!("{} ({})", real_name, username)),
+            (false, true) => Some(real_name.to_string()),
+            (true, false) => Some(username.to_string()),
+            (true, true) => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RevisionSearchResult {
+    #[serde(rename = "error_code")]
+    error_code: Option<String>,
+    #[serde(rename = "error_info")]
+    error_info: Option<String>,
+    result: Option<RevisionSearchData>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RevisionSearchData {
+    data: Vec<RevisionData>,
+    cursor: Option<CursorData>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RevisionData {
+    id: u32,
+    phid: String,
+    fields: Option<RevisionFields>,
+    attachments: Option<RevisionAttachments>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RevisionFields {
+    #[serde(rename = "authorPHID")]
+    author_phid: Option<String>,
+    title: Option<String>,
+    summary: Option<String>,
+    #[serde(rename = "testPlan")]
+    test_plan: Option<String>,
+    status: Option<RevisionStatus>,
+    #[serde(rename = "repositoryPHID")]
+    repository_phid: Option<String>,
+    /// Mozilla's custom field; a string or null
+    #[serde(rename = "bugzilla.bug-id")]
+    bug_id: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RevisionStatus {
+    value: Option<String>,
+    name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RevisionAttachments {
+    reviewers: Option<ReviewersAttachment>,
+    subscribers: Option<SubscribersAttachment>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ReviewersAttachment {
+    reviewers: Vec<ReviewerData>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ReviewerData {
+    #[serde(rename = "reviewerPHID")]
+    reviewer_phid: String,
+    status: Option<String>,
+    #[serde(rename = "isBlocking")]
+    is_blocking: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SubscribersAttachment {
+    #[serde(rename = "subscriberPHIDs")]
+    subscriber_phids: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DiffSearchResult {
+    #[serde(rename = "error_code")]
+    error_code: Option<String>,
+    #[serde(rename = "error_info")]
+    error_info: Option<String>,
+    result: Option<DiffSearchData>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DiffSearchData {
+    data: Vec<DiffData>,
+    cursor: Option<CursorData>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DiffData {
+    id: u32,
+    phid: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EdgeSearchResult {
+    #[serde(rename = "error_code")]
+    error_code: Option<String>,
+    #[serde(rename = "error_info")]
+    error_info: Option<String>,
+    result: Option<EdgeSearchData>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EdgeSearchData {
+    data: Vec<EdgeData>,
+    cursor: Option<CursorData>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EdgeData {
+    #[serde(rename = "sourcePHID")]
+    source_phid: String,
+    #[serde(rename = "edgeType")]
+    edge_type: String,
+    #[serde(rename = "destinationPHID")]
+    destination_phid: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TransactionSearchResult {
+    #[serde(rename = "error_code")]
+    error_code: Option<String>,
+    #[serde(rename = "error_info")]
+    error_info: Option<String>,
+    result: Option<TransactionSearchData>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TransactionSearchData {
+    data: Vec<TransactionData>,
+    cursor: Option<CursorData>,
+}
+
+/// Pagination cursor returned by Conduit `*.search` methods.
+#[derive(Debug, Serialize, Deserialize)]
+struct CursorData {
+    limit: Option<serde_json::Value>,
+    after: Option<String>,
+    before: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TransactionData {
+    #[serde(rename = "type")]
+    transaction_type: Option<String>,
+    #[serde(rename = "authorPHID")]
+    author_phid: Option<String>,
+    #[serde(rename = "dateCreated")]
+    date_created: u64,
+    comments: Vec<CommentData>,
+    fields: Option<serde_json::Value>,
+    id: serde_json::Value,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CommentData {
+    content: CommentContent,
+    id: serde_json::Value,
+    phid: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CommentContent {
+    raw: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Comment {
+    author: String,
+    author_phid: String,
+    date: String,
+    date_timestamp: u64,
+    content: String,
+    transaction_id: String,
+    comment_id: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct InlineComment {
+    author: String,
+    author_phid: String,
+    date: String,
+    date_timestamp: u64,
+    content: String,
+    file_path: String,
+    line_number: u32,
+    line_length: u32,
+    diff_id: String,
+    is_done: bool,
+    transaction_id: String,
+    comment_id: String,
+    comment_phid: String,
+    /// PHID of the inline comment this one replies to, if any
+    reply_to_comment_phid: Option<String>,
+    /// Source lines around the commented range (empty unless `--context` is set)
+    code_context: Vec<CodeContextLine>,
+    /// Older comment kept only because a newer reply answers it (`--since`/`--new-only`)
+    is_context: bool,
+    /// 1-based position of `diff_id` among the revision's diffs
+    diff_index: Option<u32>,
+    /// Made on an older diff, so its line numbers may no longer match the code
+    is_stale: bool,
+    /// `content` holds a code suggestion extracted from the web UI
+    is_suggestion: bool,
+    /// Where a stale comment's lines are in the latest diff, when they could be traced
+    ported_line_number: Option<u32>,
+    ported_line_length: Option<u32>,
+    /// The commented code no longer exists in the latest diff
+    is_orphaned: bool,
+    /// Where the commented code is in the local checkout (`--repo`)
+    local: Option<LocalLocation>,
+}
+
+/// An inline comment's location in the local checkout.
+#[derive(Debug, Clone, Serialize)]
+struct LocalLocation {
+    path: String,
+    line: u32,
+    status: LocalStatus,
+    /// The file was renamed since the comment was made
+    file_moved: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum LocalStatus {
+    /// The commented text is at the expected line
+    Matches,
+    /// The commented text was found at another line
+    Shifted,
+    /// The commented text is no longer in the file
+    Changed,
+    /// The diff did not include the commented text, so it could not be checked
+    Unverified,
+    /// The file does not exist locally
+    Missing,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct CodeContextLine {
+    line_number: u32,
+    text: String,
+    /// Whether this line falls inside the inline comment's range
+    commented: bool,
+}
+
+/// An inline comment together with the replies made to it, in chronological order.
+struct InlineThread<'a> {
+    comment: &'a InlineComment,
+    replies: Vec<InlineThread<'a>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ReviewAction {
+    author: String,
+    author_phid: String,
+    date: String,
+    date_timestamp: u64,
+    action: String,
+    comments: Vec<String>,
+    transaction_id: String,
+}
+
+/// A change to the revision other than a comment or a review decision.
+#[derive(Debug, Clone, Serialize)]
+struct RevisionEvent {
+    author: String,
+    author_phid: String,
+    date: String,
+    date_timestamp: u64,
+    transaction_id: String,
+    #[serde(flatten)]
+    kind: EventKind,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+enum EventKind {
+    Create,
+    /// A new diff was uploaded
+    Update {
+        old_diff_phid: Option<String>,
+        new_diff_phid: Option<String>,
+        old_diff_id: Option<u32>,
+        new_diff_id: Option<u32>,
+    },
+    Reviewers {
+        added: Vec<String>,
+        removed: Vec<String>,
+    },
+    Status {
+        old: Option<String>,
+        new: Option<String>,
+    },
+    Close {
+        commit_phids: Vec<String>,
+    },
+    Abandon,
+    Reclaim,
+    Reopen,
+    Commandeer,
+    PlanChanges,
+    Title {
+        old: Option<String>,
+        new: Option<String>,
+    },
+    Summary {
+        old: Option<String>,
+        new: Option<String>,
+    },
+    TestPlan {
+        old: Option<String>,
+        new: Option<String>,
+    },
+}
+
+impl EventKind {
+    /// Builds the event for a transaction type that carries no reviewer list.
+    fn from_transaction(transaction_type: &str, fields: &serde_json::Value) -> Option<Self> {
+        let text = |name: &str| fields.get(name).and_then(|v| v.as_str()).map(|v| v.to_string());
+        Some(match transaction_type {
+            "create" => EventKind::Create,
+            "update" => EventKind::Update {
+                old_diff_phid: text("old"),
+                new_diff_phid: text("new"),
+                old_diff_id: None,
+                new_diff_id: None,
+            },
+            "status" => EventKind::Status { old: text("old"), new: text("new") },
+            "close" => EventKind::Close {
+                commit_phids: fields
+                    .get("commitPHIDs")
+                    .and_then(|v| v.as_array())
+                    .map(|phids| {
+                        phids.iter().filter_map(|p| p.as_str()).map(|p| p.to_string()).collect()
+                    })
+                    .unwrap_or_default(),
+            },
+            "abandon" => EventKind::Abandon,
+            "reclaim" => EventKind::Reclaim,
+            "reopen" => EventKind::Reopen,
+            "commandeer" => EventKind::Commandeer,
+            "plan-changes" => EventKind::PlanChanges,
+            "title" => EventKind::Title { old: text("old"), new: text("new") },
+            "summary" => EventKind::Summary { old: text("old"), new: text("new") },
+            "testPlan" => EventKind::TestPlan { old: text("old"), new: text("new") },
+            _ => return None,
+        })
+    }
+
+    /// PHIDs of the reviewers a `reviewers` transaction adds or removes.
+    fn reviewer_phids(fields: &serde_json::Value) -> Vec<&str> {
+        fields
+            .get("operations")
+            .and_then(|o| o.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|operation| operation.get("phid").and_then(|p| p.as_str()))
+            .collect()
+    }
+
+    /// Builds the event for a `reviewers` transaction. Reviewers missing from
+    /// `names` are shown by PHID.
+    fn from_reviewer_operations(fields: &serde_json::Value, names: &HashMap<String, String>) -> Option<Self> {
+        let mut added = Vec::new();
+        let mut removed = Vec::new();
+        for operation in fields.get("operations").and_then(|o| o.as_array()).into_iter().flatten() {
+            let Some(phid) = operation.get("phid").and_then(|p| p.as_str()) else {
+                continue;
+            };
+            let mut name = names.get(phid).cloned().unwrap_or_else(|| phid.to_string());
+            if operation.get("isBlocking").and_then(|b| b.as_bool()) == Some(true) {
+                name.push_str(" (blocking)");
+            }
+            match operation.get("operation").and_then(|o| o.as_str()) {
+                Some("add") => added.push(name),
+                Some("remove") => removed.push(name),
+                _ => {}
+            }
+        }
+        if added.is_empty() && removed.is_empty() {
+            return None;
+        }
+        Some(EventKind::Reviewers { added, removed })
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            EventKind::Create => "Revision created",
+            EventKind::Update { .. } => "Diff updated",
+            EventKind::Reviewers { .. } => "Reviewers changed",
+            EventKind::Status { .. } => "Status changed",
+            EventKind::Close { .. } => "Revision closed",
+            EventKind::Abandon => "Revision abandoned",
+            EventKind::Reclaim => "Revision reclaimed",
+            EventKind::Reopen => "Revision reopened",
+            EventKind::Commandeer => "Revision commandeered",
+            EventKind::PlanChanges => "Changes planned",
+            EventKind::Title { .. } => "Title changed",
+            EventKind::Summary { .. } => "Summary edited",
+            EventKind::TestPlan { .. } => "Test plan edited",
+        }
+    }
+}
+
+/// One item of the Markdown "Review Timeline".
+enum TimelineEntry<'a> {
+    Comment(&'a Comment),
+    Action(&'a ReviewAction),
+    Event(&'a RevisionEvent),
+}
+
+impl TimelineEntry<'_> {
+    fn timestamp(&self) -> u64 {
+        match self {
+            TimelineEntry::Comment(comment) => comment.date_timestamp,
+            TimelineEntry::Action(action) => action.date_timestamp,
+            TimelineEntry::Event(event) => event.date_timestamp,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CommentsData {
+    general_comments: Vec<Comment>,
+    inline_comments: Vec<InlineComment>,
+    review_actions: Vec<ReviewAction>,
+    events: Vec<RevisionEvent>,
+    /// IDs of every diff uploaded to the revision, oldest first
+    diffs: Vec<u32>,
+    /// Newest transaction on the revision, recorded by `--new-only`
+    #[serde(skip)]
+    latest_transaction_id: Option<u64>,
+    revision: Option<RevisionInfo>,
+    /// Objects mentioned in the comments, keyed by monogram (`--expand-refs`)
+    references: BTreeMap<String, ObjectReference>,
+    /// Embedded files saved locally, keyed by monogram (`--download-attachments`)
+    attachments: BTreeMap<String, Attachment>,
+}
+
+/// What the revision is and who still has to approve it.
+#[derive(Debug, Clone, Serialize)]
+struct RevisionInfo {
+    title: String,
+    author: String,
+    author_phid: String,
+    status: String,
+    summary: String,
+    test_plan: String,
+    bug_id: Option<String>,
+    repository: Option<String>,
+    reviewers: Vec<ReviewerInfo>,
+    subscribers: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ReviewerInfo {
+    name: String,
+    phid: String,
+    /// e.g. `added`, `accepted`, `rejected`, `blocking`, `resigned`
+    status: String,
+    is_blocking: bool,
+}
+
+/// Author and path filters applied to every kind of comment.
+struct CommentFilter {
+    authors: Option<HashSet<String>>,
+    excluded_authors: HashSet<String>,
+    reviewers_only: bool,
+    latest_diff_only: bool,
+    paths: Option<GlobSet>,
+    excluded_paths: Option<GlobSet>,
+}
+
+impl CommentFilter {
+    fn is_empty(&self) -> bool {
+        self.authors.is_none()
+            && self.excluded_authors.is_empty()
+            && !self.reviewers_only
+            && !self.latest_diff_only
+            && self.paths.is_none()
+            && self.excluded_paths.is_none()
+    }
+
+    fn keeps_author(&self, author_phid: &str, revision_author_phid: Option<&str>) -> bool {
+        if self.reviewers_only && revision_author_phid == Some(author_phid) {
+            return false;
+        }
+        if self.excluded_authors.contains(author_phid) {
+            return false;
+        }
+        self.authors
+            .as_ref()
+            .is_none_or(|authors| authors.contains(author_phid))
+    }
+
+    fn keeps_path(&self, path: Option<&str>) -> bool {
+        match path {
+            Some(path) => {
+                self.paths.as_ref().is_none_or(|globs| globs.is_match(path))
+                    && !self
+                        .excluded_paths
+                        .as_ref()
+                        .is_some_and(|globs| globs.is_match(path))
+            }
+            // Only inline comments belong to a file
+            None => self.paths.is_none(),
+        }
+    }
+
+    fn apply(&self, comments_data: &mut CommentsData) {
+        let revision_author = comments_data.revision.as_ref().map(|r| r.author_phid.clone());
+        let revision_author = revision_author.as_deref();
+        comments_data.general_comments.retain(|c| {
+            self.keeps_author(&c.author_phid, revision_author) && self.keeps_path(None)
+        });
+        comments_data.inline_comments.retain(|c| {
+            self.keeps_author(&c.author_phid, revision_author)
+                && self.keeps_path(Some(&c.file_path))
+                && !(self.latest_diff_only && c.is_stale)
+        });
+        comments_data.review_actions.retain(|a| {
+            self.keeps_author(&a.author_phid, revision_author) && self.keeps_path(None)
+        });
+        comments_data.events.retain(|e| {
+            self.keeps_author(&e.author_phid, revision_author) && self.keeps_path(None)
+        });
+    }
+}
+
+/// Where incremental output starts; anything at or before it is dropped.
+#[derive(Debug, Clone, Copy)]
+enum Since {
+    Timestamp(u64),
+    Transaction(u64),
+}
+
+/// `--since` as given on the command line, before diffs are resolved to timestamps.
+#[derive(Debug, Clone, Copy)]
+enum SinceSpec {
+    Timestamp(u64),
+    Diff(u32),
+}
+
+impl CommentsData {
+    /// Every Remarkup text of the revision: comments, review action comments, summary and test plan.
+    fn remarkup_texts(&self) -> Vec<&str> {
+        let mut texts: Vec<&str> = Vec::new();
+        texts.extend(self.general_comments.iter().map(|c| c.content.as_str()));
+        texts.extend(
+            self.inline_comments
+                .iter()
+                .filter(|c| !c.is_suggestion)
+                .map(|c| c.content.as_str()),
+        );
+        texts.extend(self.review_actions.iter().flat_map(|a| a.comments.iter().map(|c| c.as_str())));
+        if let Some(revision) = &self.revision {
+            texts.push(&revision.summary);
+            texts.push(&revision.test_plan);
+        }
+        texts
+    }
+
+    /// Records the revision's diffs and labels each inline comment with its diff version.
+    fn set_diffs(&mut self, diffs: Vec<u32>) {
+        let latest = diffs.last().copied();
+        for comment in &mut self.inline_comments {
+            let diff_id = comment.diff_id.trim_matches('"').parse::<u32>().ok();
+            comment.diff_index = diff_id
+                .and_then(|id| diffs.iter().position(|d| *d == id))
+                .map(|index| index as u32 + 1);
+            comment.is_stale = diff_id.is_some() && diff_id != latest;
+        }
+        self.diffs = diffs;
+    }
+
+    /// Drops everything at or before `since`. Older inline comments that newer
+    /// replies answer are kept, flagged `is_context`, so the replies still make sense.
+    fn retain_since(&mut self, since: Since) {
+        let is_new = |timestamp: u64, transaction_id: &str| match since {
+            Since::Timestamp(cutoff) => timestamp > cutoff,
+            Since::Transaction(last_seen) => transaction_id
+                .parse::<u64>()
+                .map_or(true, |id| id > last_seen),
+        };
+
+        self.general_comments
+            .retain(|c| is_new(c.date_timestamp, &c.transaction_id));
+        self.review_actions
+            .retain(|a| is_new(a.date_timestamp, &a.transaction_id));
+        self.events
+            .retain(|e| is_new(e.date_timestamp, &e.transaction_id));
+
+        let by_phid: HashMap<&str, usize> = self
+            .inline_comments
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| !c.comment_phid.is_empty())
+            .map(|(i, c)| (c.comment_phid.as_str(), i))
+            .collect();
+        let mut keep: Vec<bool> = self
+            .inline_comments
+            .iter()
+            .map(|c| is_new(c.date_timestamp, &c.transaction_id))
+            .collect();
+        let mut context = vec![false; keep.len()];
+        for i in 0..keep.len() {
+            if !keep[i] || context[i] {
+                continue;
+            }
+            let mut parent = self.inline_comments[i].reply_to_comment_phid.as_deref();
+            while let Some(&idx) = parent.and_then(|phid| by_phid.get(phid)) {
+                if keep[idx] {
+                    break;
+                }
+                keep[idx] = true;
+                context[idx] = true;
+                parent = self.inline_comments[idx].reply_to_comment_phid.as_deref();
+            }
+        }
+
+        let mut index = 0;
+        self.inline_comments.retain_mut(|c| {
+            let (kept, is_context) = (keep[index], context[index]);
+            index += 1;
+            c.is_context = is_context;
+            kept
+        });
+    }
+}
+
+/// Top-level document emitted by `--format json`, with the same shape whether it
+/// holds one revision or several.
+#[derive(Debug, Serialize)]
+struct JsonDocument<'a> {
+    schema_version: u32,
+    base_url: &'a str,
+    revisions: Vec<JsonReport<'a>>,
+}
+
+/// One revision of a `--format json` document.
+#[derive(Debug, Serialize)]
+struct JsonReport<'a> {
+    revision_id: u32,
+    url: String,
+    #[serde(flatten)]
+    comments: &'a CommentsData,
+}
+
+/// A local browser whose cookie store can provide a Phabricator session
+/// (`phsid`/`phusr`), so web-only endpoints can be queried as the user.
+trait CookieProvider: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Returns the cookies for `domain` from the most recently used profile that
+    /// holds a Phabricator session, together with when that cookie store was last written.
+    fn find_session(&self, domain: &str) -> Result<(HashMap<String, String>, SystemTime)>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum BrowserChoice {
+    /// Use whichever supported browser has the most recent Phabricator session
+    Auto,
+    Firefox,
+    Chrome,
+    Chromium,
+    Brave,
+}
+
+impl BrowserChoice {
+    fn providers(self) -> Vec<Box<dyn CookieProvider>> {
+        match self {
+            BrowserChoice::Auto => vec![
+                Box::new(FirefoxCookieProvider),
+                Box::new(ChromiumCookieProvider::chrome()),
+                Box::new(ChromiumCookieProvider::chromium()),
+                Box::new(ChromiumCookieProvider::brave()),
+            ],
+            BrowserChoice::Firefox => vec![Box::new(FirefoxCookieProvider)],
+            BrowserChoice::Chrome => vec![Box::new(ChromiumCookieProvider::chrome())],
+            BrowserChoice::Chromium => vec![Box::new(ChromiumCookieProvider::chromium())],
+            BrowserChoice::Brave => vec![Box::new(ChromiumCookieProvider::brave())],
+        }
+    }
+}
+
+/// Opens a browser cookie database read-only. Browsers keep the database locked
+/// while running, in which case a temporary copy is opened instead; the returned
+/// path (if any) must be removed by the caller once done.
+fn open_cookie_db(path: &std::path::Path) -> Result<(Connection, Option<PathBuf>)> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    // Opening succeeds lazily; the lock only shows up on the first query
+    match conn.query_row("SELECT COUNT(*) FROM sqlite_master", [], |row| row.get::<_, i64>(0)) {
+        Ok(_) => Ok((conn, None)),
+        Err(e)
+            if e.to_string().contains("database is locked")
+                || e.to_string().contains("database disk image is malformed") =>
+        {
+            drop(conn);
+            static COPIES: AtomicUsize = AtomicUsize::new(0);
+            let temp_db = std::env::temp_dir().join(format!(
+                "cookies_extract_{}_{}.sqlite",
+                std::process::id(),
+                COPIES.fetch_add(1, Ordering::Relaxed)
+            ));
+            std::fs::copy(path, &temp_db)?;
+            let conn = Connection::open_with_flags(&temp_db, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+            Ok((conn, Some(temp_db)))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn has_phabricator_session(cookies: &HashMap<String, String>) -> bool {
+    cookies.contains_key("phsid") && cookies.contains_key("phusr")
+}
+
+struct FirefoxCookieProvider;
+
+impl CookieProvider for FirefoxCookieProvider {
+    fn name(&self) -> &str {
+        "Firefox"
+    }
+
+    fn find_session(&self, domain: &str) -> Result<(HashMap<String, String>, SystemTime)> {
+        let profile_dir = self.find_firefox_profile_dir(domain)?;
+        let cookies_db_path = profile_dir.join("cookies.sqlite");
+        let modified = cookies_db_path.metadata()?.modified()?;
+        let cookies = self.extract_cookies_from_firefox_db(&cookies_db_path, domain)?;
+        Ok((cookies, modified))
+    }
+}
+
+impl FirefoxCookieProvider {
+    fn extract_cookies_from_firefox_db(
+        &self,
+        cookies_db_path: &std::path::Path,
+        domain: &str,
+    ) -> Result<HashMap<String, String>> {
+        if !cookies_db_path.exists() {
+            anyhow::bail!(
+                "Firefox cookies database not found at: {}",
+                cookies_db_path.display()
+            );
+        }
+
+        let (conn, temp_db) = open_cookie_db(cookies_db_path)?;
+
+        let mut cookies = HashMap::new();
+        {
+            let mut stmt =
+                conn.prepare("SELECT host, name, value FROM moz_cookies WHERE host LIKE ?1")?;
+
+            let domain_pattern = format!("%{}%", domain);
+            let cookie_iter = stmt.query_map([&domain_pattern], |row| {
+                Ok((
+                    row.get::<_, String>(1)?, // name
+                    row.get::<_, String>(2)?, // value
+                ))
+            })?;
+
+            for cookie_result in cookie_iter {
+                let (name, value) = cookie_result?;
+                cookies.insert(name, value);
+            }
+        }
+
+        // Clean up temporary database if used
+        drop(conn);
+        if let Some(temp_path) = temp_db {
+            let _ = std::fs::remove_file(temp_path);
+        }
+
+        // Ensure we have the required cookies
+        if !has_phabricator_session(&cookies) {
+            anyhow::bail!(
+                "Required cookies (phsid, phusr) not found for domain: {}. Found cookies: {:?}",
+                domain,
+                cookies.keys().collect::<Vec<_>>()
+            );
+        }
+
+        Ok(cookies)
+    }
+
+    fn find_firefox_profile_dir(&self, domain: &str) -> Result<PathBuf> {
+        let firefox_dir = if cfg!(target_os = "windows") {
+            dirs::config_dir()
+                .ok_or_else(|| anyhow::anyhow!("Could not find config directory"))?
+                .join("Mozilla")
+                .join("Firefox")
+                .join("Profiles")
+        } else if cfg!(target_os = "macos") {
+            dirs::home_dir()
+                .ok_or_else(|| anyhow::anyhow!("Could not find home directory"))?
+                .join("Library")
+                .join("Application Support")
+                .join("Firefox")
+                .join("Profiles")
+        } else {
+            // Linux and other Unix-like systems
+            dirs::home_dir()
+                .ok_or_else(|| anyhow::anyhow!("Could not find home directory"))?
+                .join(".mozilla")
+                .join("firefox")
+        };
+
+        if !firefox_dir.exists() {
+            anyhow::bail!("Firefox directory not found: {}", firefox_dir.display());
+        }
+
+        // Find all profile directories
+        let mut profiles = Vec::new();
+        for entry in std::fs::read_dir(&firefox_dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.is_dir() {
+                let cookies_db = path.join("cookies.sqlite");
+                if cookies_db.exists() {
+                    if let Ok(metadata) = cookies_db.metadata() {
+                        if let Ok(modified) = metadata.modified() {
+                            profiles.push((path, modified));
+                        }
+                    }
+                }
+            }
+        }
+
+        if profiles.is_empty() {
+            anyhow::bail!(
+                "No Firefox profiles with cookies.sqlite found in: {}",
+                firefox_dir.display()
+            );
+        }
+
+        // Sort by modification time (most recent first)
+        profiles.sort_by_key(|p| std::cmp::Reverse(p.1));
+
+        // Take the most recent profile that has the required cookies
+        let domain_pattern = format!("%{}%", domain);
+
+        for (profile_path, _modified) in profiles {
+            let cookies_db = profile_path.join("cookies.sqlite");
+            let (conn, temp_db) = match open_cookie_db(&cookies_db) {
+                Ok(opened) => opened,
+                Err(e) => {
+                    debug!("Skipping Firefox profile {}: {}", profile_path.display(), e);
+                    continue;
+                }
+            };
+
+            // Check if we can find the required cookies
+            let count = conn
+                .query_row(
+                    "SELECT COUNT(*) FROM moz_cookies WHERE host LIKE ?1 AND (name = 'phsid' OR name = 'phusr')",
+                    [&domain_pattern],
+                    |row| row.get::<_, i32>(0),
+                )
+                .unwrap_or(0);
+
+            drop(conn);
+            if let Some(temp_path) = temp_db {
+                let _ = std::fs::remove_file(temp_path);
+            }
+
+            if count >= 2 {
+                return Ok(profile_path);
+            }
+        }
+
+        anyhow::bail!("No Firefox profile found with required Phabricator cookies")
+    }
+}
+
+/// Cookie provider for Chromium-family browsers, which share the `Cookies`
+/// SQLite layout and the `v10`/`v11` value encryption scheme.
+struct ChromiumCookieProvider {
+    name: &'static str,
+    /// Browser user-data directory relative to the platform config directory
+    config_subdir: &'static [&'static str],
+    /// `application` attribute of the Safe Storage secret in the Linux keyring
+    keyring_application: &'static str,
+    /// Keychain service holding the Safe Storage password on macOS
+    keychain_service: &'static str,
+}
+
+type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
+
+impl ChromiumCookieProvider {
+    fn chrome() -> Self {
+        Self {
+            name: "Chrome",
+            config_subdir: if cfg!(target_os = "macos") {
+                &["Google", "Chrome"]
+            } else {
+                &["google-chrome"]
+            },
+            keyring_application: "chrome",
+            keychain_service: "Chrome Safe Storage",
+        }
+    }
+
+    fn chromium() -> Self {
+        Self {
+            name: "Chromium",
+            config_subdir: if cfg!(target_os = "macos") {
+                &["Chromium"]
+            } else {
+                &["chromium"]
+            },
+            keyring_application: "chromium",
+            keychain_service: "Chromium Safe Storage",
+        }
+    }
+
+    fn brave() -> Self {
+        Self {
+            name: "Brave",
+            config_subdir: &["BraveSoftware", "Brave-Browser"],
+            keyring_application: "brave",
+            keychain_service: "Brave Safe Storage",
+        }
+    }
+
+    fn user_data_dir(&self) -> Result<PathBuf> {
+        let base = if cfg!(target_os = "macos") {
+            dirs::home_dir()
+                .ok_or_else(|| anyhow::anyhow!("Could not find home directory"))?
+                .join("Library")
+                .join("Application Support")
+        } else {
+            dirs::config_dir().ok_or_else(|| anyhow::anyhow!("Could not find config directory"))?
+        };
+        Ok(self.config_subdir.iter().fold(base, |dir, part| dir.join(part)))
+    }
+
+    /// Lists `Cookies` databases of every profile, most recently written first.
+    fn find_cookie_dbs(&self) -> Result<Vec<(PathBuf, SystemTime)>> {
+        let user_data_dir = self.user_data_dir()?;
+        if !user_data_dir.exists() {
+            anyhow::bail!("{} directory not found: {}", self.name, user_data_dir.display());
+        }
+
+        let mut dbs = Vec::new();
+        for entry in std::fs::read_dir(&user_data_dir)? {
+            let path = entry?.path();
+            if !path.is_dir() {
+                continue;
+            }
+            // Newer versions keep cookies under Network/, older ones in the profile root
+            for candidate in [path.join("Network").join("Cookies"), path.join("Cookies")] {
+                if let Ok(modified) = candidate.metadata().and_then(|m| m.modified()) {
+                    dbs.push((candidate, modified));
+                    break;
+                }
+            }
+        }
+
+        dbs.sort_by_key(|db| std::cmp::Reverse(db.1));
+        Ok(dbs)
+    }
+
+    fn extract_cookies_from_chromium_db(
+        &self,
+        cookies_db_path: &std::path::Path,
+        domain: &str,
+        keys: &mut ChromiumKeys,
+    ) -> Result<HashMap<String, String>> {
+        let (conn, temp_db) = open_cookie_db(cookies_db_path)?;
+
+        // Since schema version 24 the plaintext is prefixed with SHA-256(host_key)
+        let db_version: i64 = conn
+            .query_row("SELECT value FROM meta WHERE key = 'version'", [], |row| {
+                row.get::<_, String>(0)
+            })
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+
+        let mut rows = Vec::new();
+        {
+            let mut stmt = conn.prepare(
+                "SELECT name, value, encrypted_value FROM cookies WHERE host_key LIKE ?1",
+            )?;
+            let domain_pattern = format!("%{}%", domain);
+            let cookie_iter = stmt.query_map([&domain_pattern], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Vec<u8>>(2)?,
+                ))
+            })?;
+            for row in cookie_iter {
+                rows.push(row?);
+            }
+        }
+
+        drop(conn);
+        if let Some(temp_path) = temp_db {
+            let _ = std::fs::remove_file(temp_path);
+        }
+
+        let mut cookies = HashMap::new();
+        for (name, value, encrypted_value) in rows {
+            if !value.is_empty() || encrypted_value.is_empty() {
+                cookies.insert(name, value);
+                continue;
+            }
+            match self.decrypt_value(&encrypted_value, db_version, keys) {
+                Ok(decrypted) => {
+                    cookies.insert(name, decrypted);
+                }
+                Err(e) => debug!("{}: could not decrypt cookie {}: {:#}", self.name, name, e),
+            }
+        }
+
+        if !has_phabricator_session(&cookies) {
+            anyhow::bail!(
+                "Required cookies (phsid, phusr) not found for domain: {} in {}. Found cookies: {:?}",
+                domain,
+                cookies_db_path.display(),
+                cookies.keys().collect::<Vec<_>>()
+            );
+        }
+
+        Ok(cookies)
+    }
+
+    fn decrypt_value(
+        &self,
+        encrypted: &[u8],
+        db_version: i64,
+        keys: &mut ChromiumKeys,
+    ) -> Result<String> {
+        use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+
+        let (key, ciphertext) = if let Some(rest) = encrypted.strip_prefix(b"v10") {
+            (keys.v10(self)?, rest)
+        } else if let Some(rest) = encrypted.strip_prefix(b"v11") {
+            (keys.v11(self)?, rest)
+        } else {
+            // Very old profiles stored values unencrypted
+            return Ok(String::from_utf8_lossy(encrypted).into_owned());
+        };
+
+        let iv = [b' '; 16];
+        let mut plaintext = Aes128CbcDec::new(&key.into(), &iv.into())
+            .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+            .map_err(|e| anyhow::anyhow!("AES decryption failed: {}", e))?;
+
+        if db_version >= 24 && plaintext.len() >= 32 {
+            plaintext.drain(..32);
+        }
+
+        String::from_utf8(plaintext).context("Decrypted cookie is not valid UTF-8")
+    }
+
+    /// Reads the Safe Storage password from the OS secret store.
+    fn safe_storage_password(&self) -> Result<String> {
+        let output = if cfg!(target_os = "macos") {
+            std::process::Command::new("security")
+                .args(["find-generic-password", "-w", "-s", self.keychain_service])
+                .output()
+                .context("Failed to run `security`")?
+        } else {
+            std::process::Command::new("secret-tool")
+                .args(["lookup", "application", self.keyring_application])
+                .output()
+                .context("Failed to run `secret-tool` (install libsecret-tools)")?
+        };
+
+        let password = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if !output.status.success() || password.is_empty() {
+            anyhow::bail!("No {} Safe Storage password found in the keyring", self.name);
+        }
+        Ok(password)
+    }
+}
+
+impl CookieProvider for ChromiumCookieProvider {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn find_session(&self, domain: &str) -> Result<(HashMap<String, String>, SystemTime)> {
+        if cfg!(target_os = "windows") {
+            anyhow::bail!("{} cookie decryption is not supported on Windows", self.name);
+        }
+
+        let mut keys = ChromiumKeys::default();
+        let mut last_error = None;
+        for (db_path, modified) in self.find_cookie_dbs()? {
+            match self.extract_cookies_from_chromium_db(&db_path, domain, &mut keys) {
+                Ok(cookies) => return Ok((cookies, modified)),
+                Err(e) => {
+                    debug!("{}: skipping {}: {:#}", self.name, db_path.display(), e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            anyhow::anyhow!("No {} profile found with a Cookies database", self.name)
+        }))
+    }
+}
+
+/// Lazily derived AES keys for Chromium cookie values.
+#[derive(Default)]
+struct ChromiumKeys {
+    v10: Option<[u8; 16]>,
+    v11: Option<[u8; 16]>,
+}
+
+impl ChromiumKeys {
+    fn derive(password: &[u8], iterations: u32) -> [u8; 16] {
+        let mut key = [0u8; 16];
+        pbkdf2::pbkdf2_hmac::<sha1::Sha1>(password, b"saltysalt", iterations, &mut key);
+        key
+    }
+
+    /// `v10` values use the hardcoded "peanuts" password on Linux and the
+    /// Keychain password on macOS.
+    fn v10(&mut self, provider: &ChromiumCookieProvider) -> Result<[u8; 16]> {
+        if let Some(key) = self.v10 {
+            return Ok(key);
+        }
+        let key = if cfg!(target_os = "macos") {
+            Self::derive(provider.safe_storage_password()?.as_bytes(), 1003)
+        } else {
+            Self::derive(b"peanuts", 1)
+        };
+        self.v10 = Some(key);
+        Ok(key)
+    }
+
+    /// `v11` values (Linux only) use the password stored in libsecret, read with
+    /// `secret-tool`. Passwords kept in KWallet are not supported.
+    fn v11(&mut self, provider: &ChromiumCookieProvider) -> Result<[u8; 16]> {
+        if let Some(key) = self.v11 {
+            return Ok(key);
+        }
+        let key = Self::derive(provider.safe_storage_password()?.as_bytes(), 1);
+        self.v11 = Some(key);
+        Ok(key)
+    }
+}
+
+// How long cached responses stay fresh, per endpoint
+const USER_CACHE_TTL: Duration = Duration::from_secs(7 * 24 * 3600);
+const REVISION_CACHE_TTL: Duration = Duration::from_secs(24 * 3600);
+const RAW_DIFF_CACHE_TTL: Duration = Duration::from_secs(30 * 24 * 3600);
+// Transactions change whenever someone comments
+const ACTIVITY_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+// Large payloads (file contents) that are saved elsewhere and never cached
+const NO_CACHE: Duration = Duration::ZERO;
+
+/// On-disk cache of Conduit responses, keyed by endpoint, parameters and token hash.
+struct ResponseCache {
+    conn: Mutex<Connection>,
+    // Skip lookups but still store fresh responses
+    refresh: bool,
+}
+
+impl ResponseCache {
+    fn default_path() -> Option<PathBuf> {
+        dirs::cache_dir().map(|dir| dir.join("phab-comments-to-md").join("cache.sqlite"))
+    }
+
+    fn open(path: &std::path::Path, refresh: bool) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .context(format!("Failed to create cache directory {}", parent.display()))?;
+        }
+        let conn = Connection::open(path)
+            .context(format!("Failed to open cache database {}", path.display()))?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS responses (
+                 key TEXT PRIMARY KEY,
+                 body TEXT NOT NULL,
+                 fetched_at INTEGER NOT NULL
+             );",
+        )?;
+        // Nothing outlives the longest TTL. Only Conduit responses and what was
+        // extracted from pages are kept: older versions stored cookie-authenticated
+        // pages whole.
+        let expired = Self::now() - RAW_DIFF_CACHE_TTL.as_secs() as i64;
+        if let Err(e) = conn.execute(
+            "DELETE FROM responses WHERE fetched_at < ?1
+                 OR (key NOT LIKE '%/api/%' AND key NOT LIKE '%/extracted/%')",
+            [expired],
+        ) {
+            debug!("cache pruning failed: {}", e);
+        }
+        Ok(Self {
+            conn: Mutex::new(conn),
+            refresh,
+        })
+    }
+
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0)
+    }
+
+    fn get(&self, key: &str, ttl: Duration) -> Option<String> {
+        if self.refresh {
+            return None;
+        }
+        let conn = self.conn.lock().ok()?;
+        let (body, fetched_at): (String, i64) = conn
+            .query_row(
+                "SELECT body, fetched_at FROM responses WHERE key = ?1",
+                [key],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .ok()?;
+        if Self::now() - fetched_at > ttl.as_secs() as i64 {
+            trace!("cache expired: {}", key);
+            return None;
+        }
+        trace!("cache hit: {}", key);
+        Some(body)
+    }
+
+    fn put(&self, key: &str, body: &str) {
+        let Ok(conn) = self.conn.lock() else { return };
+        if let Err(e) = conn.execute(
+            "INSERT OR REPLACE INTO responses (key, body, fetched_at) VALUES (?1, ?2, ?3)",
+            rusqlite::params![key, body, Self::now()],
+        ) {
+            debug!("cache write failed for {}: {}", key, e);
+        }
+    }
+}
+
+/// Rendered changesets of the revision being extracted, fetched per path on demand.
+struct RevisionChangesets {
+    revision_id: u32,
+    // `ref` values found on the revision page, in page order
+    refs: Vec<String>,
+    // Paths each ref touches; empty when Conduit could not tell
+    paths: HashMap<String, Vec<String>>,
+    // Fetched payloads by ref; `None` remembers a failed request
+    payloads: HashMap<String, Option<Arc<str>>>,
+}
+
+impl RevisionChangesets {
+    /// Refs whose changesets may hold suggestions on `paths`. A path renamed since
+    /// the comment was made, or without path information, matches no ref; its
+    /// changeset could be any of them, so every ref is wanted then.
+    fn wanted_refs(&self, paths: &[&str]) -> Vec<String> {
+        let touches = |r: &String, path: &str| {
+            self.paths.get(r).is_some_and(|touched| touched.iter().any(|p| p == path))
+        };
+        let unmatched = paths.iter().any(|path| !self.refs.iter().any(|r| touches(r, path)));
+        self.refs
+            .iter()
+            .filter(|r| unmatched || paths.iter().any(|path| touches(r, path)))
+            .cloned()
+            .collect()
+    }
+}
+
+struct PhabricatorCommentExtractor {
+    base_url: String,
+    api_token: String,
+    client: Client,
+    user_cache: HashMap<String, String>,
+    current_revision_id: Option<u32>,
+    // Changesets of the current revision, shared by all comments on the same paths
+    changesets: Option<RevisionChangesets>,
+    max_concurrency: usize,
+    dump_web: bool,
+    // Lines of code context to attach to each inline comment (0 disables)
+    context_lines: u32,
+    // Render general comments and review actions as one timeline
+    interleave_actions: bool,
+    context_source: ContextSource,
+    // Parsed `differential.getrawdiff` output keyed by diff id
+    raw_diff_cache: HashMap<String, Arc<Vec<RawDiffFile>>>,
+    cookie_providers: Vec<Box<dyn CookieProvider>>,
+    // Outcome of the first browser lookup, failures included, so profiles are scanned once
+    browser_cookies: Mutex<Option<std::result::Result<HashMap<String, String>, String>>>,
+    response_cache: Option<ResponseCache>,
+    local_repo: Option<LocalRepo>,
+    remarkup: Remarkup,
+    // Resolve D123/T456/{F789} mentions to links and titles
+    expand_refs: bool,
+    // Where `--download-attachments` saves embedded files, and how the Markdown links to them
+    attachment_dir: Option<PathBuf>,
+    attachment_link_dir: String,
+}
+
+#[allow(dead_code)]
+impl PhabricatorCommentExtractor {
+    fn new(base_url: String, api_token: String, dump_web: bool) -> Self {
+        let client = Client::builder()
+            .user_agent(
+                "phab-comments-to-md/0.1.0 (https://github.com/padenot/phab-comments-to-md)",
+            )
+            .build()
+            .expect("Failed to build HTTP client");
+
+        Self {
+            remarkup: Remarkup::new(&base_url),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_token,
+            client,
+            user_cache: HashMap::new(),
+            current_revision_id: None,
+            changesets: None,
+            max_concurrency: 4,
+            dump_web,
+            context_lines: 0,
+            interleave_actions: false,
+            context_source: ContextSource::Auto,
+            raw_diff_cache: HashMap::new(),
+            cookie_providers: BrowserChoice::Auto.providers(),
+            browser_cookies: Mutex::new(None),
+            response_cache: None,
+            local_repo: None,
+            expand_refs: false,
+            attachment_dir: None,
+            attachment_link_dir: String::new(),
+        }
+    }
+
+    fn cache_key(&self, endpoint: &str, params: &[(&str, &str)]) -> String {
+        let mut key = format!("{}/{}", self.base_url, endpoint);
+        for (name, value) in params {
+            if *name == "api.token" {
+                // Results depend on what the token may see, but the token itself stays off disk
+                let digest = sha1::Sha1::digest(value.as_bytes());
+                let hash: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+                key.push_str(&format!("&token={}", hash));
+            } else {
+                key.push_str(&format!("&{}={}", name, value));
+            }
+        }
+        key
+    }
+
+    /// Key for data extracted from a page fetched with the browser session. Only
+    /// the extract is stored: the page itself is rendered for whoever owns the cookies.
+    fn extracted_cache_key(&self, revision_id: u32, kind: &str, params: &[(&str, &str)]) -> String {
+        let mut params = params.to_vec();
+        params.push(("api.token", self.api_token.as_str()));
+        self.cache_key(&format!("extracted/D{}/{}", revision_id, kind), &params)
+    }
+
+    fn cache_get(&self, key: &str, ttl: Duration) -> Option<String> {
+        self.response_cache.as_ref()?.get(key, ttl)
+    }
+
+    fn cache_put(&self, key: &str, body: &str) {
+        if let Some(cache) = &self.response_cache {
+            cache.put(key, body);
+        }
+    }
+
+    /// Calls a Conduit method and returns the response body, served from the
+    /// on-disk cache while younger than `ttl`. Only successful responses are cached.
+    async fn conduit_call(&self, method: &str, params: &[(&str, &str)], ttl: Duration) -> Result<String> {
+        let key = self.cache_key(&format!("api/{}", method), params);
+        if let Some(body) = self.cache_get(&key, ttl).filter(|_| !ttl.is_zero()) {
+            return Ok(body);
+        }
+
+        let url = format!("{}/api/{}", self.base_url, method);
+        let response = self
+            .client
+            .post(&url)
+            .form(params)
+            .send()
+            .await
+            .context(format!("Failed to send request to {}", url))?;
+
+        let status = response.status();
+        debug!("Response status: {}", status);
+
+        if !status.is_success() {
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "<no response body>".to_string());
+            error!("HTTP error {}: {}", status, error_text);
+            anyhow::bail!("HTTP error {}: {}", status, error_text);
+        }
+
+        let response_text = response
+            .text()
+            .await
+            .context("Failed to read response body")?;
+        debug!(
+            "Response body (first 500 chars): {}",
+            &response_text.chars().take(500).collect::<String>()
+        );
+
+        let succeeded = serde_json::from_str::<serde_json::Value>(&response_text)
+            .map(|json| json.get("error_code").is_none_or(|code| code.is_null()))
+            .unwrap_or(false);
+        if succeeded && !ttl.is_zero() {
+            self.cache_put(&key, &response_text);
+        }
+        Ok(response_text)
+    }
+
+    fn maybe_dump(&self, filename: &str, content: &str) {
+        if !self.dump_web { return; }
+        let dir = std::path::Path::new("./_phab_debug");
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            debug!("dump: mkdir failed: {}", e);
+            return;
+        }
+        let sanitized = filename.replace('/', "_");
+        let path = dir.join(sanitized);
+        if let Err(e) = std::fs::write(&path, content) {
+            debug!("dump: write failed: {}", e);
+        }
+    }
+
+
+    async fn extract_browser_cookies(&self, domain: &str) -> Result<HashMap<String, String>> {
+        // Try environment variable first for manual cookie specification
+        if let Ok(cookie_env) = std::env::var("PHABRICATOR_COOKIES") {
+            let mut cookies = HashMap::new();
+            for cookie_pair in cookie_env.split(';') {
+                let cookie_pair = cookie_pair.trim();
+                if let Some((name, value)) = cookie_pair.split_once('=') {
+                    cookies.insert(name.trim().to_string(), value.trim().to_string());
+                }
+            }
+            if has_phabricator_session(&cookies) {
+                return Ok(cookies);
+            }
+        }
+
+        if let Some(lookup) = self.browser_cookies.lock().unwrap().as_ref() {
+            return lookup.clone().map_err(|e| anyhow::anyhow!(e));
+        }
+
+        // Read every configured browser and keep the most recently written session
+        let mut best: Option<(HashMap<String, String>, SystemTime, &str)> = None;
+        let mut errors = Vec::new();
+        for provider in &self.cookie_providers {
+            match provider.find_session(domain) {
+                Ok((cookies, modified)) => {
+                    debug!("found {} session cookies for {}", provider.name(), domain);
+                    if best.as_ref().is_none_or(|(_, t, _)| modified > *t) {
+                        best = Some((cookies, modified, provider.name()));
+                    }
+                }
+                Err(e) => errors.push(format!("{}: {:#}", provider.name(), e)),
+            }
+        }
+
+        let lookup = match best {
+            Some((cookies, _, browser)) => {
+                info!("Using Phabricator session cookies from {}", browser);
+                Ok(cookies)
+            }
+            None => Err(format!(
+                "No browser session cookies found for {}:\n  {}",
+                domain,
+                errors.join("\n  ")
+            )),
+        };
+        *self.browser_cookies.lock().unwrap() = Some(lookup.clone());
+        lookup.map_err(|e| anyhow::anyhow!(e))
+    }
+
+    async fn get_csrf_token_with_cookies(&self, revision_id: u32, domain: &str) -> Option<String> {
+        let url = format!("{}/D{}", self.base_url, revision_id);
+        let mut request_builder = self.client.get(&url);
+
+        // Add browser cookies for authentication
+        if let Ok(cookies) = self.extract_browser_cookies(domain).await {
+            let mut cookie_string = String::new();
+            for (name, value) in cookies {
+                if !cookie_string.is_empty() {
+                    cookie_string.push_str("; ");
+                }
+                cookie_string.push_str(&format!("{}={}", name, value));
+            }
+            if !cookie_string.is_empty() {
+                request_builder = request_builder.header("Cookie", cookie_string);
+            }
+        }
+
+        if let Ok(response) = request_builder.send().await {
+            if let Ok(html) = response.text().await {
+                // Look for CSRF token in the HTML
+                let csrf_re = regex::Regex::new(r#"__csrf__.*?value="([^"]+)""#).unwrap();
+                if let Some(captures) = csrf_re.captures(&html) {
+                    return Some(captures.get(1)?.as_str().to_string());
+                }
+
+                // Alternative pattern
+                let csrf_re2 = regex::Regex::new(r#""current":"([^"]+)""#).unwrap();
+                if let Some(captures) = csrf_re2.captures(&html) {
+                    return Some(captures.get(1)?.as_str().to_string());
+                }
+            }
+        }
+        None
+    }
+
+    /// Fills in empty inline comments (code suggestions). Found suggestions are
+    /// cached per comment and latest diff; the others are fetched.
+    async fn resolve_suggestions(
+        &mut self,
+        revision_id: u32,
+        inline_comments: &mut [InlineComment],
+        pending: &[usize],
+        include_done: bool,
+        pb: &ProgressBar,
+    ) {
+        if pending.is_empty() {
+            return;
+        }
+
+        let latest_diff_id = self.get_latest_diff_id(revision_id).await;
+        let keys: HashMap<usize, String> = match &latest_diff_id {
+            Some(diff_id) => pending
+                .iter()
+                .map(|&idx| {
+                    let params = [
+                        ("comment", inline_comments[idx].comment_id.as_str()),
+                        ("diff", diff_id.as_str()),
+                        ("done", if include_done { "1" } else { "0" }),
+                    ];
+                    (idx, self.extracted_cache_key(revision_id, "suggestion", &params))
+                })
+                .collect(),
+            None => HashMap::new(),
+        };
+        let mut missing = Vec::new();
+        for &idx in pending {
+            // Suggestions may still be edited
+            match keys.get(&idx).and_then(|key| self.cache_get(key, REVISION_CACHE_TTL)) {
+                Some(content) => {
+                    inline_comments[idx].is_suggestion = parse_suggestion(&content).is_some();
+                    inline_comments[idx].content = content;
+                }
+                None => missing.push(idx),
+            }
+        }
+
+        self.fetch_suggestions(revision_id, inline_comments, &missing, include_done, pb)
+            .await;
+
+        for idx in missing {
+            let content = &inline_comments[idx].content;
+            if content.is_empty() || content == EMPTY_INLINE_PLACEHOLDER {
+                continue;
+            }
+            if let Some(key) = keys.get(&idx) {
+                self.cache_put(key, content);
+            }
+        }
+    }
+
+    /// Fills in suggestions from the rendered changesets. The changesets of every
+    /// path carrying such a comment are fetched once, concurrently, and shared by
+    /// those comments; only comments that cannot be matched locally fall back to
+    /// per-comment requests, which also run concurrently.
+    async fn fetch_suggestions(
+        &mut self,
+        revision_id: u32,
+        inline_comments: &mut [InlineComment],
+        pending: &[usize],
+        include_done: bool,
+        pb: &ProgressBar,
+    ) {
+        if pending.is_empty() {
+            return;
+        }
+        pb.set_message(format!("Fetching {} code suggestions...", pending.len()));
+        let paths: Vec<String> = pending
+            .iter()
+            .map(|&idx| inline_comments[idx].file_path.clone())
+            .collect();
+        let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+        let payloads = self.get_changeset_payloads(revision_id, &paths).await;
+
+        let mut unresolved = Vec::new();
+        for &idx in pending {
+            let comment = &inline_comments[idx];
+            let suggestion = self
+                .find_suggestion_in_payloads(
+                    &payloads,
+                    comment.line_number,
+                    comment.line_length,
+                    &comment.file_path,
+                    include_done,
+                    &comment.comment_id,
+                )
+                .await;
+            match suggestion {
+                Some(s) => {
+                    inline_comments[idx].is_suggestion = parse_suggestion(&s).is_some();
+                    inline_comments[idx].content = s;
+                }
+                None if payloads.is_empty() => unresolved.push(idx),
+                None => inline_comments[idx].content = EMPTY_INLINE_PLACEHOLDER.to_string(),
+            }
+        }
+
+        if unresolved.is_empty() {
+            return;
+        }
+
+        // No ref ids on the revision page: fall back to guessing changesets per comment
+        debug!("{} suggestions need per-comment changeset requests", unresolved.len());
+        let this = &*self;
+        let requests: Vec<_> = unresolved
+            .iter()
+            .map(|&idx| {
+                let c = &inline_comments[idx];
+                (idx, c.line_number, c.line_length, c.file_path.clone(), c.comment_id.clone())
+            })
+            .collect();
+        let results: Vec<(usize, Option<String>)> = futures::stream::iter(requests)
+            .map(|(idx, line_number, line_length, file_path, comment_id)| async move {
+                let suggestion = this
+                    .fetch_suggestion_fallback(
+                        revision_id,
+                        line_number,
+                        line_length,
+                        &file_path,
+                        include_done,
+                        &comment_id,
+                    )
+                    .await;
+                (idx, suggestion)
+            })
+            .buffer_unordered(this.max_concurrency)
+            .collect()
+            .await;
+
+        for (idx, suggestion) in results {
+            let content = suggestion.unwrap_or_else(|| EMPTY_INLINE_PLACEHOLDER.to_string());
+            inline_comments[idx].is_suggestion = parse_suggestion(&content).is_some();
+            inline_comments[idx].content = content;
+        }
+    }
+
+    /// Matches a suggestion against already fetched changesets: first by the
+    /// comment's `inline-<id>` anchor, then by proximity to its line.
+    async fn find_suggestion_in_payloads(
+        &self,
+        payloads: &[Arc<str>],
+        line_number: u32,
+        line_length: u32,
+        file_path: &str,
+        include_done: bool,
+        comment_id: &str,
+    ) -> Option<String> {
+        if let Some(changeset_data) = self.find_changeset_for_comment(payloads, comment_id) {
+            if let Some(s) = self.extract_suggestion_for_comment_id_from_ajax(changeset_data, comment_id, include_done) {
+                return Some(s);
+            }
+        }
+        // Fallback to heuristics over the changeset closest to this line
+        let changeset_data = self.pick_changeset_for_line(payloads, Some(line_number))?;
+        if let Some(s) = self.extract_suggestion_for_comment_id_from_ajax(changeset_data, comment_id, include_done) {
+            return Some(s);
+        }
+        self.parse_suggestions_from_ajax(changeset_data, line_number, line_length, file_path, include_done)
+            .await
+    }
+
+    /// Fetches JavaScript-rendered suggestions from Phabricator web interface
+    /// using authenticated AJAX requests with guessed changeset ids.
+    /// Only used when the revision page yields no ref parameters.
+    async fn fetch_suggestion_fallback(
+        &self,
+        revision_id: u32,
+        line_number: u32,
+        line_length: u32,
+        file_path: &str,
+        include_done: bool,
+        comment_id: &str,
+    ) -> Option<String> {
+        let changeset_data = self
+            .fetch_changeset_data(revision_id, Some(line_number), include_done)
+            .await?;
+        if let Some(s) = self.extract_suggestion_for_comment_id_from_ajax(&changeset_data, comment_id, include_done) {
+            return Some(s);
+        }
+        self.parse_suggestions_from_ajax(&changeset_data, line_number, line_length, file_path, include_done)
+            .await
+    }
+
+    /// Returns the changeset payloads touching `paths`, fetching the missing ones
+    /// concurrently. Falls back to every changeset of the revision when one of the
+    /// paths cannot be tied to a ref. Cached until another revision is requested.
+    async fn get_changeset_payloads(&mut self, revision_id: u32, paths: &[&str]) -> Vec<Arc<str>> {
+        let mut state = match self.changesets.take() {
+            Some(state) if state.revision_id == revision_id => state,
+            _ => {
+                let refs = self.extract_ref_parameters_from_page(revision_id).await;
+                let paths = if refs.is_empty() {
+                    HashMap::new()
+                } else {
+                    self.get_changeset_paths(revision_id).await
+                };
+                RevisionChangesets { revision_id, refs, paths, payloads: HashMap::new() }
+            }
+        };
+
+        let wanted = state.wanted_refs(paths);
+
+        let missing: Vec<&String> = wanted.iter().filter(|r| !state.payloads.contains_key(*r)).collect();
+        if !missing.is_empty() {
+            let domain = if let Ok(parsed_url) = Url::parse(&self.base_url) {
+                parsed_url.host_str().unwrap_or("phabricator.services.mozilla.com").to_string()
+            } else { "phabricator.services.mozilla.com".to_string() };
+            let csrf_token = self
+                .get_csrf_token_with_cookies(revision_id, &domain)
+                .await
+                .unwrap_or_else(|| "dummy".to_string());
+            let csrf_token = csrf_token.as_str();
+
+            let this = &*self;
+            let fetched: Vec<(String, Option<String>)> = futures::stream::iter(missing)
+                .map(|r| async move {
+                    let text = this.post_changeset_and_get_text(revision_id, r, csrf_token).await;
+                    if let Some(text) = &text {
+                        this.maybe_dump(&format!("changeset_ref_{}.json", r), text);
+                    }
+                    (r.clone(), text.filter(|text| changeset_response_ok(text)))
+                })
+                .buffered(this.max_concurrency)
+                .collect()
+                .await;
+            debug!("fetched {} changesets for D{}", fetched.len(), revision_id);
+            state
+                .payloads
+                .extend(fetched.into_iter().map(|(r, text)| (r, text.map(Arc::from))));
+        }
+
+        let payloads = wanted
+            .iter()
+            .filter_map(|r| state.payloads.get(r).cloned().flatten())
+            .collect();
+        self.changesets = Some(state);
+        payloads
+    }
+
+    /// Maps the changeset IDs of the latest diff, which are the `ref` values on the
+    /// revision page, to the paths they touch.
+    async fn get_changeset_paths(&self, revision_id: u32) -> HashMap<String, Vec<String>> {
+        let mut paths = HashMap::new();
+        let Some(diff_id) = self.get_latest_diff_id(revision_id).await else {
+            return paths;
+        };
+        let params = [
+            ("api.token", self.api_token.as_str()),
+            ("ids[0]", diff_id.as_str()),
+        ];
+        // Diffs are immutable once uploaded
+        let response_text = match self
+            .conduit_call("differential.querydiffs", &params, RAW_DIFF_CACHE_TTL)
+            .await
+        {
+            Ok(text) => text,
+            Err(e) => {
+                debug!("differential.querydiffs failed for diff {}: {}", diff_id, e);
+                return paths;
+            }
+        };
+        let Ok(result) = serde_json::from_str::<serde_json::Value>(&response_text) else {
+            return paths;
+        };
+
+        let changes = result
+            .pointer(&format!("/result/{}/changes", diff_id))
+            .and_then(|c| c.as_array());
+        for change in changes.into_iter().flatten() {
+            let id = match change.get("id") {
+                Some(serde_json::Value::String(id)) => id.clone(),
+                Some(serde_json::Value::Number(id)) => id.to_string(),
+                _ => continue,
+            };
+            let touched = ["currentPath", "oldPath"]
+                .iter()
+                .filter_map(|key| change.get(*key).and_then(|p| p.as_str()))
+                .map(str::to_string)
+                .collect();
+            paths.insert(id, touched);
+        }
+        paths
+    }
+
+    fn find_changeset_for_comment<'a>(&self, payloads: &'a [Arc<str>], comment_id: &str) -> Option<&'a str> {
+        payloads
+            .iter()
+            .find(|text| self.changeset_contains_inline_anchor(text, comment_id))
+            .map(|text| &**text)
+    }
+
+    fn pick_changeset_for_line<'a>(&self, payloads: &'a [Arc<str>], target_line: Option<u32>) -> Option<&'a str> {
+        payloads
+            .iter()
+            .map(|text| (self.score_changeset(text, target_line), text))
+            // Keep the first of equally scored changesets, like the sequential scan did
+            .fold(None, |best: Option<(i64, &Arc<str>)>, (score, text)| match best {
+                Some((best_score, _)) if best_score >= score => best,
+                _ => Some((score, text)),
+            })
+            .map(|(_, text)| &**text)
+    }
+
+    fn changeset_contains_inline_anchor(&self, ajax_text: &str, comment_id: &str) -> bool {
+        let mut s = ajax_text;
+        if s.starts_with("for (;;);") { s = &s[9..]; }
+        if let Ok(json) = serde_json::from_str::<serde_json::Value>(s) {
+            if let Some(payload) = json.get("payload") {
+                if let Some(changeset_html) = payload.get("changeset") {
+                    if let Some(h) = changeset_html.as_str() {
+                        let cid = comment_id.trim_matches('"');
+                        let anchor_id = format!("id=\"inline-{}\"", cid);
+                        let anchor_name = format!("name=\"inline-{}\"", cid);
+                        let cell_anchor = format!("id=\"anchor-inline-{}\"", cid);
+                        return h.contains(&anchor_id) || h.contains(&anchor_name) || h.contains(&cell_anchor);
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    /// Fetches the changeset holding this inline comment and returns the source lines
+    /// around `line_number..line_number + line_length`, padded by `context_lines`.
+    async fn fetch_code_context(
+        &mut self,
+        revision_id: u32,
+        file_path: &str,
+        comment_id: &str,
+        diff_id: &str,
+        line_number: u32,
+        line_length: u32,
+    ) -> Vec<CodeContextLine> {
+        if self.context_source != ContextSource::Web {
+            let context = self
+                .fetch_code_context_from_raw_diff(diff_id, file_path, line_number, line_length)
+                .await;
+            if !context.is_empty() || self.context_source == ContextSource::Conduit {
+                return context;
+            }
+            debug!(
+                "raw diff {} has no lines for {}:{}, falling back to web",
+                diff_id, file_path, line_number
+            );
+        }
+
+        let payloads = self.get_changeset_payloads(revision_id, &[file_path]).await;
+        match self.find_changeset_for_comment(&payloads, comment_id) {
+            Some(changeset_data) => {
+                self.extract_code_context_from_ajax(changeset_data, line_number, line_length)
+            }
+            None => {
+                debug!("no changeset found for inline {} in {}", comment_id, file_path);
+                Vec::new()
+            }
+        }
+    }
+
+    fn extract_code_context_from_ajax(
+        &self,
+        ajax_text: &str,
+        line_number: u32,
+        line_length: u32,
+    ) -> Vec<CodeContextLine> {
+        let s = ajax_text.strip_prefix("for (;;);").unwrap_or(ajax_text);
+        let html = match serde_json::from_str::<serde_json::Value>(s) {
+            Ok(json) => match json
+                .get("payload")
+                .and_then(|p| p.get("changeset"))
+                .and_then(|c| c.as_str())
+            {
+                Some(h) => h.to_string(),
+                None => return Vec::new(),
+            },
+            Err(_) => return Vec::new(),
+        };
+
+        let (old_lines, new_lines) = self.extract_changeset_lines(&html);
+        self.code_context_from_lines(&old_lines, &new_lines, line_number, line_length)
+    }
+
+    /// Token-only path: reads the lines around an inline comment from the raw diff
+    /// of the diff it was made on.
+    async fn fetch_code_context_from_raw_diff(
+        &mut self,
+        diff_id: &str,
+        file_path: &str,
+        line_number: u32,
+        line_length: u32,
+    ) -> Vec<CodeContextLine> {
+        let files = match self.get_raw_diff(diff_id).await {
+            Some(files) => files,
+            None => return Vec::new(),
+        };
+        match files.iter().find(|f| f.matches_path(file_path)) {
+            Some(file) => {
+                let (old_lines, new_lines) = file.line_maps();
+                self.code_context_from_lines(&old_lines, &new_lines, line_number, line_length)
+            }
+            None => {
+                debug!("raw diff {} does not touch {}", diff_id, file_path);
+                Vec::new()
+            }
+        }
+    }
+
+    fn code_context_from_lines(
+        &self,
+        old_lines: &HashMap<u32, String>,
+        new_lines: &HashMap<u32, String>,
+        line_number: u32,
+        line_length: u32,
+    ) -> Vec<CodeContextLine> {
+        let first = line_number;
+        let last = line_number.saturating_add(line_length.max(1) - 1);
+        let start = first.saturating_sub(self.context_lines).max(1);
+
+        // Inline comments are anchored on the new side unless the line only exists on the old side
+        let side = if new_lines.keys().any(|n| (first..=last).contains(n)) {
+            new_lines
+        } else {
+            old_lines
+        };
+        // Nothing past the last known line can be shown, however large --context is
+        let Some(&max_line) = side.keys().max() else {
+            return Vec::new();
+        };
+        let end = last.saturating_add(self.context_lines).min(max_line);
+
+        (start..=end)
+            .filter_map(|n| {
+                side.get(&n).map(|text| CodeContextLine {
+                    line_number: n,
+                    text: text.clone(),
+                    commented: n >= first && n <= last,
+                })
+            })
+            .collect()
+    }
+
+    /// Parses the rendered diff rows of a changeset into (old side, new side) line maps,
+    /// keyed by the line numbers found in `C<ref>OL<N>` / `C<ref>NL<N>` anchors.
+    fn extract_changeset_lines(&self, html: &str) -> (HashMap<u32, String>, HashMap<u32, String>) {
+        let mut old_lines = HashMap::new();
+        let mut new_lines = HashMap::new();
+
+        let anchor_re = Regex::new(r"^C\d+([ON])L(\d+)$").unwrap();
+        let document = Html::parse_document(html);
+        let row_selector = match Selector::parse("tr") {
+            Ok(sel) => sel,
+            Err(_) => return (old_lines, new_lines),
+        };
+
+        for row in document.select(&row_selector) {
+            // Rows inside inline comments (e.g. suggestion tables) are not part of the file
+            if self.is_inside_inline_comment(&row) {
+                continue;
+            }
+
+            let mut pending: Vec<(bool, u32)> = Vec::new();
+            for cell in row.children().filter_map(scraper::ElementRef::wrap) {
+                let name = cell.value().name();
+                if name != "td" && name != "th" {
+                    continue;
+                }
+                if let Some(caps) = cell.value().id().and_then(|id| anchor_re.captures(id)) {
+                    let is_new = &caps[1] == "N";
+                    if let Ok(n) = caps[2].parse::<u32>() {
+                        pending.push((is_new, n));
+                    }
+                    continue;
+                }
+                if cell.value().classes().any(|c| c == "n" || c == "copy") || pending.is_empty() {
+                    continue;
+                }
+
+                let text = cell
+                    .text()
+                    .collect::<String>()
+                    .replace('\u{00A0}', " ")
+                    .replace(['\r', '\n'], "");
+                let text = text.trim_end().to_string();
+                for (is_new, n) in pending.drain(..) {
+                    if is_new {
+                        new_lines.entry(n).or_insert_with(|| text.clone());
+                    } else {
+                        old_lines.entry(n).or_insert_with(|| text.clone());
+                    }
+                }
+            }
+        }
+
+        (old_lines, new_lines)
+    }
+
+    fn is_inside_inline_comment(&self, element: &scraper::ElementRef) -> bool {
+        let mut current = element.parent();
+        while let Some(parent_node) = current {
+            if let Some(parent_element) = parent_node.value().as_element() {
+                if parent_element.classes().any(|class| {
+                    class == "differential-inline-comment" || class == "inline-suggestion-view"
+                }) {
+                    return true;
+                }
+            }
+            current = parent_node.parent();
+        }
+        false
+    }
+
+    /// Fetches and parses the raw unified diff for a diff id via `differential.getrawdiff`.
+    /// Results (including failures) are cached for the lifetime of the extractor.
+    async fn get_raw_diff(&mut self, diff_id: &str) -> Option<Arc<Vec<RawDiffFile>>> {
+        let diff_id = diff_id.trim_matches('"');
+        if diff_id.is_empty() {
+            return None;
+        }
+        if let Some(cached) = self.raw_diff_cache.get(diff_id) {
+            return Some(cached.clone());
+        }
+
+        let files = match self.fetch_raw_diff_text(diff_id).await {
+            Ok(text) => {
+                self.maybe_dump(&format!("rawdiff_{}.diff", diff_id), &text);
+                parse_raw_diff(&text)
+            }
+            Err(e) => {
+                warn!("Failed to fetch raw diff {}: {:#}", diff_id, e);
+                Vec::new()
+            }
+        };
+        let files = Arc::new(files);
+        self.raw_diff_cache.insert(diff_id.to_string(), files.clone());
+        Some(files)
+    }
+
+    /// Translates the lines of comments made on older diffs to the latest diff by
+    /// aligning the file in both raw diffs.
+    async fn port_stale_comments(&mut self, comments_data: &mut CommentsData) {
+        let Some(latest) = comments_data.diffs.last().map(|d| d.to_string()) else {
+            return;
+        };
+        if !comments_data.inline_comments.iter().any(|c| c.is_stale) {
+            return;
+        }
+        let Some(latest_files) = self.get_raw_diff(&latest).await else {
+            return;
+        };
+
+        let mut alignments: HashMap<(String, String), Option<LineAlignment>> = HashMap::new();
+        for comment in comments_data.inline_comments.iter_mut() {
+            if !comment.is_stale || comment.line_number == 0 {
+                continue;
+            }
+            let diff_id = comment.diff_id.trim_matches('"').to_string();
+            let key = (diff_id.clone(), comment.file_path.clone());
+            if !alignments.contains_key(&key) {
+                let alignment = match self.get_raw_diff(&diff_id).await {
+                    Some(old_files) => old_files
+                        .iter()
+                        .find(|f| f.matches_path(&comment.file_path))
+                        .map(|old_file| {
+                            let (base_lines, old_lines) = old_file.line_maps();
+                            match latest_files.iter().find(|f| f.matches_path(&comment.file_path)) {
+                                Some(latest_file) => LineAlignment::new(&old_lines, &latest_file.line_maps().1),
+                                // The latest diff leaves the file as it is in the base
+                                None => LineAlignment::new(&old_lines, &base_lines),
+                            }
+                        }),
+                    None => None,
+                };
+                alignments.insert(key.clone(), alignment);
+            }
+
+            let Some(alignment) = &alignments[&key] else {
+                continue;
+            };
+            match alignment.port(comment.line_number, comment.line_length) {
+                PortedRange::Moved { line_number, line_length } => {
+                    comment.ported_line_number = Some(line_number);
+                    comment.ported_line_length = Some(line_length);
+                }
+                PortedRange::Deleted => comment.is_orphaned = true,
+                PortedRange::Unknown => {
+                    debug!(
+                        "could not port inline {} on {}:{}",
+                        comment.comment_id, comment.file_path, comment.line_number
+                    );
+                }
+            }
+        }
+    }
+
+    /// Returns the lines of `file_path` as of the latest diff: the new side when the
+    /// latest diff touches the file, otherwise the base the comment's diff was made against.
+    async fn latest_file_lines(
+        &mut self,
+        comments_data: &CommentsData,
+        comment: &InlineComment,
+    ) -> Option<HashMap<u32, String>> {
+        let comment_diff = comment.diff_id.trim_matches('"').to_string();
+        let latest = comments_data
+            .diffs
+            .last()
+            .map(|d| d.to_string())
+            .unwrap_or_else(|| comment_diff.clone());
+
+        let latest_files = self.get_raw_diff(&latest).await?;
+        if let Some(file) = latest_files.iter().find(|f| f.matches_path(&comment.file_path)) {
+            return Some(file.line_maps().1);
+        }
+        let comment_files = self.get_raw_diff(&comment_diff).await?;
+        let file = comment_files.iter().find(|f| f.matches_path(&comment.file_path))?;
+        Some(file.line_maps().0)
+    }
+
+    /// Assembles every extracted suggestion into a unified diff against the latest diff.
+    async fn build_suggestions_patch(&mut self, comments_data: &CommentsData, diff_id: u32) -> String {
+        let mut files: Vec<(String, HashMap<u32, String>, Vec<LineEdit>)> = Vec::new();
+
+        for comment in comments_data.inline_comments.iter().filter(|c| c.is_suggestion) {
+            let Some((old_lines, new_lines)) = parse_suggestion(&comment.content) else {
+                continue;
+            };
+            let (start, len) = match (comment.is_stale, comment.ported_line_number) {
+                (false, _) => (comment.line_number, comment.line_length.max(1)),
+                (true, Some(line)) => (line, comment.ported_line_length.unwrap_or(1)),
+                (true, None) => {
+                    warn!(
+                        "D{}: skipping suggestion {} on {}:{}, its code is gone from the latest diff",
+                        diff_id, comment.comment_id, comment.file_path, comment.line_number
+                    );
+                    continue;
+                }
+            };
+
+            let index = match files.iter().position(|(path, _, _)| *path == comment.file_path) {
+                Some(index) => index,
+                None => {
+                    let Some(lines) = self.latest_file_lines(comments_data, comment).await else {
+                        warn!(
+                            "D{}: skipping suggestion {}, no diff content for {}",
+                            diff_id, comment.comment_id, comment.file_path
+                        );
+                        continue;
+                    };
+                    files.push((comment.file_path.clone(), lines, Vec::new()));
+                    files.len() - 1
+                }
+            };
+            let (_, lines, edits) = &mut files[index];
+
+            let current: Option<Vec<&str>> = (start..start + len)
+                .map(|n| lines.get(&n).map(|l| l.as_str()))
+                .collect();
+            let Some(current) = current else {
+                warn!(
+                    "D{}: skipping suggestion {}, {}:{} is not covered by the diff",
+                    diff_id, comment.comment_id, comment.file_path, start
+                );
+                continue;
+            };
+            match compare_old_lines(&current, &old_lines) {
+                _ if old_lines.is_empty() => {}
+                OldLinesMatch::Exact => {}
+                OldLinesMatch::WhitespaceOnly => {
+                    warn!(
+                        "D{}: skipping suggestion {} on {}:{}, its indentation or blank lines could not be recovered",
+                        diff_id, comment.comment_id, comment.file_path, start
+                    );
+                    continue;
+                }
+                OldLinesMatch::Different => {
+                    warn!(
+                        "D{}: skipping suggestion {}, {}:{} has changed since it was made",
+                        diff_id, comment.comment_id, comment.file_path, start
+                    );
+                    continue;
+                }
+            }
+
+            edits.push(LineEdit { start, len, new_lines });
+        }
+
+        files
+            .into_iter()
+            .filter(|(_, _, edits)| !edits.is_empty())
+            .map(|(path, lines, edits)| format_file_patch(&path, &lines, edits))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Resolves every inline comment against the local checkout, checking the
+    /// commented text from the comment's own diff is still there.
+    async fn map_to_local_repo(&mut self, comments_data: &mut CommentsData) {
+        for comment in comments_data.inline_comments.iter_mut() {
+            if comment.line_number == 0 || comment.file_path.is_empty() {
+                continue;
+            }
+
+            let diff_id = comment.diff_id.trim_matches('"').to_string();
+            let expected: Vec<String> = match self.get_raw_diff(&diff_id).await {
+                Some(files) => files
+                    .iter()
+                    .find(|f| f.matches_path(&comment.file_path))
+                    .map(|file| {
+                        let (_, new_lines) = file.line_maps();
+                        (comment.line_number..comment.line_number + comment.line_length.max(1))
+                            .map_while(|n| new_lines.get(&n).cloned())
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                None => Vec::new(),
+            };
+            // A partial range cannot be checked reliably
+            let expected = if expected.len() == comment.line_length.max(1) as usize {
+                expected
+            } else {
+                Vec::new()
+            };
+
+            let Some(repo) = &self.local_repo else { return };
+            // The checkout most likely has the latest diff applied
+            let line = comment.ported_line_number.unwrap_or(comment.line_number);
+            let location = repo.locate(&comment.file_path, line, &expected);
+
+            let reference = format!("{}:{}", location.path, location.line);
+            if location.file_moved {
+                warn!("{} has moved to {} locally", comment.file_path, location.path);
+            }
+            match location.status {
+                LocalStatus::Missing => warn!("{} does not exist in the local checkout", location.path),
+                LocalStatus::Changed => warn!(
+                    "The code commented at {}:{} no longer matches {} locally",
+                    comment.file_path, comment.line_number, reference
+                ),
+                LocalStatus::Shifted => debug!("{}:{} is at {} locally", comment.file_path, comment.line_number, reference),
+                LocalStatus::Matches | LocalStatus::Unverified => {}
+            }
+            comment.local = Some(location);
+        }
+    }
+
+    async fn fetch_raw_diff_text(&self, diff_id: &str) -> Result<String> {
+        let params = [
+            ("api.token", self.api_token.as_str()),
+            ("diffID", diff_id),
+        ];
+
+        debug!("Fetching raw diff {}", diff_id);
+        // Diffs are immutable once uploaded
+        let response_text = self
+            .conduit_call("differential.getrawdiff", &params, RAW_DIFF_CACHE_TTL)
+            .await?;
+
+        let result: RawDiffResult = serde_json::from_str(&response_text)
+            .context("Failed to parse differential.getrawdiff response")?;
+
+        if let Some(error_code) = result.error_code {
+            anyhow::bail!(
+                "API Error: {} - {}",
+                error_code,
+                result.error_info.unwrap_or_default()
+            );
+        }
+
+        result.result.context("No result data")
+    }
+
+    async fn post_changeset_and_get_text(&self, revision_id: u32, ref_param: &str, csrf_token: &str) -> Option<String> {
+        let domain = if let Ok(parsed_url) = Url::parse(&self.base_url) {
+            parsed_url.host_str().unwrap_or("phabricator.services.mozilla.com").to_string()
+        } else { "phabricator.services.mozilla.com".to_string() };
+
+        let changeset_url = format!("{}/differential/changeset/", self.base_url);
+        let headers = [
+            ("User-Agent", "Mozilla/5.0 (X11; Linux x86_64; rv:142.0) Gecko/20100101 Firefox/142.0"),
+            ("Accept", "*/*"),
+            ("Accept-Language", "en-US,en;q=0.5"),
+            ("Accept-Encoding", "gzip, deflate, br"),
+            ("X-Phabricator-Csrf", csrf_token),
+            ("X-Phabricator-Via", &format!("/D{}", revision_id)),
+            ("Content-Type", "application/x-www-form-urlencoded"),
+            ("Origin", &self.base_url),
+            ("Connection", "keep-alive"),
+            ("Sec-Fetch-Dest", "empty"),
+            ("Sec-Fetch-Mode", "cors"),
+            ("Sec-Fetch-Site", "same-origin"),
+        ];
+        let form_data = [("ref", ref_param), ("device", "2up"), ("__wflow__", "true"), ("__ajax__", "true"), ("__metablock__", "2")];
+
+        let mut request = self.client.post(&changeset_url);
+        for (k, v) in headers.iter() { request = request.header(*k, *v); }
+        if let Ok(cookies) = self.extract_browser_cookies(&domain).await {
+            let cookie_string = cookies
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<_>>()
+                .join("; ");
+            request = request.header("Cookie", cookie_string);
+        }
+        request.form(&form_data).send().await.ok()?.text().await.ok()
+    }
+
+    async fn get_changeset_ids(&self, revision_id: u32) -> Vec<String> {
+        // First get the changeset IDs from the differential API
+        let revision_phid = match self.get_revision_phid(revision_id).await {
+            Ok(phid) => phid,
+            Err(_) => return Vec::new(),
+        };
+
+        // Get transactions to find diff information
+        let transactions = match self.get_transactions(&revision_phid).await {
+            Ok(trans) => trans,
+            Err(_) => return Vec::new(),
+        };
+
+        let mut changeset_ids = Vec::new();
+
+        // Look for differential diff information in transactions
+        for transaction in transactions {
+            if let Some(fields) = transaction.fields {
+                // Check for diff field that might contain changeset information
+                if let Some(diff_field) = fields.get("diff") {
+                    if let Some(diff_obj) = diff_field.as_object() {
+                        if let Some(id) = diff_obj.get("id") {
+                            if let Some(id_str) = id.as_str() {
+                                changeset_ids.push(id_str.to_string());
+                            } else if let Some(id_num) = id.as_u64() {
+                                changeset_ids.push(id_num.to_string());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        // If we didn't find changeset IDs in transactions, try the direct diff API
+        if changeset_ids.is_empty() {
+            if let Some(diff_id) = self.get_latest_diff_id(revision_id).await {
+                changeset_ids.push(diff_id);
+            }
+        }
+
+        changeset_ids
+    }
+
+    async fn get_latest_diff_id(&self, revision_id: u32) -> Option<String> {
+        let revision_id_str = revision_id.to_string();
+        let params = [
+            ("api.token", self.api_token.as_str()),
+            ("constraints[revisionIDs][0]", revision_id_str.as_str()),
+            ("order", "newest"),
+            ("limit", "1"),
+        ];
+        // New diffs get uploaded while a review is going on
+        let response_text = match self
+            .conduit_call("differential.diff.search", &params, ACTIVITY_CACHE_TTL)
+            .await
+        {
+            Ok(text) => text,
+            Err(e) => {
+                debug!("differential.diff.search failed for D{}: {}", revision_id, e);
+                return None;
+            }
+        };
+        let result: DiffSearchResult = serde_json::from_str(&response_text).ok()?;
+        if let Some(error_code) = result.error_code {
+            debug!(
+                "API Error: {} - {}",
+                error_code,
+                result.error_info.unwrap_or_default()
+            );
+            return None;
+        }
+        result
+            .result?
+            .data
+            .first()
+            .map(|diff| diff.id.to_string())
+    }
+
+    /// Returns when a diff (not revision) was uploaded, for `--since diff:<ID>`.
+    async fn get_diff_timestamp(&self, diff_id: u32) -> Result<u64> {
+        let diff_id_str = diff_id.to_string();
+        let params = [
+            ("api.token", self.api_token.as_str()),
+            ("constraints[ids][0]", diff_id_str.as_str()),
+        ];
+        let response_text = self
+            .conduit_call("differential.diff.search", &params, RAW_DIFF_CACHE_TTL)
+            .await?;
+        let result: serde_json::Value = serde_json::from_str(&response_text)
+            .context("Failed to parse differential.diff.search response")?;
+        if let Some(error_code) = result.get("error_code").and_then(|c| c.as_str()) {
+            anyhow::bail!(
+                "API Error: {} - {}",
+                error_code,
+                result.get("error_info").and_then(|i| i.as_str()).unwrap_or_default()
+            );
+        }
+        result
+            .pointer("/result/data/0/fields/dateCreated")
+            .and_then(|d| d.as_u64())
+            .context(format!("Diff {} not found", diff_id))
+    }
+
+    async fn resolve_since(&self, spec: SinceSpec) -> Result<u64> {
+        match spec {
+            SinceSpec::Timestamp(timestamp) => Ok(timestamp),
+            SinceSpec::Diff(diff_id) => self.get_diff_timestamp(diff_id).await,
+        }
+    }
+
+    /// Fetches the `/D<id>` page with the browser session. Never cached on disk:
+    /// the page is rendered for whoever owns the cookies.
+    async fn fetch_revision_page(&self, revision_id: u32) -> Option<String> {
+        let url = format!("{}/D{}", self.base_url, revision_id);
+
+        // Try to extract browser cookies for authentication
+        let domain = if let Ok(parsed_url) = Url::parse(&self.base_url) {
+            parsed_url
+                .host_str()
+                .unwrap_or("phabricator.services.mozilla.com")
+                .to_string()
+        } else {
+            "phabricator.services.mozilla.com".to_string()
+        };
+
+        let mut request_builder = self.client.get(&url);
+
+        // Add browser cookies if available
+        if let Ok(cookies) = self.extract_browser_cookies(&domain).await {
+            if !cookies.is_empty() {
+                let mut cookie_string = String::new();
+                for (name, value) in cookies {
+                    if !cookie_string.is_empty() {
+                        cookie_string.push_str("; ");
+                    }
+                    cookie_string.push_str(&format!("{}={}", name, value));
+                }
+                request_builder = request_builder.header("Cookie", cookie_string);
+            }
+        }
+
+        request_builder.send().await.ok()?.text().await.ok()
+    }
+
+    /// Returns the ref parameters of the revision page, cached per latest diff.
+    async fn extract_ref_parameters_from_page(&self, revision_id: u32) -> Vec<String> {
+        let key = self
+            .get_latest_diff_id(revision_id)
+            .await
+            .map(|diff_id| self.extracted_cache_key(revision_id, "refs", &[("diff", &diff_id)]));
+        // The refs of a diff never change
+        if let Some(refs) = key
+            .as_deref()
+            .and_then(|key| self.cache_get(key, RAW_DIFF_CACHE_TTL))
+            .and_then(|body| serde_json::from_str::<Vec<String>>(&body).ok())
+        {
+            return refs;
+        }
+
+        let refs = self.scrape_ref_parameters(revision_id).await;
+        if let (Some(key), false) = (&key, refs.is_empty()) {
+            if let Ok(body) = serde_json::to_string(&refs) {
+                self.cache_put(key, &body);
+            }
+        }
+        refs
+    }
+
+    /// Extracts ref parameters from Phabricator revision page HTML for AJAX requests
+    async fn scrape_ref_parameters(&self, revision_id: u32) -> Vec<String> {
+        if let Some(html) = self.fetch_revision_page(revision_id).await {
+            // Extract all ref parameters from the HTML using regex
+            let re = regex::Regex::new(r#"ref=(\d+)"#).unwrap();
+            let mut refs = Vec::new();
+
+            for captures in re.captures_iter(&html) {
+                if let Some(ref_match) = captures.get(1) {
+                    let ref_value = ref_match.as_str().to_string();
+                    if !refs.contains(&ref_value) {
+                        refs.push(ref_value);
+                    }
+                }
+            }
+
+            // If no refs found with simple pattern, try more comprehensive search
+            if refs.is_empty() {
+                // Try looking for refs in various JavaScript formats
+                let patterns = vec![
+                    r#""ref":"(\d+)""#,        // JSON: "ref":"123456"
+                    r#"'ref':\s*'(\d+)'"#,     // JS: 'ref': '123456'
+                    r#"ref:\s*'(\d+)'"#,       // JS: ref: '123456'
+                    r#"ref:\s*(\d+)"#,         // JS: ref: 123456
+                    r#"\bC(\d{7,8})[ON]L\d+"#, // HTML IDs like C8450617OL1, C8450617NL1
+                ];
+
+                for pattern in patterns {
+                    let re = regex::Regex::new(pattern).unwrap();
+                    for captures in re.captures_iter(&html) {
+                        if let Some(ref_match) = captures.get(1) {
+                            let ref_value = ref_match.as_str().to_string();
+                            if !refs.contains(&ref_value) && ref_value.len() >= 7 {
+                                refs.push(ref_value);
+                            }
+                        }
+                    }
+                }
+
+                // Look for changeset IDs in JavaScript data or JSON (7-8 digit numbers)
+                if refs.is_empty() {
+                    let js_re = regex::Regex::new(r"\b\d{7,8}\b").unwrap();
+                    for js_match in js_re.find_iter(&html) {
+                        let ref_value = js_match.as_str().to_string();
+                        if !refs.contains(&ref_value) {
+                            refs.push(ref_value);
+                        }
+                    }
+                }
+
+                // Try to find them in differential/ URLs specifically
+                let diff_re =
+                    regex::Regex::new(r"differential/changeset/[^?]*\?[^&]*ref=(\d+)")
+                        .unwrap();
+                for captures in diff_re.captures_iter(&html) {
+                    if let Some(ref_match) = captures.get(1) {
+                        let ref_value = ref_match.as_str().to_string();
+                        if !refs.contains(&ref_value) {
+                            refs.push(ref_value);
+                        }
+                    }
+                }
+            }
+
+            debug!("found {} ref ids on revision page", refs.len());
+            refs
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Scores a changeset response by how likely it holds the suggestion for
+    /// `target_line`; higher is better.
+    fn score_changeset(&self, text: &str, target_line: Option<u32>) -> i64 {
+        // Prefer responses whose nearest suggestion is closer to target_line
+        let mut score: i64 = 0;
+        if text.contains("suggestionText") { score += 100; }
+        if text.contains("inline-suggestion-view") { score += 10; }
+        if text.contains("differential-inline-comment") { score += 1; }
+
+        let Some(tline) = target_line else { return score; };
+        let s = text.strip_prefix("for (;;);").unwrap_or(text);
+        let Ok(json) = serde_json::from_str::<serde_json::Value>(s) else { return score; };
+        let Some(html) = json.get("payload").and_then(|p| p.get("changeset")).and_then(|c| c.as_str()) else {
+            return score;
+        };
+        let Ok(anchor_re) = regex::Regex::new(r"C\d{7,8}[ON]L(\d+)") else { return score; };
+
+        // Distance from each suggestion to the closest line anchor before it
+        let mut best_dist: i64 = i64::MAX;
+        let mut idx = 0usize;
+        while let Some(pos) = html[idx..].find("inline-suggestion-view") {
+            let abs_pos = idx + pos;
+            let mut back_start = abs_pos.saturating_sub(8000);
+            while !html.is_char_boundary(back_start) {
+                back_start -= 1;
+            }
+            let last_line = anchor_re
+                .captures_iter(&html[back_start..abs_pos])
+                .filter_map(|cap| cap.get(1)?.as_str().parse::<u32>().ok())
+                .last();
+            if let Some(n) = last_line {
+                best_dist = best_dist.min((n as i64 - tline as i64).abs());
+            }
+            idx = abs_pos + 1;
+        }
+        if best_dist != i64::MAX { score += 1_000_000 - best_dist; }
+        score
+    }
+
+    async fn fetch_changeset_with_refs(
+        &self,
+        revision_id: u32,
+        ref_params: &[String],
+        target_line: Option<u32>,
+        _include_done: bool,
+    ) -> Option<String> {
+        // Get domain for browser cookies
+        let domain = if let Ok(parsed_url) = Url::parse(&self.base_url) {
+            parsed_url
+                .host_str()
+                .unwrap_or("phabricator.services.mozilla.com")
+                .to_string()
+        } else {
+            "phabricator.services.mozilla.com".to_string()
+        };
+
+        // Get CSRF token first (this also needs cookies)
+        let csrf_token = self
+            .get_csrf_token_with_cookies(revision_id, &domain)
+            .await
+            .unwrap_or_else(|| "dummy".to_string());
+
+        // Set up the AJAX request similar to the curl command
+        let changeset_url = format!("{}/differential/changeset/", self.base_url);
+
+        let headers = [
+            (
+                "User-Agent",
+                "Mozilla/5.0 (X11; Linux x86_64; rv:142.0) Gecko/20100101 Firefox/142.0",
+            ),
+            ("Accept", "*/*"),
+            ("Accept-Language", "en-US,en;q=0.5"),
+            ("Accept-Encoding", "gzip, deflate, br"),
+            ("X-Phabricator-Csrf", &csrf_token),
+            ("X-Phabricator-Via", &format!("/D{}", revision_id)),
+            ("Content-Type", "application/x-www-form-urlencoded"),
+            ("Origin", &self.base_url),
+            ("Connection", "keep-alive"),
+            ("Sec-Fetch-Dest", "empty"),
+            ("Sec-Fetch-Mode", "cors"),
+            ("Sec-Fetch-Site", "same-origin"),
+        ];
+
+        // Try each ref parameter and prioritize those with suggestionText
+        let mut best_response: Option<String> = None;
+        let mut best_score: i64 = -1; // higher is better in this scheme
+
+        for ref_param in ref_params {
+            let form_data = [
+                ("ref", ref_param.as_str()),
+                ("device", "1up"),
+                ("__wflow__", "true"),
+                ("__ajax__", "true"),
+                ("__metablock__", "7"),
+            ];
+
+            let mut request = self.client.post(&changeset_url);
+            for (key, value) in headers.iter() {
+                request = request.header(*key, *value);
+            }
+
+            // Add browser cookies for authentication
+            if let Ok(cookies) = self.extract_browser_cookies(&domain).await {
+                let mut cookie_string = String::new();
+                for (name, value) in cookies {
+                    if !cookie_string.is_empty() {
+                        cookie_string.push_str("; ");
+                    }
+                    cookie_string.push_str(&format!("{}={}", name, value));
+                }
+                if !cookie_string.is_empty() {
+                    request = request.header("Cookie", cookie_string);
+                }
+            }
+
+            match request.form(&form_data).send().await {
+                Ok(response) => {
+                    // Capture response details before consuming the response
+                    if let Ok(text) = response.text().await {
+                        // dump and log characteristics
+                        let has_suggestion_text = text.contains("suggestionText");
+                        let has_inline_view   = text.contains("inline-suggestion-view");
+                        let has_inline_comment= text.contains("differential-inline-comment");
+                        self.maybe_dump(&format!("changeset_ref_{}_sug{}_view{}_inline{}.json",
+                            ref_param, has_suggestion_text as u8, has_inline_view as u8, has_inline_comment as u8), &text);
+                        debug!("ref {}: suggestion_text={} inline_view={} inline_comment={}",
+                               ref_param, has_suggestion_text, has_inline_view, has_inline_comment);
+
+                        let score = self.score_changeset(&text, target_line);
+
+                        if score > best_score {
+                            best_score = score;
+                            best_response = Some(text);
+                        }
+                    }
+                }
+                Err(_) => continue,
+            }
+        }
+
+        if let Some(response) = best_response {
+            return Some(response);
+        }
+
+        None
+    }
+
+    async fn fetch_changeset_data(&self, revision_id: u32, target_line: Option<u32>, include_done: bool) -> Option<String> {
+        // First try to extract ref parameters from the initial page
+        let ref_params = self.extract_ref_parameters_from_page(revision_id).await;
+
+        if !ref_params.is_empty() {
+            // Use the extracted ref parameters directly
+            if let Some(result) = self
+                .fetch_changeset_with_refs(revision_id, &ref_params, target_line, include_done)
+                .await
+            {
+                return Some(result);
+            }
+        }
+
+        // Fallback: Get the actual changeset IDs the old way
+        let changeset_ids = self.get_changeset_ids(revision_id).await;
+
+        if changeset_ids.is_empty() {
+            return None;
+        }
+
+        // Get CSRF token first
+        let csrf_token = self
+            .get_csrf_token(revision_id)
+            .await
+            .unwrap_or_else(|| "dummy".to_string());
+
+        // Set up the AJAX request similar to the curl command
+        let changeset_url = format!("{}/differential/changeset/", self.base_url);
+
+        let headers = [
             (
                 "User-Agent",
                 "Mozilla/5.0 (X11; Linux x86_64; rv:142.0) Gecko/20100101 Firefox/142.0",
@@ -985,791 +4204,1728 @@ impl PhabricatorCommentExtractor {
             ("Sec-Fetch-Site", "same-origin"),
         ];
 
-        // Try each changeset ID until we find one with suggestions
-        for changeset_id in changeset_ids {
-            // Try to get changeset data for each specific file that might contain suggestions
-            let result = self
-                .try_fetch_specific_changeset(&changeset_url, &headers, &changeset_id)
-                .await;
-            if result.is_some() {
-                return result;
+        // Try each changeset ID until we find one with suggestions
+        for changeset_id in changeset_ids {
+            // Try to get changeset data for each specific file that might contain suggestions
+            let result = self
+                .try_fetch_specific_changeset(&changeset_url, &headers, &changeset_id)
+                .await;
+            if result.is_some() {
+                return result;
+            }
+
+            // Note: The proper solution would be to extract ref values from the HTML page
+            // but this requires session authentication (cookies) and gzip decompression, not just API tokens.
+            // For now, we limit our attempts to the API-provided changeset IDs.
+        }
+
+        // If no specific changeset worked, try to find file-specific changesets
+        if let Some(result) = self
+            .try_fetch_file_specific_changeset(revision_id, &changeset_url, &headers)
+            .await
+        {
+            return Some(result);
+        }
+
+        None
+    }
+
+    async fn try_fetch_specific_changeset(
+        &self,
+        changeset_url: &str,
+        headers: &[(&str, &str); 12],
+        changeset_id: &str,
+    ) -> Option<String> {
+        let form_data = [
+            ("ref", changeset_id),
+            ("device", "2up"),
+            ("__wflow__", "true"),
+            ("__ajax__", "true"),
+            ("__metablock__", "2"),
+        ];
+
+        let mut request = self.client.post(changeset_url);
+        for (key, value) in headers.iter() {
+            request = request.header(*key, *value);
+        }
+
+
+        if let Ok(response) = request.form(&form_data).send().await {
+            // Capture response details before consuming the response
+            if let Ok(text) = response.text().await {
+                self.maybe_dump(&format!("changeset_try_specific_{}.json", changeset_id), &text);
+
+                // Check if this response contains suggestions or meaningful diff content
+                if text.contains("inline-suggestion-view")
+                    || text.contains("suggestionText")
+                    || (text.len() > 1000 && text.contains("differential-diff"))
+                {
+                    return Some(text);
+                }
+            }
+        }
+        None
+    }
+
+    async fn try_fetch_file_specific_changeset(
+        &self,
+        revision_id: u32,
+        changeset_url: &str,
+        headers: &[(&str, &str); 12],
+    ) -> Option<String> {
+        // Try some variations of changeset IDs
+        let potential_refs = vec![
+            format!("{}", revision_id),
+            format!("{}", revision_id + 1),
+            format!("{}", revision_id + 2),
+            format!("{}", revision_id - 1),
+            format!("{}", revision_id - 2),
+        ];
+
+        for ref_id in potential_refs {
+            let form_data = [
+                ("ref", ref_id.as_str()),
+                ("device", "2up"),
+                ("__wflow__", "true"),
+                ("__ajax__", "true"),
+                ("__metablock__", "2"),
+            ];
+
+            let mut request = self.client.post(changeset_url);
+            for (key, value) in headers.iter() {
+                request = request.header(*key, *value);
             }
 
-            // Note: The proper solution would be to extract ref values from the HTML page
-            // but this requires session authentication (cookies) and gzip decompression, not just API tokens.
-            // For now, we limit our attempts to the API-provided changeset IDs.
+            if let Ok(response) = request.form(&form_data).send().await {
+                // Capture response details before consuming the response
+                if let Ok(text) = response.text().await {
+                    self.maybe_dump(&format!("changeset_try_file_specific_{}.json", ref_id), &text);
+
+                    // Check for suggestions in general
+                    if text.contains("inline-suggestion-view")
+                        || text.contains("suggestionText")
+                    {
+                        return Some(text);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    async fn parse_suggestions_from_ajax(
+        &self,
+        ajax_response: &str,
+        line_number: u32,
+        line_length: u32,
+        file_path: &str,
+        include_done: bool,
+    ) -> Option<String> {
+        debug!("matching suggestion for file '{}' around line {} (len {})", file_path, line_number, line_length);
+        // Prefer extracting inline suggestion content from HTML first (ties to the correct inline comment)
+        if ajax_response.contains("inline-suggestion-view") {
+            // Parse the HTML and extract the suggestion content
+            let mut response = ajax_response;
+            if response.starts_with("for (;;);") {
+                response = &response[9..];
+            }
+
+            if let Ok(data) = serde_json::from_str::<serde_json::Value>(response) {
+                if let Some(payload) = data.get("payload") {
+                    if let Some(changeset_html) = payload.get("changeset") {
+                        if let Some(html_str) = changeset_html.as_str() {
+                            // Extract the first inline-suggestion-view block (cell-specific parsing)
+                            if let Some(suggestion) = self.extract_inline_suggestion(html_str) {
+                                debug!("fallback inline suggestion extracted from HTML (len={})", suggestion.len());
+                                return Some(suggestion);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        // Fallback: JSON suggestion mapped to the nearest line, if available
+        debug!("Attempting to extract suggestion from JSON format (nearest line)");
+        if let Some(suggestion) = self.extract_suggestion_from_json_for_line(ajax_response, line_number, line_length) {
+            debug!("json suggestion near line {} extracted (len={})", line_number, suggestion.len());
+            return Some(suggestion);
+        }
+
+        // Fallback: any JSON suggestion
+        debug!("Attempting to extract any suggestion from JSON format");
+        if let Some(suggestion) = self.extract_suggestion_from_json(ajax_response) {
+            debug!("inline suggestion extracted from JSON (len={})", suggestion.len());
+            debug!("Successfully extracted suggestion from JSON");
+            return Some(suggestion);
+        }
+
+        // Try to extract diff content from the changeset
+        if let Some(diff_content) = self.extract_diff_from_changeset(ajax_response) {
+            return Some(format!(
+                "**Code changes:**\n\n```diff\n{}\n```",
+                diff_content
+            ));
+        }
+
+        let mut response = ajax_response;
+
+        // The AJAX response starts with for (;;); followed by JSON
+        if response.starts_with("for (;;);") {
+            response = &response[9..]; // Remove the for (;;); prefix
+        }
+
+        match serde_json::from_str::<serde_json::Value>(response) {
+            Ok(data) => {
+                trace!("Successfully parsed AJAX response JSON");
+                // Look for HTML content in the JSON response
+                if let Some(payload) = data.get("payload") {
+                    if let Some(changeset_html) = payload.get("changeset") {
+                        if let Some(html_str) = changeset_html.as_str() {
+                            // Parse HTML for suggestions
+                            let document = Html::parse_document(html_str);
+                            return self
+                                .find_suggestions_in_html(
+                                    &document,
+                                    line_number,
+                                    file_path,
+                                    include_done,
+                                )
+                                .await;
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                debug!("Response is not JSON ({}), treating as HTML", e);
+                // Not JSON, treat as HTML
+                let document = Html::parse_document(response);
+                return self
+                    .find_suggestions_in_html(&document, line_number, file_path, include_done)
+                    .await;
+            }
+        }
+
+        None
+    }
+
+    fn extract_suggestion_for_comment_id_from_ajax(&self, ajax_response: &str, comment_id: &str, include_done: bool) -> Option<String> {
+        let mut response = ajax_response;
+        if response.starts_with("for (;;);") { response = &response[9..]; }
+        if let Ok(data) = serde_json::from_str::<serde_json::Value>(response) {
+            if let Some(payload) = data.get("payload") {
+                if let Some(changeset_html) = payload.get("changeset") {
+                    if let Some(html_str) = changeset_html.as_str() {
+                        return self.extract_suggestion_for_comment_id_in_html(html_str, comment_id, include_done);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn extract_suggestion_for_comment_id_in_html(&self, html: &str, comment_id: &str, _include_done: bool) -> Option<String> {
+        // Find the anchor for this inline comment
+        let anchor = format!("id=\"inline-{}\"", comment_id.trim_matches('"'));
+        let name_anchor = format!("name=\"inline-{}\"", comment_id.trim_matches('"'));
+        let anchor_pos = html.find(&anchor).or_else(|| html.find(&name_anchor));
+        let anchor_pos = anchor_pos?;
+
+        // Look forward for the inline comment container and a suggestion view
+        let search_area = &html[anchor_pos..std::cmp::min(anchor_pos + 40000, html.len())];
+        let div_rel = search_area.find("differential-inline-comment");
+        let div_rel = div_rel?;
+        let block_area = &search_area[div_rel..];
+
+        // Do not filter DONE here; higher-level logic already filters inlines by is_done via API fields.
+
+        // Find suggestion table inside this block
+        if let Some(sugg_rel) = block_area.find("inline-suggestion-view") {
+            let sugg_area = &block_area[sugg_rel..];
+            if let Some(table_rel) = sugg_area.find("<table") {
+                let table_area = &sugg_area[table_rel..];
+                if let Some(end_rel) = table_area.find("</table>") {
+                    let table_html = &table_area[..end_rel + 8];
+
+                    // Build diff lines from this table using cell-specific extraction
+                    let document = Html::parse_document(table_html);
+                    let mut diff_lines = Vec::new();
+                    if let Ok(row_selector) = Selector::parse("tr") {
+                        for row in document.select(&row_selector) {
+                            // Removed lines (old side)
+                            if let Ok(old_sel) = Selector::parse("td.left.old, td.left.old.old-full, td.old, .diff-old") {
+                                if let Some(old_cell) = row.select(&old_sel).next() {
+                                    let mut s = old_cell.text().collect::<String>().replace('\u{00A0}', " ");
+                                    s = s.replace('\r', "");
+                                    s = s.replace('\n', "");
+                                    // Strip leading aural markers only; preserve indentation
+                                    while s.starts_with("- ") { s = s[2..].to_string(); }
+                                    if !s.trim().is_empty() && s != "-" {
+                                        diff_lines.push(format!("- {}", s));
+                                    }
+                                }
+                            }
+                            // Added lines (new side)
+                            if let Ok(new_sel) = Selector::parse("td.right.new, td.right.new.new-full, td.new, .diff-new") {
+                                if let Some(new_cell) = row.select(&new_sel).next() {
+                                    let mut s = new_cell.text().collect::<String>().replace('\u{00A0}', " ");
+                                    s = s.replace('\r', "");
+                                    s = s.replace('\n', "");
+                                    // Strip leading aural markers only; preserve indentation
+                                    while s.starts_with("+ ") { s = s[2..].to_string(); }
+                                    if !s.trim().is_empty() && s != "+" {
+                                        diff_lines.push(format!("+ {}", s));
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    if !diff_lines.is_empty() { return Some(diff_lines.join("\n")); }
+                }
+            }
+        }
+        None
+    }
+
+    // Extract the inline suggestion diff closest to the given line number.
+    // Heuristic: scan for each occurrence of "inline-suggestion-view" and look backward
+    // in HTML for an anchor like C########OL<N>/NL<N>; choose the block with smallest |N - line_number|.
+    // nearest-line heuristic removed in favor of comment-id anchor matching
+
+    fn extract_suggestion_from_json(&self, json_response: &str) -> Option<String> {
+        // Strip the "for (;;);" prefix that Phabricator adds for security
+        let clean_json = json_response.strip_prefix("for (;;);").unwrap_or(json_response);
+
+        // Parse the JSON response to extract suggestionText
+        match serde_json::from_str::<serde_json::Value>(clean_json) {
+            Ok(json) => {
+                trace!("Successfully parsed suggestion JSON");
+                // Look for suggestionText in the JSON structure
+                if let Some(suggestion_text) = self.find_suggestion_text_recursive(&json) {
+                    if !suggestion_text.trim().is_empty() {
+                        return Some(format!(
+                            "**Suggested changes:**\n\n```diff\n{}\n```",
+                            suggestion_text.trim_matches('\n')
+                        ));
+                    }
+                }
+            }
+            Err(e) => {
+                debug!("Failed to parse suggestion JSON: {}", e);
+            }
         }
 
-        // If no specific changeset worked, try to find file-specific changesets
-        if let Some(result) = self
-            .try_fetch_file_specific_changeset(revision_id, &changeset_url, &headers)
-            .await
-        {
-            return Some(result);
+        // Fallback: try to extract suggestionText using regex that handles escaped content
+        let re = regex::Regex::new(r#""suggestionText":"((?:[^"\\]|\\.)*)""#).unwrap();
+        if let Some(captures) = re.captures(json_response) {
+            if let Some(suggestion_match) = captures.get(1) {
+                let suggestion_text = suggestion_match
+                    .as_str()
+                    .replace("\\n", "\n")
+                    .replace("\\t", "\t")
+                    .replace("\\u003e", ">")
+                    .replace("\\u003c", "<")
+                    .replace("\\/", "/")
+                    .replace("\\\"", "\"")
+                    .replace("\\\\", "\\");
+
+                if !suggestion_text.trim().is_empty() {
+                    return Some(format!(
+                        "**Suggested changes:**\n\n```diff\n{}\n```",
+                        suggestion_text.trim_matches('\n')
+                    ));
+                }
+            }
+        } else {
+            debug!("No suggestionText found using regex");
         }
 
         None
     }
 
-    async fn try_fetch_specific_changeset(
-        &self,
-        changeset_url: &str,
-        headers: &[(&str, &str); 12],
-        changeset_id: &str,
-    ) -> Option<String> {
-        let form_data = [
-            ("ref", changeset_id),
-            ("device", "2up"),
-            ("__wflow__", "true"),
-            ("__ajax__", "true"),
-            ("__metablock__", "2"),
-        ];
-
-        let mut request = self.client.post(changeset_url);
-        for (key, value) in headers.iter() {
-            request = request.header(*key, *value);
-        }
-
+    fn extract_suggestion_from_json_for_line(&self, json_response: &str, line_number: u32, line_length: u32) -> Option<String> {
+        let clean_json = json_response.strip_prefix("for (;;);").unwrap_or(json_response);
 
-        match request.form(&form_data).send().await {
-            Ok(response) => {
-                // Capture response details before consuming the response
-                match response.text().await {
-                    Ok(text) => {
-                        self.maybe_dump(&format!("changeset_try_specific_{}.json", changeset_id), &text);
-
-                        // Check if this response contains suggestions or meaningful diff content
-                        if text.contains("inline-suggestion-view")
-                            || text.contains("suggestionText")
-                            || (text.len() > 1000 && text.contains("differential-diff"))
-                        {
-                            return Some(text);
+        if let Ok(json) = serde_json::from_str::<serde_json::Value>(clean_json) {
+            // Walk tree and find objects with `line` (or similar) and `suggestionText`; choose nearest/intersecting
+            let mut best_text: Option<String> = None;
+            let mut best_dist: i64 = i64::MAX;
+            fn visit(value: &serde_json::Value, target_start: u32, target_len: u32, best_dist: &mut i64, best_text: &mut Option<String>) {
+                match value {
+                    serde_json::Value::Object(map) => {
+                        let line = map
+                            .get("line").and_then(|v| v.as_u64()).map(|v| v as u32)
+                            .or_else(|| map.get("lineNumber").and_then(|v| v.as_u64()).map(|v| v as u32))
+                            .or_else(|| map.get("newLine").and_then(|v| v.as_u64()).map(|v| v as u32));
+                        let length = map
+                            .get("length").and_then(|v| v.as_u64()).map(|v| v as u32)
+                            .or_else(|| map.get("len").and_then(|v| v.as_u64()).map(|v| v as u32))
+                            .unwrap_or(1);
+                        let text = map.get("suggestionText").and_then(|v| v.as_str());
+                        if let (Some(l), Some(t)) = (line, text) {
+                            let target_end = target_start + target_len.saturating_sub(1);
+                            let block_end = l + length.saturating_sub(1);
+                            let intersects = block_end >= target_start && l <= target_end;
+                            let d = if intersects { 0 } else { (l as i64 - target_start as i64).abs() };
+                            if !t.trim().is_empty() && d < *best_dist {
+                                *best_dist = d;
+                                *best_text = Some(t.to_string());
+                            }
                         }
+                        for (_k, v) in map.iter() { visit(v, target_start, target_len, best_dist, best_text); }
+                    }
+                    serde_json::Value::Array(arr) => {
+                        for v in arr { visit(v, target_start, target_len, best_dist, best_text); }
                     }
-                    Err(_) => {}
+                    _ => {}
                 }
             }
-            Err(_) => {}
+            visit(&json, line_number, line_length, &mut best_dist, &mut best_text);
+            if let Some(t) = best_text { return Some(format!("**Suggested changes:**\n\n```diff\n{}\n```", t.trim_matches('\n'))); }
         }
         None
     }
 
-    async fn try_fetch_file_specific_changeset(
-        &self,
-        revision_id: u32,
-        changeset_url: &str,
-        headers: &[(&str, &str); 12],
-    ) -> Option<String> {
-        // Try some variations of changeset IDs
-        let potential_refs = vec![
-            format!("{}", revision_id),
-            format!("{}", revision_id + 1),
-            format!("{}", revision_id + 2),
-            format!("{}", revision_id - 1),
-            format!("{}", revision_id - 2),
-        ];
-
-        for ref_id in potential_refs {
-            let form_data = [
-                ("ref", ref_id.as_str()),
-                ("device", "2up"),
-                ("__wflow__", "true"),
-                ("__ajax__", "true"),
-                ("__metablock__", "2"),
-            ];
+    fn find_suggestion_text_recursive(&self, value: &serde_json::Value) -> Option<String> {
+        match value {
+            serde_json::Value::Object(map) => {
+                // Check if this object has suggestionText
+                if let Some(suggestion_text) = map.get("suggestionText") {
+                    if let Some(text) = suggestion_text.as_str() {
+                        if !text.trim().is_empty() { return Some(text.to_string()); }
+                    }
+                }
 
-            let mut request = self.client.post(changeset_url);
-            for (key, value) in headers.iter() {
-                request = request.header(*key, *value);
+                // Recursively search in all object values
+                for (_key, val) in map {
+                    if let Some(result) = self.find_suggestion_text_recursive(val) {
+                        return Some(result);
+                    }
+                }
             }
-
-            match request.form(&form_data).send().await {
-                Ok(response) => {
-                    // Capture response details before consuming the response
-                    match response.text().await {
-                        Ok(text) => {
-                            self.maybe_dump(&format!("changeset_try_file_specific_{}.json", ref_id), &text);
-
-                            // Check for suggestions in general
-                            if text.contains("inline-suggestion-view")
-                                || text.contains("suggestionText")
-                            {
-                                return Some(text);
-                            }
-                        }
-                        Err(_) => {}
+            serde_json::Value::Array(arr) => {
+                // Recursively search in all array elements
+                for val in arr {
+                    if let Some(result) = self.find_suggestion_text_recursive(val) {
+                        return Some(result);
                     }
                 }
-                Err(_) => {}
             }
+            _ => {}
         }
         None
     }
 
-    async fn parse_suggestions_from_ajax(
+    async fn find_suggestions_in_html(
         &self,
-        ajax_response: &str,
-        line_number: u32,
-        line_length: u32,
-        file_path: &str,
+        document: &Html,
+        _line_number: u32,
+        _file_path: &str,
         include_done: bool,
     ) -> Option<String> {
-        debug!("matching suggestion for file '{}' around line {} (len {})", file_path, line_number, line_length);
-        // Prefer extracting inline suggestion content from HTML first (ties to the correct inline comment)
-        if ajax_response.contains("inline-suggestion-view") {
-            // Parse the HTML and extract the suggestion content
-            let mut response = ajax_response;
-            if response.starts_with("for (;;);") {
-                response = &response[9..];
-            }
+        // Look for inline-suggestion-view elements
+        if let Ok(suggestion_selector) = Selector::parse(".inline-suggestion-view") {
+            let suggestions: Vec<_> = document.select(&suggestion_selector).collect();
+            debug!(".inline-suggestion-view blocks found: {}", suggestions.len());
 
-            if let Ok(data) = serde_json::from_str::<serde_json::Value>(response) {
-                if let Some(payload) = data.get("payload") {
-                    if let Some(changeset_html) = payload.get("changeset") {
-                        if let Some(html_str) = changeset_html.as_str() {
-                            // Extract the first inline-suggestion-view block (cell-specific parsing)
-                            if let Some(suggestion) = self.extract_inline_suggestion(html_str) {
-                                debug!("fallback inline suggestion extracted from HTML (len={})", suggestion.len());
-                                return Some(suggestion);
-                            }
-                        }
-                    }
+            // Extract suggestions from available suggestion elements
+            for suggestion in suggestions.iter() {
+                // Check if this suggestion is marked as "done"
+                let is_done = self.is_suggestion_done(suggestion);
+                if is_done && !include_done {
+                    debug!("skipping DONE suggestion block");
+                    continue;
                 }
-            }
-        }
-
-        // Fallback: JSON suggestion mapped to the nearest line, if available
-        debug!("Attempting to extract suggestion from JSON format (nearest line)");
-        if let Some(suggestion) = self.extract_suggestion_from_json_for_line(ajax_response, line_number, line_length) {
-            debug!("json suggestion near line {} extracted (len={})", line_number, suggestion.len());
-            return Some(suggestion);
-        }
 
-        // Fallback: any JSON suggestion
-        debug!("Attempting to extract any suggestion from JSON format");
-        if let Some(suggestion) = self.extract_suggestion_from_json(ajax_response) {
-            debug!("inline suggestion extracted from JSON (len={})", suggestion.len());
-            debug!("Successfully extracted suggestion from JSON");
-            return Some(suggestion);
+                // Extract the suggestion content from the table structure
+                if let Some(suggestion_text) = self.extract_suggestion_from_table(suggestion) {
+                    debug!("chose suggestion from HTML table");
+                    return Some(suggestion_text);
+                }
+            }
         }
 
-        // Try to extract diff content from the changeset
-        if let Some(diff_content) = self.extract_diff_from_changeset(ajax_response) {
-            return Some(format!(
-                "**Code changes:**\n\n```diff\n{}\n```",
-                diff_content
-            ));
-        }
+        None
+    }
 
+    fn extract_diff_from_changeset(&self, ajax_response: &str) -> Option<String> {
         let mut response = ajax_response;
 
-        // The AJAX response starts with for (;;); followed by JSON
+        // Remove the for (;;); prefix if present
         if response.starts_with("for (;;);") {
-            response = &response[9..]; // Remove the for (;;); prefix
+            response = &response[9..];
         }
 
+        // Parse JSON response
         match serde_json::from_str::<serde_json::Value>(response) {
             Ok(data) => {
-                trace!("Successfully parsed AJAX response JSON");
-                // Look for HTML content in the JSON response
+                trace!("Successfully parsed changeset diff JSON");
                 if let Some(payload) = data.get("payload") {
                     if let Some(changeset_html) = payload.get("changeset") {
                         if let Some(html_str) = changeset_html.as_str() {
-                            // Parse HTML for suggestions
+                            // Parse HTML and extract diff content using cell-specific selectors
+                            debug!("Parsing HTML for diff content extraction");
                             let document = Html::parse_document(html_str);
-                            return self
-                                .find_suggestions_in_html(
-                                    &document,
-                                    line_number,
-                                    file_path,
-                                    include_done,
-                                )
-                                .await;
-                        }
-                    }
-                }
-            }
-            Err(e) => {
-                debug!("Response is not JSON ({}), treating as HTML", e);
-                // Not JSON, treat as HTML
-                let document = Html::parse_document(response);
-                return self
-                    .find_suggestions_in_html(&document, line_number, file_path, include_done)
-                    .await;
-            }
-        }
-
-        None
-    }
 
-    fn extract_suggestion_for_comment_id_from_ajax(&self, ajax_response: &str, comment_id: &str, include_done: bool) -> Option<String> {
-        let mut response = ajax_response;
-        if response.starts_with("for (;;);") { response = &response[9..]; }
-        if let Ok(data) = serde_json::from_str::<serde_json::Value>(response) {
-            if let Some(payload) = data.get("payload") {
-                if let Some(changeset_html) = payload.get("changeset") {
-                    if let Some(html_str) = changeset_html.as_str() {
-                        return self.extract_suggestion_for_comment_id_in_html(html_str, comment_id, include_done);
+                            if let Ok(row_sel) = Selector::parse("tr") {
+                                let mut diff_lines = Vec::new();
+                                for row in document.select(&row_sel) {
+                                    // Old/removed cells
+                                    if let Ok(old_sel) = Selector::parse("td.left.old, td.left.old.old-full, td.old, .diff-old") {
+                                        if let Some(old_cell) = row.select(&old_sel).next() {
+                                            let mut s = old_cell.text().collect::<String>().replace('\u{00A0}', " ");
+                                            s = s.replace('\r', "");
+                                            s = s.replace('\n', "");
+                                            while s.starts_with("- ") { s = s[2..].to_string(); }
+                                            if !s.trim().is_empty() && s != "-" { diff_lines.push(format!("- {}", s)); }
+                                        }
+                                    }
+                                    // New/added cells
+                                    if let Ok(new_sel) = Selector::parse("td.right.new, td.right.new.new-full, td.new, .diff-new") {
+                                        if let Some(new_cell) = row.select(&new_sel).next() {
+                                            let mut s = new_cell.text().collect::<String>().replace('\u{00A0}', " ");
+                                            s = s.replace('\r', "");
+                                            s = s.replace('\n', "");
+                                            while s.starts_with("+ ") { s = s[2..].to_string(); }
+                                            if !s.trim().is_empty() && s != "+" { diff_lines.push(format!("+ {}", s)); }
+                                        }
+                                    }
+                                }
+                                if !diff_lines.is_empty() { return Some(diff_lines.join("\n")); }
+                            }
+                        }
                     }
                 }
             }
+            Err(e) => {
+                debug!("Failed to parse changeset diff JSON: {}", e);
+            }
         }
+
         None
     }
 
-    fn extract_suggestion_for_comment_id_in_html(&self, html: &str, comment_id: &str, _include_done: bool) -> Option<String> {
-        // Find the anchor for this inline comment
-        let anchor = format!("id=\"inline-{}\"", comment_id.trim_matches('"'));
-        let name_anchor = format!("name=\"inline-{}\"", comment_id.trim_matches('"'));
-        let anchor_pos = html.find(&anchor).or_else(|| html.find(&name_anchor));
-        let anchor_pos = match anchor_pos { Some(p) => p, None => return None };
-
-        // Look forward for the inline comment container and a suggestion view
-        let search_area = &html[anchor_pos..std::cmp::min(anchor_pos + 40000, html.len())];
-        let div_rel = search_area.find("differential-inline-comment");
-        let div_rel = match div_rel { Some(r) => r, None => return None };
-        let block_area = &search_area[div_rel..];
-
-        // Do not filter DONE here; higher-level logic already filters inlines by is_done via API fields.
-
-        // Find suggestion table inside this block
-        if let Some(sugg_rel) = block_area.find("inline-suggestion-view") {
-            let sugg_area = &block_area[sugg_rel..];
-            if let Some(table_rel) = sugg_area.find("<table") {
-                let table_area = &sugg_area[table_rel..];
-                if let Some(end_rel) = table_area.find("</table>") {
-                    let table_html = &table_area[..end_rel + 8];
+    /// Extracts suggestion diff content from HTML table showing old/new lines
+    fn extract_inline_suggestion(&self, html: &str) -> Option<String> {
+        // Look for inline-suggestion-view content
+        if let Some(start) = html.find("inline-suggestion-view") {
+            // Find the table containing the suggestion
+            let search_area = &html[start..];
+            if let Some(table_start) = search_area.find("<table") {
+                if let Some(table_end) = search_area.find("</table>") {
+                    let table_html = &search_area[table_start..table_end + 8];
 
-                    // Build diff lines from this table using cell-specific extraction
+                    // Parse the table to extract old and new lines
                     let document = Html::parse_document(table_html);
                     let mut diff_lines = Vec::new();
+
                     if let Ok(row_selector) = Selector::parse("tr") {
                         for row in document.select(&row_selector) {
-                            // Removed lines (old side)
                             if let Ok(old_sel) = Selector::parse("td.left.old, td.left.old.old-full, td.old, .diff-old") {
                                 if let Some(old_cell) = row.select(&old_sel).next() {
                                     let mut s = old_cell.text().collect::<String>().replace('\u{00A0}', " ");
                                     s = s.replace('\r', "");
                                     s = s.replace('\n', "");
-                                    // Strip leading aural markers only; preserve indentation
                                     while s.starts_with("- ") { s = s[2..].to_string(); }
-                                    if !s.trim().is_empty() && s != "-" {
-                                        diff_lines.push(format!("- {}", s));
-                                    }
+                                    if !s.trim().is_empty() && s != "-" { diff_lines.push(format!("- {}", s)); }
                                 }
                             }
-                            // Added lines (new side)
                             if let Ok(new_sel) = Selector::parse("td.right.new, td.right.new.new-full, td.new, .diff-new") {
                                 if let Some(new_cell) = row.select(&new_sel).next() {
                                     let mut s = new_cell.text().collect::<String>().replace('\u{00A0}', " ");
                                     s = s.replace('\r', "");
                                     s = s.replace('\n', "");
-                                    // Strip leading aural markers only; preserve indentation
                                     while s.starts_with("+ ") { s = s[2..].to_string(); }
-                                    if !s.trim().is_empty() && s != "+" {
-                                        diff_lines.push(format!("+ {}", s));
-                                    }
+                                    if !s.trim().is_empty() && s != "+" { diff_lines.push(format!("+ {}", s)); }
                                 }
                             }
                         }
                     }
-                    if !diff_lines.is_empty() { return Some(diff_lines.join("\n")); }
+
+                    if !diff_lines.is_empty() {
+                        return Some(diff_lines.join("\n"));
+                    }
                 }
             }
         }
         None
     }
 
-    /// Extract the inline suggestion diff closest to the given line number.
-    /// Heuristic: scan for each occurrence of "inline-suggestion-view" and look backward
-    /// in HTML for an anchor like C########OL<N>/NL<N>; choose the block with smallest |N - line_number|.
-    // nearest-line heuristic removed in favor of comment-id anchor matching
+    fn is_suggestion_done(&self, suggestion_element: &scraper::ElementRef) -> bool {
+        // Look for parent inline comment that has "inline-is-done" class
+        let mut current = suggestion_element.parent();
+        while let Some(parent_node) = current {
+            if let Some(parent_element) = parent_node.value().as_element() {
+                if parent_element
+                    .classes()
+                    .any(|class| class == "inline-is-done")
+                {
+                    return true;
+                }
+            }
+            current = parent_node.parent();
+        }
+        false
+    }
 
-    fn extract_suggestion_from_json(&self, json_response: &str) -> Option<String> {
-        // Strip the "for (;;);" prefix that Phabricator adds for security
-        let clean_json = if json_response.starts_with("for (;;);") {
-            &json_response[9..]
+    fn extract_suggestion_from_table(
+        &self,
+        suggestion_element: &scraper::ElementRef,
+    ) -> Option<String> {
+        let mut diff_lines = Vec::new();
+
+        // Strategy 1: Try to find table with diff content
+        if let Ok(table_selector) = Selector::parse("table") {
+            if let Some(table) = suggestion_element.select(&table_selector).next() {
+                if let Ok(row_selector) = Selector::parse("tr") {
+                    for row in table.select(&row_selector) {
+                        // Cells are kept verbatim, blank ones included: indentation and
+                        // empty lines are part of the suggestion
+                        if let Ok(old_selector) = Selector::parse("td.left.old, td.old, .diff-old")
+                        {
+                            if let Some(old_cell) = row.select(&old_selector).next() {
+                                diff_lines.push(format!("- {}", cell_source_text(&old_cell)));
+                            }
+                        }
+
+                        if let Ok(new_selector) = Selector::parse("td.right.new, td.new, .diff-new")
+                        {
+                            if let Some(new_cell) = row.select(&new_selector).next() {
+                                diff_lines.push(format!("+ {}", cell_source_text(&new_cell)));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        if !diff_lines.is_empty() {
+            Some(diff_lines.join("\n"))
         } else {
-            json_response
-        };
+            None
+        }
+    }
 
-        // Parse the JSON response to extract suggestionText
-        match serde_json::from_str::<serde_json::Value>(clean_json) {
-            Ok(json) => {
-                trace!("Successfully parsed suggestion JSON");
-                // Look for suggestionText in the JSON structure
-                if let Some(suggestion_text) = self.find_suggestion_text_recursive(&json) {
-                    if !suggestion_text.trim().is_empty() {
-                        return Some(format!(
-                            "**Suggested changes:**\n\n```diff\n{}\n```",
-                            suggestion_text.trim()
-                        ));
+
+    async fn get_csrf_token(&self, revision_id: u32) -> Option<String> {
+        let review_url = format!("{}/D{}", self.base_url, revision_id);
+
+        if let Ok(response) = self.client.get(&review_url).send().await {
+            if let Ok(html) = response.text().await {
+                let document = Html::parse_document(&html);
+
+                // Look for CSRF token in meta tag
+                let meta_selector = Selector::parse("meta[name='csrf-token']").ok()?;
+                if let Some(meta) = document.select(&meta_selector).next() {
+                    return meta.value().attr("content").map(|s| s.to_string());
+                }
+
+                // Look for CSRF token in script tags
+                let script_selector = Selector::parse("script").ok()?;
+                let csrf_regex = Regex::new(r#"csrf["']?\s*:\s*["']([^"']+)"#).ok()?;
+
+                for script in document.select(&script_selector) {
+                    if let Some(script_content) = script.text().next() {
+                        if script_content.to_lowercase().contains("csrf") {
+                            if let Some(captures) = csrf_regex.captures(script_content) {
+                                return captures.get(1).map(|m| m.as_str().to_string());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    async fn get_user_info(&mut self, user_phid: &str) -> String {
+        if let Some(cached) = self.user_cache.get(user_phid) {
+            return cached.clone();
+        }
+
+        let params = [
+            ("api.token", self.api_token.as_str()),
+            ("constraints[phids][0]", user_phid),
+        ];
+
+        match self.conduit_call("user.search", &params, USER_CACHE_TTL).await {
+            Ok(response_text) => {
+                if let Ok(result) = serde_json::from_str::<UserSearchResult>(&response_text) {
+                    if result.error_code.is_some() {
+                        self.user_cache
+                            .insert(user_phid.to_string(), user_phid.to_string());
+                        return user_phid.to_string();
+                    }
+
+                    if let Some(data) = result.result {
+                        if let Some(user_data) = data.data.first() {
+                            let display_name = user_data
+                                .fields
+                                .display_name()
+                                .unwrap_or_else(|| user_phid.to_string());
+
+                            self.user_cache
+                                .insert(user_phid.to_string(), display_name.clone());
+                            return display_name;
+                        }
                     }
                 }
             }
             Err(e) => {
-                debug!("Failed to parse suggestion JSON: {}", e);
+                warn!("Failed to fetch user info for {}: {}", user_phid, e);
             }
         }
 
-        // Fallback: try to extract suggestionText using regex that handles escaped content
-        let re = regex::Regex::new(r#""suggestionText":"((?:[^"\\]|\\.)*)""#).unwrap();
-        if let Some(captures) = re.captures(json_response) {
-            if let Some(suggestion_match) = captures.get(1) {
-                let suggestion_text = suggestion_match
-                    .as_str()
-                    .replace("\\n", "\n")
-                    .replace("\\t", "\t")
-                    .replace("\\u003e", ">")
-                    .replace("\\u003c", "<")
-                    .replace("\\/", "/")
-                    .replace("\\\"", "\"")
-                    .replace("\\\\", "\\");
+        self.user_cache
+            .insert(user_phid.to_string(), user_phid.to_string());
+        user_phid.to_string()
+    }
 
-                if !suggestion_text.trim().is_empty() {
-                    return Some(format!(
-                        "**Suggested changes:**\n\n```diff\n{}\n```",
-                        suggestion_text.trim()
-                    ));
+    /// Resolves many user PHIDs with batched `user.search` calls into the cache
+    /// `get_user_info` reads from. Users left unresolved are looked up one by one later.
+    async fn prefetch_user_info(&mut self, phids: &[String]) {
+        let unknown: BTreeSet<&String> = phids
+            .iter()
+            .filter(|phid| phid.starts_with("PHID-USER-") && !self.user_cache.contains_key(*phid))
+            .collect();
+        let unknown: Vec<&String> = unknown.into_iter().collect();
+
+        for chunk in unknown.chunks(100) {
+            let mut params = vec![
+                ("api.token".to_string(), self.api_token.clone()),
+                ("limit".to_string(), "100".to_string()),
+            ];
+            for (i, phid) in chunk.iter().enumerate() {
+                params.push((format!("constraints[phids][{}]", i), phid.to_string()));
+            }
+            let params: Vec<(&str, &str)> = params
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect();
+
+            let response_text = match self.conduit_call("user.search", &params, USER_CACHE_TTL).await {
+                Ok(text) => text,
+                Err(e) => {
+                    warn!("Failed to fetch user info for {} users: {}", chunk.len(), e);
+                    continue;
+                }
+            };
+            let Some(data) = serde_json::from_str::<UserSearchResult>(&response_text)
+                .ok()
+                .and_then(|result| result.result)
+            else {
+                continue;
+            };
+            for user in data.data {
+                if let (Some(phid), Some(name)) = (user.phid, user.fields.display_name()) {
+                    self.user_cache.insert(phid, name);
                 }
             }
-        } else {
-            debug!("No suggestionText found using regex");
         }
+    }
 
-        None
+    async fn get_revision_phid(&self, diff_id: u32) -> Result<String> {
+        Ok(self.get_revision_data(diff_id).await?.phid)
     }
 
-    fn extract_suggestion_from_json_for_line(&self, json_response: &str, line_number: u32, line_length: u32) -> Option<String> {
-        let clean_json = if json_response.starts_with("for (;;);") {
-            &json_response[9..]
+    async fn get_revision_data(&self, diff_id: u32) -> Result<RevisionData> {
+        let diff_id_str = diff_id.to_string();
+        let params = [
+            ("api.token", self.api_token.as_str()),
+            ("constraints[ids][0]", diff_id_str.as_str()),
+            ("attachments[reviewers]", "1"),
+            ("attachments[subscribers]", "1"),
+        ];
+
+        debug!("Fetching revision data for diff_id={}", diff_id);
+
+        // Status and reviewers change as the review progresses
+        let response_text = self
+            .conduit_call("differential.revision.search", &params, ACTIVITY_CACHE_TTL)
+            .await?;
+
+        let result: RevisionSearchResult =
+            serde_json::from_str(&response_text).context(format!(
+                "Failed to parse JSON response. Response was: {}",
+                response_text
+            ))?;
+
+        if let Some(error_code) = result.error_code {
+            anyhow::bail!(
+                "API Error: {} - {}",
+                error_code,
+                result.error_info.unwrap_or_default()
+            );
+        }
+
+        let data = result.result.context("No result data")?;
+        data.data.into_iter().next().context("No revision found")
+    }
+
+    /// Resolves the revision's fields and attachments into display names.
+    async fn revision_info(&mut self, revision: RevisionData) -> RevisionInfo {
+        let fields = revision.fields;
+        let attachments = revision.attachments;
+        let reviewers = attachments
+            .as_ref()
+            .and_then(|a| a.reviewers.as_ref())
+            .map(|r| r.reviewers.as_slice())
+            .unwrap_or_default();
+        let subscriber_phids = attachments
+            .as_ref()
+            .and_then(|a| a.subscribers.as_ref())
+            .map(|s| s.subscriber_phids.as_slice())
+            .unwrap_or_default();
+        let author_phid = fields
+            .as_ref()
+            .and_then(|f| f.author_phid.clone())
+            .unwrap_or_default();
+        let repository_phid = fields.as_ref().and_then(|f| f.repository_phid.clone());
+
+        // Users get the usual "Real Name (username)"; projects and repositories their full name
+        let mut other_phids: Vec<String> = reviewers
+            .iter()
+            .map(|r| r.reviewer_phid.clone())
+            .chain(subscriber_phids.iter().cloned())
+            .filter(|phid| !phid.starts_with("PHID-USER-"))
+            .collect();
+        other_phids.extend(repository_phid.clone());
+        let other_names = if other_phids.is_empty() {
+            HashMap::new()
         } else {
-            json_response
+            self.get_phid_names(&other_phids).await.unwrap_or_else(|e| {
+                warn!("Could not resolve reviewer and repository names: {:#}", e);
+                HashMap::new()
+            })
         };
 
-        if let Ok(json) = serde_json::from_str::<serde_json::Value>(clean_json) {
-            // Walk tree and find objects with `line` (or similar) and `suggestionText`; choose nearest/intersecting
-            let mut best_text: Option<String> = None;
-            let mut best_dist: i64 = i64::MAX;
-            fn visit(value: &serde_json::Value, target_start: u32, target_len: u32, best_dist: &mut i64, best_text: &mut Option<String>) {
-                match value {
-                    serde_json::Value::Object(map) => {
-                        let line = map
-                            .get("line").and_then(|v| v.as_u64()).map(|v| v as u32)
-                            .or_else(|| map.get("lineNumber").and_then(|v| v.as_u64()).map(|v| v as u32))
-                            .or_else(|| map.get("newLine").and_then(|v| v.as_u64()).map(|v| v as u32));
-                        let length = map
-                            .get("length").and_then(|v| v.as_u64()).map(|v| v as u32)
-                            .or_else(|| map.get("len").and_then(|v| v.as_u64()).map(|v| v as u32))
-                            .unwrap_or(1);
-                        let text = map.get("suggestionText").and_then(|v| v.as_str());
-                        if let (Some(l), Some(t)) = (line, text) {
-                            let target_end = target_start + target_len.saturating_sub(1);
-                            let block_end = l + length.saturating_sub(1);
-                            let intersects = block_end >= target_start && l <= target_end;
-                            let d = if intersects { 0 } else { (l as i64 - target_start as i64).abs() };
-                            if !t.trim().is_empty() && d < *best_dist {
-                                *best_dist = d;
-                                *best_text = Some(t.to_string());
-                            }
-                        }
-                        for (_k, v) in map.iter() { visit(v, target_start, target_len, best_dist, best_text); }
-                    }
-                    serde_json::Value::Array(arr) => {
-                        for v in arr { visit(v, target_start, target_len, best_dist, best_text); }
-                    }
-                    _ => {}
-                }
+        let user_phids: Vec<String> = reviewers
+            .iter()
+            .map(|r| r.reviewer_phid.clone())
+            .chain(subscriber_phids.iter().cloned())
+            .chain(Some(author_phid.clone()))
+            .collect();
+        self.prefetch_user_info(&user_phids).await;
+
+        let mut reviewer_infos = Vec::new();
+        for reviewer in reviewers {
+            reviewer_infos.push(ReviewerInfo {
+                name: self.display_name(&reviewer.reviewer_phid, &other_names).await,
+                phid: reviewer.reviewer_phid.clone(),
+                status: reviewer.status.clone().unwrap_or_default(),
+                is_blocking: reviewer.is_blocking.unwrap_or(false),
+            });
+        }
+        let mut subscribers = Vec::new();
+        for phid in subscriber_phids {
+            subscribers.push(self.display_name(phid, &other_names).await);
+        }
+
+        let author = if author_phid.is_empty() {
+            String::new()
+        } else {
+            self.get_user_info(&author_phid).await
+        };
+        let bug_id = fields
+            .as_ref()
+            .and_then(|f| f.bug_id.as_ref())
+            .and_then(|b| match b {
+                serde_json::Value::String(s) if !s.is_empty() => Some(s.clone()),
+                serde_json::Value::Number(n) => Some(n.to_string()),
+                _ => None,
+            });
+        let status = fields
+            .as_ref()
+            .and_then(|f| f.status.as_ref())
+            .and_then(|s| s.name.clone().or_else(|| s.value.clone()))
+            .unwrap_or_default();
+
+        RevisionInfo {
+            title: fields.as_ref().and_then(|f| f.title.clone()).unwrap_or_default(),
+            author,
+            author_phid,
+            status,
+            summary: fields.as_ref().and_then(|f| f.summary.clone()).unwrap_or_default(),
+            test_plan: fields.as_ref().and_then(|f| f.test_plan.clone()).unwrap_or_default(),
+            bug_id,
+            repository: repository_phid.map(|phid| other_names.get(&phid).cloned().unwrap_or(phid)),
+            reviewers: reviewer_infos,
+            subscribers,
+        }
+    }
+
+    async fn display_name(&mut self, phid: &str, other_names: &HashMap<String, String>) -> String {
+        if phid.starts_with("PHID-USER-") {
+            self.get_user_info(phid).await
+        } else {
+            other_names.get(phid).cloned().unwrap_or_else(|| phid.to_string())
+        }
+    }
+
+    /// Looks up the full names of arbitrary objects (projects, repositories, ...).
+    async fn get_phid_names(&self, phids: &[String]) -> Result<HashMap<String, String>> {
+        let mut names = HashMap::new();
+        for (phid, object) in self.phid_lookup(phids).await? {
+            let name = object
+                .get("fullName")
+                .or_else(|| object.get("name"))
+                .and_then(|n| n.as_str());
+            if let Some(name) = name {
+                names.insert(phid, name.to_string());
             }
-            visit(&json, line_number, line_length, &mut best_dist, &mut best_text);
-            if let Some(t) = best_text { return Some(format!("**Suggested changes:**\n\n```diff\n{}\n```", t.trim())); }
         }
-        None
+        Ok(names)
     }
 
-    fn find_suggestion_text_recursive(&self, value: &serde_json::Value) -> Option<String> {
-        match value {
-            serde_json::Value::Object(map) => {
-                // Check if this object has suggestionText
-                if let Some(suggestion_text) = map.get("suggestionText") {
-                    if let Some(text) = suggestion_text.as_str() {
-                        if !text.trim().is_empty() { return Some(text.to_string()); }
-                    }
-                }
+    /// Resolves monograms such as `D123`, `T456` or `F789` to their URL and title.
+    async fn lookup_object_refs(&self, names: &[String]) -> Result<HashMap<String, ObjectReference>> {
+        let mut references = HashMap::new();
+        for (name, object) in self.phid_lookup(names).await? {
+            let field = |key: &str| object.get(key).and_then(|v| v.as_str()).unwrap_or_default();
+            if field("uri").is_empty() {
+                continue;
+            }
+            // fullName is "D123: Title"; objects without a title repeat the name
+            let full_name = field("fullName");
+            let title = full_name
+                .strip_prefix(&format!("{}: ", field("name")))
+                .or(Some(full_name).filter(|f| !f.is_empty() && *f != field("name")))
+                .map(|t| t.to_string());
+            references.insert(
+                name,
+                ObjectReference {
+                    uri: field("uri").to_string(),
+                    type_name: field("typeName").to_string(),
+                    title,
+                },
+            );
+        }
+        Ok(references)
+    }
 
-                // Recursively search in all object values
-                for (_key, val) in map {
-                    if let Some(result) = self.find_suggestion_text_recursive(val) {
-                        return Some(result);
-                    }
-                }
+    /// Calls `phid.lookup`, which accepts both monograms and PHIDs, and returns the
+    /// found objects keyed by the name they were asked for. Unknown names are left out.
+    async fn phid_lookup(&self, names: &[String]) -> Result<serde_json::Map<String, serde_json::Value>> {
+        let mut params = vec![("api.token".to_string(), self.api_token.clone())];
+        for (i, name) in names.iter().enumerate() {
+            params.push((format!("names[{}]", i), name.clone()));
+        }
+        let params: Vec<(&str, &str)> = params
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+
+        let response_text = self
+            .conduit_call("phid.lookup", &params, REVISION_CACHE_TTL)
+            .await?;
+        let result: serde_json::Value = serde_json::from_str(&response_text)
+            .context("Failed to parse phid.lookup response")?;
+        if let Some(error_code) = result.get("error_code").and_then(|c| c.as_str()) {
+            anyhow::bail!(
+                "API Error: {} - {}",
+                error_code,
+                result.get("error_info").and_then(|i| i.as_str()).unwrap_or_default()
+            );
+        }
+
+        match result.get("result") {
+            Some(serde_json::Value::Object(objects)) => Ok(objects.clone()),
+            // An empty result comes back as a JSON array
+            _ => Ok(serde_json::Map::new()),
+        }
+    }
+
+    /// Looks up every object the revision's comments mention, for `--expand-refs`.
+    async fn resolve_references(&mut self, comments_data: &mut CommentsData) {
+        let names: BTreeSet<String> = comments_data
+            .remarkup_texts()
+            .into_iter()
+            .flat_map(|text| self.remarkup.monograms(text))
+            .map(|name| name.to_string())
+            .collect();
+
+        let unknown: Vec<String> = names
+            .iter()
+            .filter(|name| !self.remarkup.references.contains_key(*name))
+            .cloned()
+            .collect();
+        for batch in unknown.chunks(100) {
+            match self.lookup_object_refs(batch).await {
+                Ok(found) => self.remarkup.references.extend(found),
+                Err(e) => warn!("Could not resolve object references: {:#}", e),
             }
-            serde_json::Value::Array(arr) => {
-                // Recursively search in all array elements
-                for val in arr {
-                    if let Some(result) = self.find_suggestion_text_recursive(val) {
-                        return Some(result);
-                    }
-                }
+        }
+        debug!("{} of {} object references resolved", self.remarkup.references.len(), names.len());
+
+        comments_data.references = names
+            .into_iter()
+            .filter_map(|name| {
+                let reference = self.remarkup.references.get(&name)?.clone();
+                Some((name, reference))
+            })
+            .collect();
+    }
+
+    /// Finds the PHID and name of files by ID.
+    async fn get_file_info(&self, ids: &[u64]) -> Result<HashMap<u64, (String, String)>> {
+        let mut params = vec![("api.token".to_string(), self.api_token.clone())];
+        for (i, id) in ids.iter().enumerate() {
+            params.push((format!("constraints[ids][{}]", i), id.to_string()));
+        }
+        let params: Vec<(&str, &str)> = params
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+
+        let response_text = self
+            .conduit_call("file.search", &params, REVISION_CACHE_TTL)
+            .await?;
+        let result: serde_json::Value = serde_json::from_str(&response_text)
+            .context("Failed to parse file.search response")?;
+        if let Some(error_code) = result.get("error_code").and_then(|c| c.as_str()) {
+            anyhow::bail!(
+                "API Error: {} - {}",
+                error_code,
+                result.get("error_info").and_then(|i| i.as_str()).unwrap_or_default()
+            );
+        }
+
+        let mut files = HashMap::new();
+        let data = result.pointer("/result/data").and_then(|d| d.as_array());
+        for file in data.into_iter().flatten() {
+            let id = file.get("id").and_then(|i| i.as_u64());
+            let phid = file.get("phid").and_then(|p| p.as_str());
+            let name = file.pointer("/fields/name").and_then(|n| n.as_str());
+            if let (Some(id), Some(phid)) = (id, phid) {
+                let name = name.filter(|n| !n.is_empty()).map(|n| n.to_string());
+                files.insert(id, (phid.to_string(), name.unwrap_or_else(|| format!("F{}", id))));
             }
-            _ => {}
         }
-        None
+        Ok(files)
     }
 
-    async fn find_suggestions_in_html(
-        &self,
-        document: &Html,
-        _line_number: u32,
-        _file_path: &str,
-        include_done: bool,
-    ) -> Option<String> {
-        // Look for inline-suggestion-view elements
-        if let Ok(suggestion_selector) = Selector::parse(".inline-suggestion-view") {
-            let suggestions: Vec<_> = document.select(&suggestion_selector).collect();
-            debug!(".inline-suggestion-view blocks found: {}", suggestions.len());
+    /// Fetches a file's contents through `file.download`.
+    async fn download_file(&self, phid: &str) -> Result<Vec<u8>> {
+        let params = [("api.token", self.api_token.as_str()), ("phid", phid)];
+        let response_text = self.conduit_call("file.download", &params, NO_CACHE).await?;
+        let result: serde_json::Value = serde_json::from_str(&response_text)
+            .context("Failed to parse file.download response")?;
+        if let Some(error_code) = result.get("error_code").and_then(|c| c.as_str()) {
+            anyhow::bail!(
+                "API Error: {} - {}",
+                error_code,
+                result.get("error_info").and_then(|i| i.as_str()).unwrap_or_default()
+            );
+        }
+        let data = result
+            .get("result")
+            .and_then(|r| r.as_str())
+            .context("file.download returned no data")?;
+        base64::engine::general_purpose::STANDARD
+            .decode(data)
+            .context("file.download returned invalid base64")
+    }
 
-            // Extract suggestions from available suggestion elements
-            for suggestion in suggestions.iter() {
-                // Check if this suggestion is marked as "done"
-                let is_done = self.is_suggestion_done(suggestion);
-                if is_done && !include_done {
-                    debug!("skipping DONE suggestion block");
-                    continue;
-                }
+    /// Saves every file embedded in the revision's comments, for `--download-attachments`.
+    /// Files already on disk are not downloaded again.
+    async fn download_attachments(&mut self, comments_data: &mut CommentsData) {
+        let Some(dir) = self.attachment_dir.clone() else { return };
+        let ids: BTreeSet<u64> = comments_data
+            .remarkup_texts()
+            .into_iter()
+            .flat_map(|text| self.remarkup.embedded_files(text))
+            .collect();
+
+        let unknown: Vec<u64> = ids
+            .iter()
+            .filter(|id| !self.remarkup.attachments.contains_key(&format!("F{}", id)))
+            .copied()
+            .collect();
+        let mut files = HashMap::new();
+        for batch in unknown.chunks(100) {
+            match self.get_file_info(batch).await {
+                Ok(found) => files.extend(found),
+                Err(e) => warn!("Could not look up attached files: {:#}", e),
+            }
+        }
 
-                // Extract the suggestion content from the table structure
-                if let Some(suggestion_text) = self.extract_suggestion_from_table(suggestion) {
-                    debug!("chose suggestion from HTML table");
-                    return Some(suggestion_text);
+        for id in unknown {
+            let Some((phid, name)) = files.get(&id) else {
+                warn!("F{} is not visible to this token, not downloading it", id);
+                continue;
+            };
+            let safe_name: String = name
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() || "._-".contains(c) { c } else { '_' })
+                .collect();
+            let file_name = format!("F{}-{}", id, safe_name);
+            let path = dir.join(&file_name);
+            if !path.exists() {
+                let result = match self.download_file(phid).await {
+                    Ok(contents) => fs::write(&path, contents).context(format!("Failed to write {}", path.display())),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    warn!("Could not download F{}: {:#}", id, e);
+                    continue;
                 }
+                info!("Saved F{} to {}", id, path.display());
             }
+            let link = if self.attachment_link_dir.is_empty() {
+                file_name
+            } else {
+                format!("{}/{}", self.attachment_link_dir, file_name)
+            };
+            self.remarkup.attachments.insert(
+                format!("F{}", id),
+                Attachment {
+                    name: name.clone(),
+                    path,
+                    link,
+                },
+            );
         }
 
-        None
+        comments_data.attachments = ids
+            .into_iter()
+            .filter_map(|id| {
+                let name = format!("F{}", id);
+                let attachment = self.remarkup.attachments.get(&name)?.clone();
+                Some((name, attachment))
+            })
+            .collect();
     }
 
-    fn extract_diff_from_changeset(&self, ajax_response: &str) -> Option<String> {
-        let mut response = ajax_response;
+    /// Makes a Conduit call that changes something, failing on API errors.
+    async fn conduit_write(&self, method: &str, params: &[(&str, &str)]) -> Result<serde_json::Value> {
+        let response_text = self.conduit_call(method, params, NO_CACHE).await?;
+        let result: serde_json::Value = serde_json::from_str(&response_text)
+            .context(format!("Failed to parse {} response", method))?;
+        if let Some(error_code) = result.get("error_code").and_then(|c| c.as_str()) {
+            anyhow::bail!(
+                "API Error: {} - {}",
+                error_code,
+                result.get("error_info").and_then(|i| i.as_str()).unwrap_or_default()
+            );
+        }
+        Ok(result)
+    }
 
-        // Remove the for (;;); prefix if present
-        if response.starts_with("for (;;);") {
-            response = &response[9..];
+    /// Replies to a comment: a threaded inline reply for inline comments, a quoting
+    /// comment for general ones. Only previews unless `confirm` is set; inline
+    /// replies are refused unless `publish_drafts` is set too.
+    async fn reply_to_comment(
+        &self,
+        extracted: &[(u32, CommentsData)],
+        comment_id: &str,
+        message: &str,
+        confirm: bool,
+        publish_drafts: bool,
+    ) -> Result<()> {
+        let inline = extracted.iter().find_map(|(diff_id, data)| {
+            let comment = data.inline_comments.iter().find(|c| c.comment_id == comment_id)?;
+            Some((*diff_id, comment))
+        });
+        let general = extracted.iter().find_map(|(diff_id, data)| {
+            let comment = data.general_comments.iter().find(|c| c.comment_id == comment_id)?;
+            Some((*diff_id, comment))
+        });
+
+        if let Some((revision_id, comment)) = inline {
+            let diff_id = comment.diff_id.trim_matches('"');
+            println!(
+                "{} to inline comment {} by {} on {}:{} (D{}, diff {}):",
+                if confirm { "Replying" } else { "Would reply" },
+                comment_id,
+                comment.author,
+                comment.file_path,
+                comment.line_number,
+                revision_id,
+                diff_id
+            );
+            println!("{}", message);
+            check_draft_publishing(&[revision_id], confirm, publish_drafts)?;
+            if !confirm {
+                println!("Dry run: nothing was posted.");
+                return Ok(());
+            }
+
+            let domain = Url::parse(&self.base_url)?
+                .host_str()
+                .context("Base URL has no host")?
+                .to_string();
+            let csrf_token = self
+                .get_csrf_token_with_cookies(revision_id, &domain)
+                .await
+                .context("Could not get a CSRF token; make sure you are logged in to Phabricator in your browser")?;
+            self.post_inline_reply(revision_id, comment, message, &csrf_token, &domain)
+                .await
+                .context("Failed to create the inline reply")?;
+            let revision_id = revision_id.to_string();
+            self.conduit_write(
+                "differential.createcomment",
+                &[
+                    ("api.token", self.api_token.as_str()),
+                    ("revision_id", revision_id.as_str()),
+                    ("attach_inlines", "1"),
+                ],
+            )
+            .await
+            .context("Failed to publish the inline reply")?;
+            println!("Reply posted to D{}", revision_id);
+            return Ok(());
         }
 
-        // Parse JSON response
-        match serde_json::from_str::<serde_json::Value>(response) {
-            Ok(data) => {
-                trace!("Successfully parsed changeset diff JSON");
-                if let Some(payload) = data.get("payload") {
-                    if let Some(changeset_html) = payload.get("changeset") {
-                        if let Some(html_str) = changeset_html.as_str() {
-                            // Parse HTML and extract diff content using cell-specific selectors
-                            debug!("Parsing HTML for diff content extraction");
-                            let document = Html::parse_document(html_str);
+        let Some((revision_id, comment)) = general else {
+            anyhow::bail!(
+                "No comment {} found in the extracted revisions (is it hidden by a filter?)",
+                comment_id
+            );
+        };
+        // Quote the comment the way the web UI's "Quote" action does
+        let username = comment
+            .author
+            .rsplit_once(" (")
+            .and_then(|(_, username)| username.strip_suffix(')'))
+            .unwrap_or(&comment.author);
+        let mut text = format!(">>! In D{}#{}, @{} wrote:\n", revision_id, comment.transaction_id, username);
+        for line in comment.content.lines() {
+            text.push_str(&format!("> {}\n", line));
+        }
+        text.push('\n');
+        text.push_str(message);
+
+        println!(
+            "{} to comment {} by {} on D{}:",
+            if confirm { "Replying" } else { "Would reply" },
+            comment_id,
+            comment.author,
+            revision_id
+        );
+        println!("{}", text);
+        if !confirm {
+            println!("Dry run: nothing was posted.");
+            return Ok(());
+        }
+        let object = format!("D{}", revision_id);
+        self.conduit_write(
+            "differential.revision.edit",
+            &[
+                ("api.token", self.api_token.as_str()),
+                ("objectIdentifier", object.as_str()),
+                ("transactions[0][type]", "comment"),
+                ("transactions[0][value]", text.as_str()),
+            ],
+        )
+        .await
+        .context("Failed to post the reply")?;
+        println!("Reply posted to D{}", revision_id);
+        Ok(())
+    }
 
-                            if let Ok(row_sel) = Selector::parse("tr") {
-                                let mut diff_lines = Vec::new();
-                                for row in document.select(&row_sel) {
-                                    // Old/removed cells
-                                    if let Ok(old_sel) = Selector::parse("td.left.old, td.left.old.old-full, td.old, .diff-old") {
-                                        if let Some(old_cell) = row.select(&old_sel).next() {
-                                            let mut s = old_cell.text().collect::<String>().replace('\u{00A0}', " ");
-                                            s = s.replace('\r', "");
-                                            s = s.replace('\n', "");
-                                            while s.starts_with("- ") { s = s[2..].to_string(); }
-                                            if !s.trim().is_empty() && s != "-" { diff_lines.push(format!("- {}", s)); }
-                                        }
-                                    }
-                                    // New/added cells
-                                    if let Ok(new_sel) = Selector::parse("td.right.new, td.right.new.new-full, td.new, .diff-new") {
-                                        if let Some(new_cell) = row.select(&new_sel).next() {
-                                            let mut s = new_cell.text().collect::<String>().replace('\u{00A0}', " ");
-                                            s = s.replace('\r', "");
-                                            s = s.replace('\n', "");
-                                            while s.starts_with("+ ") { s = s[2..].to_string(); }
-                                            if !s.trim().is_empty() && s != "+" { diff_lines.push(format!("+ {}", s)); }
-                                        }
-                                    }
-                                }
-                                if !diff_lines.is_empty() { return Some(diff_lines.join("\n")); }
-                            }
-                        }
-                    }
-                }
-            }
-            Err(e) => {
-                debug!("Failed to parse changeset diff JSON: {}", e);
+    /// Sets the done state of inline comments through the web UI's inline endpoint,
+    /// then submits a comment so the done marks are published. Only previews unless
+    /// `confirm` is set, and refuses unless `publish_drafts` is set too.
+    async fn mark_inlines_done(
+        &self,
+        extracted: &[(u32, CommentsData)],
+        comment_ids: &[String],
+        done: bool,
+        message: Option<&str>,
+        confirm: bool,
+        publish_drafts: bool,
+    ) -> Result<()> {
+        let state = if done { "done" } else { "not done" };
+        let plan = DoneMarkPlan::new(extracted, comment_ids, done)?;
+        for comment in &plan.unchanged {
+            println!("Inline comment {} on {}:{} is already {}", comment.comment_id, comment.file_path, comment.line_number, state);
+        }
+        for (revision_id, comment) in &plan.toggles {
+            println!(
+                "{} inline comment {} by {} on {}:{} (D{}) as {}",
+                if confirm { "Marking" } else { "Would mark" },
+                comment.comment_id,
+                comment.author,
+                comment.file_path,
+                comment.line_number,
+                revision_id,
+                state
+            );
+        }
+        if let Some(message) = message {
+            println!("With the comment:\n{}", message);
+        }
+        check_draft_publishing(&plan.revisions, confirm, publish_drafts)?;
+        if !confirm {
+            println!("Dry run: nothing was changed.");
+            return Ok(());
+        }
+        if plan.toggles.is_empty() {
+            return Ok(());
+        }
+
+        let domain = Url::parse(&self.base_url)?
+            .host_str()
+            .context("Base URL has no host")?
+            .to_string();
+        // The token comes from the revision page, so fetch it once per revision
+        let mut csrf_tokens: HashMap<u32, String> = HashMap::new();
+        for (revision_id, comment) in &plan.toggles {
+            let csrf_token = match csrf_tokens.entry(*revision_id) {
+                std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                std::collections::hash_map::Entry::Vacant(entry) => entry.insert(
+                    self.get_csrf_token_with_cookies(*revision_id, &domain)
+                        .await
+                        .context("Could not get a CSRF token; make sure you are logged in to Phabricator in your browser")?,
+                ),
+            };
+            let now_done = self
+                .toggle_inline_done(*revision_id, &comment.comment_id, csrf_token, &domain)
+                .await
+                .context(format!("Failed to mark inline comment {} as {}", comment.comment_id, state))?;
+            if now_done != Some(done) {
+                warn!("Phabricator did not confirm inline comment {} is now {}", comment.comment_id, state);
             }
         }
 
-        None
+        // Done marks are drafts until a comment is submitted on the revision
+        for revision_id in plan.revisions {
+            let revision_id = revision_id.to_string();
+            let mut params = vec![
+                ("api.token", self.api_token.as_str()),
+                ("revision_id", revision_id.as_str()),
+                ("attach_inlines", "1"),
+            ];
+            if let Some(message) = message {
+                params.push(("message", message));
+            }
+            self.conduit_write("differential.createcomment", &params)
+                .await
+                .context(format!("Failed to publish the done marks on D{}", revision_id))?;
+            println!("Done marks published on D{}", revision_id);
+        }
+        Ok(())
     }
 
-    /// Extracts suggestion diff content from HTML table showing old/new lines
-    fn extract_inline_suggestion(&self, html: &str) -> Option<String> {
-        // Look for inline-suggestion-view content
-        if let Some(start) = html.find("inline-suggestion-view") {
-            // Find the table containing the suggestion
-            let search_area = &html[start..];
-            if let Some(table_start) = search_area.find("<table") {
-                if let Some(table_end) = search_area.find("</table>") {
-                    let table_html = &search_area[table_start..table_end + 8];
+    /// Toggles an inline comment's done checkbox, as clicking it in the web UI does.
+    /// Returns the new state when the response reports it.
+    async fn toggle_inline_done(
+        &self,
+        revision_id: u32,
+        comment_id: &str,
+        csrf_token: &str,
+        domain: &str,
+    ) -> Result<Option<bool>> {
+        let json = self
+            .post_inline_edit(
+                revision_id,
+                &[("op", "done"), ("id", comment_id)],
+                csrf_token,
+                domain,
+                &format!("inline_done_{}.txt", comment_id),
+            )
+            .await?;
+        Ok(json.pointer("/payload/isChecked").and_then(|c| c.as_bool()))
+    }
 
-                    // Parse the table to extract old and new lines
-                    let document = Html::parse_document(table_html);
-                    let mut diff_lines = Vec::new();
+    /// Creates a draft inline reply to `parent`, as the web UI's "Reply" does.
+    /// Phabricator threads it under the parent and places it on the parent's
+    /// changeset, side and lines, which Conduit's `differential.createinline` cannot do.
+    async fn post_inline_reply(
+        &self,
+        revision_id: u32,
+        parent: &InlineComment,
+        message: &str,
+        csrf_token: &str,
+        domain: &str,
+    ) -> Result<()> {
+        if parent.comment_phid.is_empty() {
+            anyhow::bail!("Inline comment {} has no PHID to reply to", parent.comment_id);
+        }
+        // The location comes from the parent, so none is sent
+        self.post_inline_edit(
+            revision_id,
+            &[
+                ("op", "new"),
+                ("replyToCommentPHID", parent.comment_phid.as_str()),
+                ("text", message),
+            ],
+            csrf_token,
+            domain,
+            &format!("inline_reply_{}.txt", parent.comment_id),
+        )
+        .await?;
+        Ok(())
+    }
 
-                    if let Ok(row_selector) = Selector::parse("tr") {
-                        for row in document.select(&row_selector) {
-                            if let Ok(old_sel) = Selector::parse("td.left.old, td.left.old.old-full, td.old, .diff-old") {
-                                if let Some(old_cell) = row.select(&old_sel).next() {
-                                    let mut s = old_cell.text().collect::<String>().replace('\u{00A0}', " ");
-                                    s = s.replace('\r', "");
-                                    s = s.replace('\n', "");
-                                    while s.starts_with("- ") { s = s[2..].to_string(); }
-                                    if !s.trim().is_empty() && s != "-" { diff_lines.push(format!("- {}", s)); }
-                                }
-                            }
-                            if let Ok(new_sel) = Selector::parse("td.right.new, td.right.new.new-full, td.new, .diff-new") {
-                                if let Some(new_cell) = row.select(&new_sel).next() {
-                                    let mut s = new_cell.text().collect::<String>().replace('\u{00A0}', " ");
-                                    s = s.replace('\r', "");
-                                    s = s.replace('\n', "");
-                                    while s.starts_with("+ ") { s = s[2..].to_string(); }
-                                    if !s.trim().is_empty() && s != "+" { diff_lines.push(format!("+ {}", s)); }
-                                }
-                            }
-                        }
-                    }
+    /// Posts a form to the web UI's inline comment endpoint with the browser session.
+    async fn post_inline_edit(
+        &self,
+        revision_id: u32,
+        form: &[(&str, &str)],
+        csrf_token: &str,
+        domain: &str,
+        dump_name: &str,
+    ) -> Result<serde_json::Value> {
+        let url = format!("{}/differential/comment/inline/edit/{}/", self.base_url, revision_id);
+        let cookies = self.extract_browser_cookies(domain).await?;
+        let cookie_string = cookies
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("; ");
+        let mut form_data = vec![("__csrf__", csrf_token), ("__form__", "1"), ("__ajax__", "true")];
+        form_data.extend_from_slice(form);
+        let text = self
+            .client
+            .post(&url)
+            .header("X-Phabricator-Csrf", csrf_token)
+            .header("X-Phabricator-Via", format!("/D{}", revision_id))
+            .header("Cookie", cookie_string)
+            .form(&form_data)
+            .send()
+            .await
+            .context(format!("Failed to send request to {}", url))?
+            .text()
+            .await?;
+        self.maybe_dump(dump_name, &text);
 
-                    if !diff_lines.is_empty() {
-                        return Some(diff_lines.join("\n"));
-                    }
-                }
-            }
+        // The AJAX response starts with for (;;); followed by JSON
+        let json: serde_json::Value = serde_json::from_str(text.strip_prefix("for (;;);").unwrap_or(&text))
+            .context("Unexpected response from the inline comment endpoint")?;
+        if let Some(error) = json.get("error").filter(|e| !e.is_null()) {
+            anyhow::bail!("Phabricator rejected the request: {}", error);
         }
-        None
+        Ok(json)
     }
 
-    fn is_suggestion_done(&self, suggestion_element: &scraper::ElementRef) -> bool {
-        // Look for parent inline comment that has "inline-is-done" class
-        let mut current = suggestion_element.parent();
-        while let Some(parent_node) = current {
-            if let Some(parent_element) = parent_node.value().as_element() {
-                if parent_element
-                    .classes()
-                    .any(|class| class == "inline-is-done")
-                {
-                    return true;
-                }
+    /// Resolves usernames to user PHIDs. Names that do not exist are an error when
+    /// `required`, and are otherwise skipped with a warning.
+    async fn get_user_phids(&self, usernames: &[String], required: bool) -> Result<HashSet<String>> {
+        let mut params = vec![("api.token".to_string(), self.api_token.clone())];
+        for (i, username) in usernames.iter().enumerate() {
+            params.push((format!("constraints[usernames][{}]", i), username.clone()));
+        }
+        let params: Vec<(&str, &str)> = params
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+
+        let response_text = self
+            .conduit_call("user.search", &params, USER_CACHE_TTL)
+            .await?;
+        let result: UserSearchResult = serde_json::from_str(&response_text)
+            .context("Failed to parse user.search response")?;
+        if let Some(error_code) = result.error_code {
+            anyhow::bail!(
+                "API Error: {} - {}",
+                error_code,
+                result.error_info.unwrap_or_default()
+            );
+        }
+
+        let users = result.result.context("No result data")?.data;
+        let found: HashSet<String> = users
+            .iter()
+            .filter_map(|u| u.fields.username.as_deref())
+            .map(|u| u.to_lowercase())
+            .collect();
+        let missing: Vec<&str> = usernames
+            .iter()
+            .filter(|u| !found.contains(&u.to_lowercase()))
+            .map(|u| u.as_str())
+            .collect();
+        if !missing.is_empty() {
+            if required {
+                anyhow::bail!("Unknown Phabricator user(s): {}", missing.join(", "));
             }
-            current = parent_node.parent();
+            warn!("Ignoring unknown Phabricator user(s): {}", missing.join(", "));
         }
-        false
+        Ok(users.into_iter().filter_map(|u| u.phid).collect())
     }
 
-    fn extract_suggestion_from_table(
-        &self,
-        suggestion_element: &scraper::ElementRef,
-    ) -> Option<String> {
-        let mut diff_lines = Vec::new();
+    /// Fetches `revision.parent`/`revision.child` edges for the given revisions.
+    async fn get_revision_edges(&self, source_phids: &[String]) -> Result<Vec<EdgeData>> {
+        let mut edges = Vec::new();
+        let mut after: Option<String> = None;
 
-        // Strategy 1: Try to find table with diff content
-        if let Ok(table_selector) = Selector::parse("table") {
-            if let Some(table) = suggestion_element.select(&table_selector).next() {
-                if let Ok(row_selector) = Selector::parse("tr") {
-                    for row in table.select(&row_selector) {
-                        // Look for old lines (removed)
-                        if let Ok(old_selector) = Selector::parse("td.left.old, td.old, .diff-old")
-                        {
-                            if let Some(old_cell) = row.select(&old_selector).next() {
-                                let text = old_cell.text().collect::<String>().trim().to_string();
-                                if !text.is_empty() && text != "-" {
-                                    let cleaned = text.trim_start_matches("- ").trim();
-                                    if !cleaned.is_empty() {
-                                        diff_lines.push(format!("- {}", cleaned));
-                                    }
-                                }
-                            }
-                        }
+        loop {
+            let mut params: Vec<(String, String)> = vec![
+                ("api.token".to_string(), self.api_token.clone()),
+                ("types[0]".to_string(), "revision.parent".to_string()),
+                ("types[1]".to_string(), "revision.child".to_string()),
+            ];
+            for (i, phid) in source_phids.iter().enumerate() {
+                params.push((format!("sourcePHIDs[{}]", i), phid.clone()));
+            }
+            if let Some(after) = &after {
+                params.push(("after".to_string(), after.clone()));
+            }
+            let params: Vec<(&str, &str)> = params
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect();
+
+            // Stacks are rearranged while a review is going on
+            let response_text = self
+                .conduit_call("edge.search", &params, ACTIVITY_CACHE_TTL)
+                .await?;
+            let result: EdgeSearchResult = serde_json::from_str(&response_text)
+                .context("Failed to parse edge.search response")?;
+
+            if let Some(error_code) = result.error_code {
+                anyhow::bail!(
+                    "API Error: {} - {}",
+                    error_code,
+                    result.error_info.unwrap_or_default()
+                );
+            }
 
-                        // Look for new lines (added)
-                        if let Ok(new_selector) = Selector::parse("td.right.new, td.new, .diff-new")
-                        {
-                            if let Some(new_cell) = row.select(&new_selector).next() {
-                                let text = new_cell.text().collect::<String>().trim().to_string();
-                                if !text.is_empty() && text != "+" {
-                                    let cleaned = text.trim_start_matches("+ ").trim();
-                                    if !cleaned.is_empty() {
-                                        diff_lines.push(format!("+ {}", cleaned));
-                                    }
-                                }
-                            }
-                        }
-                    }
+            let data = result.result.context("No result data")?;
+            let page_len = data.data.len();
+            edges.extend(data.data);
+            match data.cursor.and_then(|c| c.after) {
+                Some(cursor) if page_len > 0 && after.as_deref() != Some(cursor.as_str()) => {
+                    after = Some(cursor)
                 }
+                _ => break,
             }
         }
 
-        if !diff_lines.is_empty() {
-            Some(diff_lines.join("\n"))
-        } else {
-            None
-        }
+        Ok(edges)
     }
 
+    async fn get_revision_ids(&self, phids: &[String]) -> Result<HashMap<String, u32>> {
+        let mut ids = HashMap::new();
 
-    async fn get_csrf_token(&self, revision_id: u32) -> Option<String> {
-        let review_url = format!("{}/D{}", self.base_url, revision_id);
+        for chunk in phids.chunks(100) {
+            let mut params: Vec<(String, String)> = vec![
+                ("api.token".to_string(), self.api_token.clone()),
+                ("limit".to_string(), "100".to_string()),
+            ];
+            for (i, phid) in chunk.iter().enumerate() {
+                params.push((format!("constraints[phids][{}]", i), phid.clone()));
+            }
+            let params: Vec<(&str, &str)> = params
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect();
+
+            // A revision's PHID and ID never change
+            let response_text = self
+                .conduit_call("differential.revision.search", &params, REVISION_CACHE_TTL)
+                .await?;
+            let result: RevisionSearchResult = serde_json::from_str(&response_text)
+                .context("Failed to parse differential.revision.search response")?;
+
+            if let Some(error_code) = result.error_code {
+                anyhow::bail!(
+                    "API Error: {} - {}",
+                    error_code,
+                    result.error_info.unwrap_or_default()
+                );
+            }
 
-        match self.client.get(&review_url).send().await {
-            Ok(response) => {
-                if let Ok(html) = response.text().await {
-                    let document = Html::parse_document(&html);
+            for revision in result.result.context("No result data")?.data {
+                ids.insert(revision.phid, revision.id);
+            }
+        }
 
-                    // Look for CSRF token in meta tag
-                    let meta_selector = Selector::parse("meta[name='csrf-token']").ok()?;
-                    if let Some(meta) = document.select(&meta_selector).next() {
-                        return meta.value().attr("content").map(|s| s.to_string());
-                    }
+        Ok(ids)
+    }
 
-                    // Look for CSRF token in script tags
-                    let script_selector = Selector::parse("script").ok()?;
-                    let csrf_regex = Regex::new(r#"csrf["']?\s*:\s*["']([^"']+)"#).ok()?;
+    /// Lists the IDs of every diff of a revision, oldest first.
+    async fn get_revision_diffs(&self, revision_phid: &str) -> Result<Vec<u32>> {
+        let mut diffs = Vec::new();
+        let mut after: Option<String> = None;
 
-                    for script in document.select(&script_selector) {
-                        if let Some(script_content) = script.text().next() {
-                            if script_content.to_lowercase().contains("csrf") {
-                                if let Some(captures) = csrf_regex.captures(script_content) {
-                                    return captures.get(1).map(|m| m.as_str().to_string());
-                                }
-                            }
-                        }
-                    }
+        loop {
+            let mut params = vec![
+                ("api.token", self.api_token.as_str()),
+                ("constraints[revisionPHIDs][0]", revision_phid),
+                ("limit", "100"),
+            ];
+            if let Some(after) = after.as_deref() {
+                params.push(("after", after));
+            }
+
+            // New diffs get uploaded while a review is going on
+            let response_text = self
+                .conduit_call("differential.diff.search", &params, ACTIVITY_CACHE_TTL)
+                .await?;
+            let result: DiffSearchResult = serde_json::from_str(&response_text)
+                .context("Failed to parse differential.diff.search response")?;
+
+            if let Some(error_code) = result.error_code {
+                anyhow::bail!(
+                    "API Error: {} - {}",
+                    error_code,
+                    result.error_info.unwrap_or_default()
+                );
+            }
+
+            let data = result.result.context("No result data")?;
+            diffs.extend(data.data.iter().map(|diff| diff.id));
+            match data.cursor.and_then(|c| c.after) {
+                Some(cursor) if !data.data.is_empty() && after.as_deref() != Some(cursor.as_str()) => {
+                    after = Some(cursor)
                 }
+                _ => break,
             }
-            Err(_) => {}
         }
 
-        None
+        diffs.sort_unstable();
+        diffs.dedup();
+        Ok(diffs)
     }
 
-    async fn get_user_info(&mut self, user_phid: &str) -> String {
-        if let Some(cached) = self.user_cache.get(user_phid) {
-            return cached.clone();
-        }
+    /// Maps diff PHIDs to their numeric IDs.
+    async fn get_diff_ids(&self, phids: &[String]) -> Result<HashMap<String, u32>> {
+        let mut ids = HashMap::new();
 
-        let url = format!("{}/api/user.search", self.base_url);
-        let params = [
-            ("api.token", self.api_token.as_str()),
-            ("constraints[phids][0]", user_phid),
-        ];
+        for chunk in phids.chunks(100) {
+            let mut params: Vec<(String, String)> = vec![
+                ("api.token".to_string(), self.api_token.clone()),
+                ("limit".to_string(), "100".to_string()),
+            ];
+            for (i, phid) in chunk.iter().enumerate() {
+                params.push((format!("constraints[phids][{}]", i), phid.clone()));
+            }
+            let params: Vec<(&str, &str)> = params
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect();
+
+            let response_text = self
+                .conduit_call("differential.diff.search", &params, RAW_DIFF_CACHE_TTL)
+                .await?;
+            let result: DiffSearchResult = serde_json::from_str(&response_text)
+                .context("Failed to parse differential.diff.search response")?;
+
+            if let Some(error_code) = result.error_code {
+                anyhow::bail!(
+                    "API Error: {} - {}",
+                    error_code,
+                    result.error_info.unwrap_or_default()
+                );
+            }
 
-        match self.client.post(&url).form(&params).send().await {
-            Ok(response) => {
-                if let Ok(result) = response.json::<UserSearchResult>().await {
-                    if let Some(_) = result.error_code {
-                        self.user_cache
-                            .insert(user_phid.to_string(), user_phid.to_string());
-                        return user_phid.to_string();
-                    }
+            for diff in result.result.context("No result data")?.data {
+                ids.insert(diff.phid, diff.id);
+            }
+        }
 
-                    if let Some(data) = result.result {
-                        if let Some(user_data) = data.data.first() {
-                            let fields = &user_data.fields;
-                            let real_name = fields.real_name.as_deref().unwrap_or("");
-                            let username = fields.username.as_deref().unwrap_or("");
-
-                            let display_name = if !real_name.is_empty() {
-                                if !username.is_empty() {
-                                    format!("{} ({})", real_name, username)
-                                } else {
-                                    real_name.to_string()
-                                }
-                            } else if !username.is_empty() {
-                                username.to_string()
-                            } else {
-                                user_phid.to_string()
-                            };
+        Ok(ids)
+    }
 
-                            self.user_cache
-                                .insert(user_phid.to_string(), display_name.clone());
-                            return display_name;
-                        }
+    /// Fills in the numeric diff IDs of `update` events.
+    async fn resolve_update_diff_ids(&self, events: &mut [RevisionEvent]) {
+        let mut phids: Vec<String> = Vec::new();
+        for event in events.iter() {
+            if let EventKind::Update { old_diff_phid, new_diff_phid, .. } = &event.kind {
+                for phid in [old_diff_phid, new_diff_phid].into_iter().flatten() {
+                    if !phids.contains(phid) {
+                        phids.push(phid.clone());
                     }
                 }
             }
+        }
+        if phids.is_empty() {
+            return;
+        }
+
+        let ids = match self.get_diff_ids(&phids).await {
+            Ok(ids) => ids,
             Err(e) => {
-                warn!("Failed to fetch user info for {}: {}", user_phid, e);
+                warn!("Could not resolve diff IDs of revision updates: {:#}", e);
+                return;
+            }
+        };
+        for event in events.iter_mut() {
+            if let EventKind::Update { old_diff_phid, new_diff_phid, old_diff_id, new_diff_id } = &mut event.kind {
+                *old_diff_id = old_diff_phid.as_ref().and_then(|p| ids.get(p).copied());
+                *new_diff_id = new_diff_phid.as_ref().and_then(|p| ids.get(p).copied());
             }
         }
-
-        self.user_cache
-            .insert(user_phid.to_string(), user_phid.to_string());
-        user_phid.to_string()
     }
 
-    async fn get_revision_phid(&self, diff_id: u32) -> Result<String> {
-        let url = format!("{}/api/differential.revision.search", self.base_url);
-        let params = [
-            ("api.token", self.api_token.as_str()),
-            ("constraints[ids][0]", &diff_id.to_string()),
-        ];
-
-        debug!(
-            "Fetching revision PHID for diff_id={} from: {}",
-            diff_id, url
-        );
-        debug!("Request params: {:?}", params);
-
-        let response = self
-            .client
-            .post(&url)
-            .form(&params)
-            .send()
-            .await
-            .context(format!("Failed to send request to {}", url))?;
-
-        let status = response.status();
-        debug!("Response status: {}", status);
-
-        if !status.is_success() {
-            let error_text = response
-                .text()
-                .await
-                .unwrap_or_else(|_| "<no response body>".to_string());
-            error!("HTTP error {}: {}", status, error_text);
-            anyhow::bail!("HTTP error {}: {}", status, error_text);
+    /// Walks parent/child edges from a revision and returns every revision in its
+    /// stack, parents before children (ties broken by revision ID).
+    async fn discover_stack(&self, diff_id: u32) -> Result<Vec<u32>> {
+        let start_phid = self.get_revision_phid(diff_id).await?;
+
+        let mut visited: HashSet<String> = HashSet::new();
+        visited.insert(start_phid.clone());
+        // (parent, child) pairs
+        let mut links: HashSet<(String, String)> = HashSet::new();
+        let mut frontier = vec![start_phid];
+
+        while !frontier.is_empty() {
+            let edges = self.get_revision_edges(&frontier).await?;
+            frontier = Vec::new();
+            for edge in edges {
+                let (parent, child) = match edge.edge_type.as_str() {
+                    // The source depends on the destination
+                    "revision.parent" => (edge.destination_phid, edge.source_phid),
+                    "revision.child" => (edge.source_phid, edge.destination_phid),
+                    _ => continue,
+                };
+                for phid in [&parent, &child] {
+                    if visited.insert(phid.clone()) {
+                        frontier.push(phid.clone());
+                    }
+                }
+                links.insert((parent, child));
+            }
         }
 
-        let response_text = response
-            .text()
-            .await
-            .context("Failed to read response body")?;
-        debug!(
-            "Response body (first 500 chars): {}",
-            &response_text.chars().take(500).collect::<String>()
-        );
+        let phids: Vec<String> = visited.into_iter().collect();
+        let ids = self.get_revision_ids(&phids).await?;
 
-        let result: RevisionSearchResult =
-            serde_json::from_str(&response_text).context(format!(
-                "Failed to parse JSON response. Response was: {}",
-                response_text
-            ))?;
+        // Kahn's algorithm over parent -> child links
+        let mut parent_count: HashMap<u32, usize> = ids.values().map(|id| (*id, 0)).collect();
+        let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+        for (parent, child) in &links {
+            if let (Some(p), Some(c)) = (ids.get(parent), ids.get(child)) {
+                children.entry(*p).or_default().push(*c);
+                *parent_count.entry(*c).or_default() += 1;
+            }
+        }
 
-        if let Some(error_code) = result.error_code {
-            anyhow::bail!(
-                "API Error: {} - {}",
-                error_code,
-                result.error_info.unwrap_or_default()
-            );
+        let mut ready: BTreeSet<u32> = parent_count
+            .iter()
+            .filter(|(_, count)| **count == 0)
+            .map(|(id, _)| *id)
+            .collect();
+        let mut order = Vec::new();
+        while let Some(id) = ready.pop_first() {
+            order.push(id);
+            for child in children.get(&id).into_iter().flatten() {
+                if let Some(count) = parent_count.get_mut(child) {
+                    *count -= 1;
+                    if *count == 0 {
+                        ready.insert(*child);
+                    }
+                }
+            }
         }
 
-        let data = result.result.context("No result data")?;
-        let revision_data = data.data.first().context("No revision found")?;
+        if order.len() < parent_count.len() {
+            warn!("Revision stack of D{} contains a dependency cycle", diff_id);
+            let mut rest: Vec<u32> = parent_count
+                .keys()
+                .filter(|id| !order.contains(id))
+                .copied()
+                .collect();
+            rest.sort_unstable();
+            order.extend(rest);
+        }
 
-        Ok(revision_data.phid.clone())
+        if !order.contains(&diff_id) {
+            order.push(diff_id);
+        }
+        Ok(order)
     }
 
     async fn get_revision_phid_with_progress(
@@ -1777,18 +5933,20 @@ impl PhabricatorCommentExtractor {
         diff_id: u32,
         pb: &ProgressBar,
     ) -> Result<String> {
-        let url = format!("{}/api/differential.revision.search", self.base_url);
+        let diff_id_str = diff_id.to_string();
         let params = [
             ("api.token", self.api_token.as_str()),
-            ("constraints[ids][0]", &diff_id.to_string()),
+            ("constraints[ids][0]", diff_id_str.as_str()),
         ];
 
         pb.set_message("Making API request...");
-        let response = self.client.post(&url).form(&params).send().await?;
+        let response_text = self
+            .conduit_call("differential.revision.search", &params, REVISION_CACHE_TTL)
+            .await?;
         pb.inc(1);
 
         pb.set_message("Parsing response...");
-        let result: RevisionSearchResult = response.json().await?;
+        let result: RevisionSearchResult = serde_json::from_str(&response_text)?;
         pb.inc(1);
 
         if let Some(error_code) = result.error_code {
@@ -1805,84 +5963,105 @@ impl PhabricatorCommentExtractor {
         Ok(revision_data.phid.clone())
     }
 
+    /// Fetches every transaction on an object, following `cursor.after` until
+    /// the server reports there are no more pages.
     async fn get_transactions(&self, object_phid: &str) -> Result<Vec<TransactionData>> {
-        let url = format!("{}/api/transaction.search", self.base_url);
-        let params = [
-            ("api.token", self.api_token.as_str()),
-            ("objectIdentifier", object_phid),
-        ];
+        self.get_transactions_with_progress(object_phid, &ProgressBar::hidden())
+            .await
+    }
 
-        debug!(
-            "Fetching transactions for object_phid={} from: {}",
-            object_phid, url
-        );
-        debug!("Request params: {:?}", params);
+    async fn get_transactions_with_progress(
+        &self,
+        object_phid: &str,
+        pb: &ProgressBar,
+    ) -> Result<Vec<TransactionData>> {
+        let mut transactions = Vec::new();
+        let mut after: Option<String> = None;
+        let mut page = 0usize;
 
-        let response = self
-            .client
-            .post(&url)
-            .form(&params)
-            .send()
-            .await
-            .context(format!("Failed to send request to {}", url))?;
+        loop {
+            page += 1;
+            pb.set_message(format!("Fetching transactions (page {})...", page));
+            let result = self
+                .get_transactions_page(object_phid, after.as_deref())
+                .await;
+            pb.inc(1);
 
-        let status = response.status();
-        debug!("Response status: {}", status);
+            let data = match result {
+                Ok(data) => data,
+                // The first page failing means we have nothing to show at all
+                Err(e) if page == 1 => return Err(e),
+                Err(e) => {
+                    warn!(
+                        "transaction.search failed on page {} after {} transactions; output may be incomplete: {:#}",
+                        page,
+                        transactions.len(),
+                        e
+                    );
+                    break;
+                }
+            };
 
-        if !status.is_success() {
-            let error_text = response
-                .text()
-                .await
-                .unwrap_or_else(|_| "<no response body>".to_string());
-            error!("HTTP error {}: {}", status, error_text);
-            anyhow::bail!("HTTP error {}: {}", status, error_text);
+            let page_len = data.data.len();
+            transactions.extend(data.data);
+
+            let next = data.cursor.and_then(|c| c.after);
+            match next {
+                Some(cursor) if page_len == 0 => {
+                    warn!(
+                        "transaction.search returned an empty page {} with cursor {}; output may be incomplete",
+                        page, cursor
+                    );
+                    break;
+                }
+                Some(cursor) if after.as_deref() == Some(cursor.as_str()) => {
+                    warn!(
+                        "transaction.search returned the same cursor {} twice; output may be incomplete",
+                        cursor
+                    );
+                    break;
+                }
+                Some(cursor) => after = Some(cursor),
+                None => break,
+            }
         }
 
-        let response_text = response
-            .text()
-            .await
-            .context("Failed to read response body")?;
         debug!(
-            "Response body (first 500 chars): {}",
-            &response_text.chars().take(500).collect::<String>()
+            "Fetched {} transactions for {} in {} page(s)",
+            transactions.len(),
+            object_phid,
+            page
         );
-
-        let result: TransactionSearchResult =
-            serde_json::from_str(&response_text).context(format!(
-                "Failed to parse JSON response. Response was: {}",
-                response_text
-            ))?;
-
-        if let Some(error_code) = result.error_code {
-            anyhow::bail!(
-                "API Error: {} - {}",
-                error_code,
-                result.error_info.unwrap_or_default()
-            );
-        }
-
-        let data = result.result.context("No result data")?;
-        Ok(data.data)
+        Ok(transactions)
     }
 
-    async fn get_transactions_with_progress(
+    async fn get_transactions_page(
         &self,
         object_phid: &str,
-        pb: &ProgressBar,
-    ) -> Result<Vec<TransactionData>> {
-        let url = format!("{}/api/transaction.search", self.base_url);
-        let params = [
+        after: Option<&str>,
+    ) -> Result<TransactionSearchData> {
+        let mut params = vec![
             ("api.token", self.api_token.as_str()),
             ("objectIdentifier", object_phid),
         ];
+        if let Some(after) = after {
+            params.push(("after", after));
+        }
 
-        pb.set_message("Making transactions API request...");
-        let response = self.client.post(&url).form(&params).send().await?;
-        pb.inc(1);
+        debug!(
+            "Fetching transactions for object_phid={} (after={:?})",
+            object_phid, after
+        );
 
-        pb.set_message("Parsing transactions response...");
-        let result: TransactionSearchResult = response.json().await?;
-        pb.inc(1);
+        let response_text = self
+            .conduit_call("transaction.search", &params, ACTIVITY_CACHE_TTL)
+            .await?;
+
+        let result: TransactionSearchResult =
+            serde_json::from_str(&response_text).context(format!(
+                "Failed to parse JSON response. Response was: {}",
+                response_text
+            ))?;
 
         if let Some(error_code) = result.error_code {
             anyhow::bail!(
@@ -1892,8 +6071,7 @@ impl PhabricatorCommentExtractor {
             );
         }
 
-        let data = result.result.context("No result data")?;
-        Ok(data.data)
+        result.result.context("No result data")
     }
 
     fn format_timestamp(&self, timestamp: u64) -> String {
@@ -1916,8 +6094,50 @@ impl PhabricatorCommentExtractor {
             general_comments: Vec::new(),
             inline_comments: Vec::new(),
             review_actions: Vec::new(),
+            events: Vec::new(),
+            diffs: Vec::new(),
+            latest_transaction_id: transactions.iter().filter_map(|t| t.id.as_u64()).max(),
+            revision: None,
+            references: BTreeMap::new(),
+            attachments: BTreeMap::new(),
         };
 
+        // Indices into `inline_comments` whose suggestion must be fetched from the web UI
+        let mut pending_suggestions = Vec::new();
+
+        // Reviewers may be users, projects or packages; name them all with batched lookups
+        let reviewer_phids: BTreeSet<String> = transactions
+            .iter()
+            .filter(|t| t.transaction_type.as_deref() == Some("reviewers"))
+            .filter_map(|t| t.fields.as_ref())
+            .flat_map(EventKind::reviewer_phids)
+            .map(str::to_string)
+            .collect();
+        let other_phids: Vec<String> = reviewer_phids
+            .iter()
+            .filter(|phid| !phid.starts_with("PHID-USER-"))
+            .cloned()
+            .collect();
+        let other_names = if other_phids.is_empty() {
+            HashMap::new()
+        } else {
+            self.get_phid_names(&other_phids).await.unwrap_or_else(|e| {
+                warn!("Could not resolve reviewer names: {:#}", e);
+                HashMap::new()
+            })
+        };
+        let user_phids: Vec<String> = reviewer_phids
+            .iter()
+            .cloned()
+            .chain(transactions.iter().filter_map(|t| t.author_phid.clone()))
+            .collect();
+        self.prefetch_user_info(&user_phids).await;
+        let mut reviewer_names = HashMap::new();
+        for phid in reviewer_phids {
+            let name = self.display_name(&phid, &other_names).await;
+            reviewer_names.insert(phid, name);
+        }
+
         let total_transactions = transactions.len();
         for (i, transaction) in transactions.into_iter().enumerate() {
             pb.set_message(format!(
@@ -1951,40 +6171,7 @@ impl PhabricatorCommentExtractor {
                 "inline" => {
                     let fields = transaction.fields.unwrap_or(serde_json::Value::Null);
                     for comment in transaction.comments {
-                        let mut content = comment.content.raw.unwrap_or_default();
-                        // removed debug print
-                        if content.is_empty() {
-                            // Try to get suggestion content from web interface
-                            let line_number =
-                                fields.get("line").and_then(|v| v.as_u64()).unwrap_or(0) as u32;
-                            let file_path =
-                                fields.get("path").and_then(|v| v.as_str()).unwrap_or("");
-
-                            let line_length =
-                                fields.get("length").and_then(|v| v.as_u64()).unwrap_or(1) as u32;
-                            if line_number > 0 && !file_path.is_empty() {
-                                if let Some(suggestion) = self
-                                    .fetch_suggestion_from_web(
-                                        self.current_revision_id.unwrap_or(0),
-                                        line_number,
-                                        line_length,
-                                        file_path,
-                                        include_done,
-                                        &comment.id.to_string(),
-                                    )
-                                    .await
-                                {
-                                    // removed debug print
-                                    content = suggestion;
-                                } else {
-                                    // removed debug print
-                                    content = "*[Empty inline comment - likely contains a code suggestion that cannot be extracted via API]*".to_string();
-                                }
-                            } else {
-                                // removed debug print
-                                content = "*[Empty inline comment - likely contains a code suggestion that cannot be extracted via API]*".to_string();
-                            }
-                        }
+                        let content = comment.content.raw.unwrap_or_default();
 
                         let file_path = fields
                             .get("path")
@@ -2004,12 +6191,44 @@ impl PhabricatorCommentExtractor {
                             .get("isDone")
                             .and_then(|v| v.as_bool())
                             .unwrap_or(false);
+                        let reply_to_comment_phid = fields
+                            .get("replyToCommentPHID")
+                            .and_then(|v| v.as_str())
+                            .filter(|s| !s.is_empty())
+                            .map(|s| s.to_string());
 
                         // Skip "done" inline comments unless explicitly requested
                         if is_done && !include_done {
                             continue;
                         }
 
+                        // Empty inlines usually hold a code suggestion that only the web UI renders
+                        let content = if !content.is_empty() {
+                            content
+                        } else if line_number > 0 && !file_path.is_empty() {
+                            pending_suggestions.push(comments_data.inline_comments.len());
+                            String::new()
+                        } else {
+                            EMPTY_INLINE_PLACEHOLDER.to_string()
+                        };
+
+                        let code_context = if self.context_lines > 0
+                            && line_number > 0
+                            && !file_path.is_empty()
+                        {
+                            self.fetch_code_context(
+                                self.current_revision_id.unwrap_or(0),
+                                &file_path,
+                                &comment.id.to_string(),
+                                &diff_id,
+                                line_number,
+                                line_length,
+                            )
+                            .await
+                        } else {
+                            Vec::new()
+                        };
+
                         comments_data.inline_comments.push(InlineComment {
                             author: author_name.clone(),
                             author_phid: author_phid.to_string(),
@@ -2023,10 +6242,21 @@ impl PhabricatorCommentExtractor {
                             is_done,
                             transaction_id: transaction.id.to_string(),
                             comment_id: comment.id.to_string(),
+                            comment_phid: comment.phid.clone().unwrap_or_default(),
+                            reply_to_comment_phid,
+                            code_context,
+                            is_context: false,
+                            diff_index: None,
+                            is_stale: false,
+                            is_suggestion: false,
+                            ported_line_number: None,
+                            ported_line_length: None,
+                            is_orphaned: false,
+                            local: None,
                         });
                     }
                 }
-                "request-changes" | "accept" | "reject" | "request-review" => {
+                "request-changes" | "accept" | "reject" | "request-review" | "resign" => {
                     let mut action_comments = Vec::new();
                     for comment in transaction.comments {
                         let content = comment.content.raw.unwrap_or_default();
@@ -2039,6 +6269,7 @@ impl PhabricatorCommentExtractor {
                         author: author_name.clone(),
                         author_phid: author_phid.to_string(),
                         date: date.clone(),
+                        date_timestamp: transaction.date_created,
                         action: transaction
                             .transaction_type
                             .clone()
@@ -2047,17 +6278,53 @@ impl PhabricatorCommentExtractor {
                         transaction_id: transaction.id.to_string(),
                     });
                 }
-                _ => {}
+                "reviewers" => {
+                    let fields = transaction.fields.unwrap_or(serde_json::Value::Null);
+                    if let Some(kind) = EventKind::from_reviewer_operations(&fields, &reviewer_names) {
+                        comments_data.events.push(RevisionEvent {
+                            author: author_name.clone(),
+                            author_phid: author_phid.to_string(),
+                            date: date.clone(),
+                            date_timestamp: transaction.date_created,
+                            transaction_id: transaction.id.to_string(),
+                            kind,
+                        });
+                    }
+                }
+                other => {
+                    let fields = transaction.fields.unwrap_or(serde_json::Value::Null);
+                    if let Some(kind) = EventKind::from_transaction(other, &fields) {
+                        comments_data.events.push(RevisionEvent {
+                            author: author_name.clone(),
+                            author_phid: author_phid.to_string(),
+                            date: date.clone(),
+                            date_timestamp: transaction.date_created,
+                            transaction_id: transaction.id.to_string(),
+                            kind,
+                        });
+                    }
+                }
             }
 
             // Increment progress for each transaction processed
             pb.inc(1);
         }
 
+        self.resolve_update_diff_ids(&mut comments_data.events).await;
+
+        self.resolve_suggestions(
+            self.current_revision_id.unwrap_or(0),
+            &mut comments_data.inline_comments,
+            &pending_suggestions,
+            include_done,
+            pb,
+        )
+        .await;
+
         comments_data
     }
 
-    fn format_as_markdown(&self, comments_data: CommentsData, diff_id: u32) -> String {
+    fn format_as_markdown(&self, comments_data: &CommentsData, diff_id: u32) -> String {
         let mut md_lines = Vec::new();
 
         // Header with clickable URL
@@ -2067,8 +6334,12 @@ impl PhabricatorCommentExtractor {
         ));
         md_lines.push(String::new());
 
+        if let Some(revision) = &comments_data.revision {
+            self.format_revision_info(&mut md_lines, revision);
+        }
+
         // General Comments - sorted chronologically
-        if !comments_data.general_comments.is_empty() {
+        if !comments_data.general_comments.is_empty() && !self.interleave_actions {
             md_lines.push("## General Comments".to_string());
             md_lines.push(String::new());
 
@@ -2076,15 +6347,36 @@ impl PhabricatorCommentExtractor {
             sorted_comments.sort_by_key(|c| c.date_timestamp);
 
             for comment in &sorted_comments {
-                md_lines.push(format!(
-                    "### Comment by {} ({})",
-                    comment.author, comment.date
-                ));
-                md_lines.push(String::new());
-                md_lines.push(comment.content.clone());
-                md_lines.push(String::new());
-                md_lines.push("---".to_string());
-                md_lines.push(String::new());
+                self.format_general_comment(&mut md_lines, comment);
+            }
+        }
+
+        // Review Timeline - accept/request-changes decisions, optionally with general comments
+        let has_timeline = !comments_data.review_actions.is_empty()
+            || !comments_data.events.is_empty()
+            || (self.interleave_actions && !comments_data.general_comments.is_empty());
+        if has_timeline {
+            md_lines.push("## Review Timeline".to_string());
+            md_lines.push(String::new());
+
+            let mut entries: Vec<TimelineEntry> = comments_data
+                .review_actions
+                .iter()
+                .map(TimelineEntry::Action)
+                .chain(comments_data.events.iter().map(TimelineEntry::Event))
+                .collect();
+            if self.interleave_actions {
+                entries.extend(comments_data.general_comments.iter().map(TimelineEntry::Comment));
+            }
+            // Stable sort keeps an action ahead of a comment posted in the same second
+            entries.sort_by_key(|entry| entry.timestamp());
+
+            for entry in entries {
+                match entry {
+                    TimelineEntry::Comment(comment) => self.format_general_comment(&mut md_lines, comment),
+                    TimelineEntry::Action(action) => self.format_review_action(&mut md_lines, action),
+                    TimelineEntry::Event(event) => self.format_revision_event(&mut md_lines, event),
+                }
             }
         }
 
@@ -2117,8 +6409,9 @@ impl PhabricatorCommentExtractor {
                 md_lines.push(format!("### File: `{}`", file_path));
                 md_lines.push(String::new());
 
-                for comment in file_comments {
-                    let line_info = if comment.line_length > 1 {
+                for thread in build_inline_threads(&file_comments) {
+                    let comment = thread.comment;
+                    let mut line_info = if comment.line_length > 1 {
                         format!(
                             "Line {}-{}",
                             comment.line_number,
@@ -2127,78 +6420,793 @@ impl PhabricatorCommentExtractor {
                     } else {
                         format!("Line {}", comment.line_number)
                     };
+                    match (comment.ported_line_number, comment.ported_line_length) {
+                        (Some(number), Some(length)) if length > 1 => {
+                            line_info.push_str(&format!(" (now {}-{})", number, number + length - 1))
+                        }
+                        (Some(number), _) => line_info.push_str(&format!(" (now {})", number)),
+                        _ => {}
+                    }
 
                     let done_marker = if comment.is_done { " [DONE]" } else { "" };
+                    // A reply whose parent was filtered out (e.g. done) starts its own thread
+                    let reply_marker = if comment.reply_to_comment_phid.is_some() {
+                        " [REPLY]"
+                    } else {
+                        ""
+                    };
+                    let context_marker = if comment.is_context { " [EARLIER]" } else { "" };
                     md_lines.push(format!(
-                        "#### {} - {} ({}){}",
-                        line_info, comment.author, comment.date, done_marker
+                        "#### {}{} - {} ({}){}{}{}{}",
+                        line_info,
+                        self.diff_label(comment, comments_data.diffs.len()),
+                        comment.author,
+                        comment.date,
+                        done_marker,
+                        reply_marker,
+                        context_marker,
+                        if comment.is_orphaned {
+                            " [ORPHANED]"
+                        } else if comment.is_stale {
+                            " [STALE]"
+                        } else {
+                            ""
+                        }
                     ));
                     md_lines.push(String::new());
 
-                    if !comment.content.is_empty() {
+                    if let Some(local) = &comment.local {
+                        self.format_local_location(&mut md_lines, local);
+                    }
+
+                    if !comment.code_context.is_empty() {
+                        self.format_code_context(&mut md_lines, &comment.code_context);
+                    }
+
+                    if comment.is_suggestion {
                         md_lines.push(comment.content.clone());
+                    } else if !comment.content.is_empty() {
+                        md_lines.push(self.remarkup.to_markdown(&comment.content));
                     } else {
                         md_lines.push("*[No comment text]*".to_string());
                     }
+                    md_lines.push(String::new());
+
+                    for reply in &thread.replies {
+                        self.format_inline_reply(&mut md_lines, reply, 1);
+                    }
+
+                    md_lines.push("---".to_string());
+                    md_lines.push(String::new());
+                }
+            }
+        }
+
+        md_lines.join("\n")
+    }
+
+    /// Renders the metadata preamble: what the patch is and who still has to approve it.
+    fn format_revision_info(&self, md_lines: &mut Vec<String>, revision: &RevisionInfo) {
+        md_lines.push("## Revision".to_string());
+        md_lines.push(String::new());
+
+        let mut fields = vec![
+            ("Title", revision.title.clone()),
+            ("Author", revision.author.clone()),
+            ("Status", revision.status.clone()),
+        ];
+        if let Some(bug_id) = &revision.bug_id {
+            fields.push(("Bug", bug_id.clone()));
+        }
+        if let Some(repository) = &revision.repository {
+            fields.push(("Repository", repository.clone()));
+        }
+        if !revision.reviewers.is_empty() {
+            let reviewers = revision
+                .reviewers
+                .iter()
+                .map(|r| {
+                    let blocking = if r.is_blocking && r.status != "blocking" {
+                        ", blocking"
+                    } else {
+                        ""
+                    };
+                    format!("{} ({}{})", r.name, r.status, blocking)
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            fields.push(("Reviewers", reviewers));
+        }
+        if !revision.subscribers.is_empty() {
+            fields.push(("Subscribers", revision.subscribers.join(", ")));
+        }
+        for (name, value) in fields.into_iter().filter(|(_, v)| !v.is_empty()) {
+            md_lines.push(format!("- **{}:** {}", name, value));
+        }
+        md_lines.push(String::new());
+
+        for (heading, text) in [("Summary", &revision.summary), ("Test Plan", &revision.test_plan)] {
+            if text.trim().is_empty() {
+                continue;
+            }
+            md_lines.push(format!("### {}", heading));
+            md_lines.push(String::new());
+            md_lines.push(self.remarkup.to_markdown(text.trim_end()));
+            md_lines.push(String::new());
+        }
+    }
+
+    fn format_general_comment(&self, md_lines: &mut Vec<String>, comment: &Comment) {
+        md_lines.push(format!(
+            "### Comment by {} ({})",
+            comment.author, comment.date
+        ));
+        md_lines.push(String::new());
+        md_lines.push(self.remarkup.to_markdown(&comment.content));
+        md_lines.push(String::new());
+        md_lines.push("---".to_string());
+        md_lines.push(String::new());
+    }
+
+    fn format_review_action(&self, md_lines: &mut Vec<String>, action: &ReviewAction) {
+        let label = match action.action.as_str() {
+            "accept" => "Accepted",
+            "request-changes" => "Requested changes",
+            "reject" => "Rejected",
+            "request-review" => "Requested review",
+            "resign" => "Resigned",
+            other => other,
+        };
+        md_lines.push(format!(
+            "### {} by {} ({})",
+            label, action.author, action.date
+        ));
+        md_lines.push(String::new());
+        for comment in &action.comments {
+            md_lines.push(self.remarkup.to_markdown(comment));
+            md_lines.push(String::new());
+        }
+        md_lines.push("---".to_string());
+        md_lines.push(String::new());
+    }
+
+    fn format_revision_event(&self, md_lines: &mut Vec<String>, event: &RevisionEvent) {
+        md_lines.push(format!(
+            "### {} by {} ({})",
+            event.kind.label(),
+            event.author,
+            event.date
+        ));
+        md_lines.push(String::new());
+
+        let diff_label = |id: &Option<u32>, phid: &Option<String>| match (id, phid) {
+            (Some(id), _) => format!("Diff {}", id),
+            (None, Some(phid)) => phid.clone(),
+            (None, None) => "?".to_string(),
+        };
+        let mut details = Vec::new();
+        match &event.kind {
+            EventKind::Update { old_diff_phid, new_diff_phid, old_diff_id, new_diff_id } => {
+                let new = diff_label(new_diff_id, new_diff_phid);
+                if old_diff_phid.is_some() {
+                    details.push(format!("{} → {}", diff_label(old_diff_id, old_diff_phid), new));
+                } else {
+                    details.push(new);
+                }
+            }
+            EventKind::Reviewers { added, removed } => {
+                if !added.is_empty() {
+                    details.push(format!("Added: {}", added.join(", ")));
+                }
+                if !removed.is_empty() {
+                    details.push(format!("Removed: {}", removed.join(", ")));
+                }
+            }
+            EventKind::Status { old, new } => details.push(format!(
+                "{} → {}",
+                old.as_deref().unwrap_or("?"),
+                new.as_deref().unwrap_or("?")
+            )),
+            EventKind::Close { commit_phids } if !commit_phids.is_empty() => {
+                details.push(format!("Commits: {}", commit_phids.join(", ")))
+            }
+            EventKind::Title { new: Some(new), .. } => details.push(format!("New title: {}", new)),
+            EventKind::Summary { new: Some(new), .. } | EventKind::TestPlan { new: Some(new), .. } => {
+                details.extend(new.lines().map(|line| format!("> {}", line).trim_end().to_string()))
+            }
+            _ => {}
+        }
+        if !details.is_empty() {
+            // Blockquoted text is already one block; plain details are separate lines
+            if details.iter().all(|d| d.starts_with('>')) {
+                md_lines.extend(details);
+            } else {
+                md_lines.push(details.join("  \n"));
+            }
+            md_lines.push(String::new());
+        }
+        md_lines.push("---".to_string());
+        md_lines.push(String::new());
+    }
+
+    fn format_local_location(&self, md_lines: &mut Vec<String>, local: &LocalLocation) {
+        let mut notes = Vec::new();
+        if local.file_moved {
+            notes.push("file moved");
+        }
+        match local.status {
+            LocalStatus::Shifted => notes.push("code moved"),
+            LocalStatus::Changed => notes.push("code no longer matches"),
+            LocalStatus::Unverified => notes.push("not verified"),
+            LocalStatus::Missing => notes.push("file not found"),
+            LocalStatus::Matches => {}
+        }
+        let notes = if notes.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", notes.join(", "))
+        };
+        md_lines.push(format!("Local: `{}:{}`{}", local.path, local.line, notes));
+        md_lines.push(String::new());
+    }
+
+    /// " (Diff N of M)" when the revision has more than one diff.
+    fn diff_label(&self, comment: &InlineComment, diff_count: usize) -> String {
+        match comment.diff_index {
+            Some(index) if diff_count > 1 => format!(" (Diff {} of {})", index, diff_count),
+            _ => String::new(),
+        }
+    }
+
+    /// Renders source context as a fenced block; commented lines are marked with `>`.
+    fn format_code_context(&self, md_lines: &mut Vec<String>, context: &[CodeContextLine]) {
+        let width = context
+            .iter()
+            .map(|l| l.line_number.to_string().len())
+            .max()
+            .unwrap_or(1);
+        md_lines.push("```".to_string());
+        for line in context {
+            let marker = if line.commented { '>' } else { ' ' };
+            md_lines.push(format!(
+                "{} {:>width$} | {}",
+                marker,
+                line.line_number,
+                line.text,
+                width = width
+            ));
+        }
+        md_lines.push("```".to_string());
+        md_lines.push(String::new());
+    }
+
+    /// Renders a reply (and its own replies) as nested blockquotes, one level per depth.
+    fn format_inline_reply(&self, md_lines: &mut Vec<String>, thread: &InlineThread, depth: usize) {
+        let prefix = "> ".repeat(depth);
+        let comment = thread.comment;
+        let done_marker = if comment.is_done { " [DONE]" } else { "" };
+        let context_marker = if comment.is_context { " [EARLIER]" } else { "" };
+
+        md_lines.push(format!(
+            "{}**Reply by {} ({}){}{}:**",
+            prefix, comment.author, comment.date, done_marker, context_marker
+        ));
+        md_lines.push(prefix.trim_end().to_string());
+        let content = if comment.content.is_empty() {
+            "*[No comment text]*".to_string()
+        } else if comment.is_suggestion {
+            comment.content.clone()
+        } else {
+            self.remarkup.to_markdown(&comment.content)
+        };
+        for line in content.lines() {
+            md_lines.push(format!("{}{}", prefix, line).trim_end().to_string());
+        }
+        md_lines.push(String::new());
+
+        for reply in &thread.replies {
+            self.format_inline_reply(md_lines, reply, depth + 1);
+        }
+    }
+
+    fn json_report<'a>(&'a self, comments_data: &'a CommentsData, diff_id: u32) -> JsonReport<'a> {
+        JsonReport {
+            revision_id: diff_id,
+            url: format!("{}/D{}", self.base_url, diff_id),
+            comments: comments_data,
+        }
+    }
+
+    fn json_document<'a>(&'a self, revisions: Vec<JsonReport<'a>>) -> Result<String> {
+        let document = JsonDocument {
+            schema_version: JSON_SCHEMA_VERSION,
+            base_url: &self.base_url,
+            revisions,
+        };
+        serde_json::to_string_pretty(&document).context("Failed to serialize comments as JSON")
+    }
+
+    fn format_as_json(&self, comments_data: &CommentsData, diff_id: u32) -> Result<String> {
+        self.json_document(vec![self.json_report(comments_data, diff_id)])
+    }
+
+    async fn extract_revision(&mut self, diff_id: u32, include_done: bool) -> Result<CommentsData> {
+        self.current_revision_id = Some(diff_id);
+
+        // First, get basic info to calculate progress steps
+        let revision = self.get_revision_data(diff_id).await?;
+        let transactions = self.get_transactions(&revision.phid).await?;
+
+        // Now create progress bar based on actual transaction count
+        let total_steps = transactions.len() as u64;
+        let pb = ProgressBar::new(total_steps);
+        pb.set_style(
+            ProgressStyle::default_bar()
+                .template(
+                    "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}",
+                )?
+                .progress_chars("#>-"),
+        );
+
+        // Enable steady tick for animation
+        pb.enable_steady_tick(std::time::Duration::from_millis(100));
+
+        pb.set_message(format!("Processing {} transactions...", transactions.len()));
+        let mut comments_data = self
+            .extract_comments_with_progress(transactions, &pb, include_done)
+            .await;
+        match self.get_revision_diffs(&revision.phid).await {
+            Ok(diffs) => {
+                comments_data.set_diffs(diffs);
+                self.port_stale_comments(&mut comments_data).await;
+            }
+            Err(e) => warn!("Could not list the diffs of D{}: {:#}", diff_id, e),
+        }
+        if self.local_repo.is_some() {
+            self.map_to_local_repo(&mut comments_data).await;
+        }
+        comments_data.revision = Some(self.revision_info(revision).await);
+        if self.expand_refs {
+            self.resolve_references(&mut comments_data).await;
+        }
+        if self.attachment_dir.is_some() {
+            self.download_attachments(&mut comments_data).await;
+        }
+
+        pb.finish_with_message("Done!");
+
+        // Clear the progress bar before outputting results
+        pb.finish_and_clear();
+
+        Ok(comments_data)
+    }
+
+    /// Lists the revisions of a stack in extraction order, ahead of their sections.
+    fn format_stack_overview(&self, revisions: &[(u32, CommentsData)]) -> String {
+        let mut md_lines = vec!["# Revision Stack".to_string(), String::new()];
+        for (i, (diff_id, _)) in revisions.iter().enumerate() {
+            md_lines.push(format!("{}. {}/D{}", i + 1, self.base_url, diff_id));
+        }
+        md_lines.push(String::new());
+        md_lines.join("\n")
+    }
+
+    fn format_revision(
+        &self,
+        comments_data: &CommentsData,
+        diff_id: u32,
+        format: OutputFormat,
+    ) -> Result<String> {
+        match format {
+            OutputFormat::Markdown => Ok(self.format_as_markdown(comments_data, diff_id)),
+            OutputFormat::Json => self.format_as_json(comments_data, diff_id),
+        }
+    }
+
+    /// Formats several revisions as one document: Markdown sections back to back,
+    /// or one JSON document listing every revision.
+    fn format_combined(
+        &self,
+        revisions: &[(u32, CommentsData)],
+        format: OutputFormat,
+    ) -> Result<String> {
+        match format {
+            OutputFormat::Markdown => Ok(revisions
+                .iter()
+                .map(|(diff_id, comments_data)| self.format_as_markdown(comments_data, *diff_id))
+                .collect::<Vec<_>>()
+                .join("\n\n")),
+            OutputFormat::Json => self.json_document(
+                revisions
+                    .iter()
+                    .map(|(diff_id, comments_data)| self.json_report(comments_data, *diff_id))
+                    .collect(),
+            ),
+        }
+    }
+
+    fn extract_diff_id_from_url(&self, url: &str) -> Option<u32> {
+        debug!("Extracting diff ID from URL: {}", url);
+        let re = Regex::new(r"/D(\d+)(?:\?|$|#)").ok()?;
+        let captures = re.captures(url)?;
+        let diff_id = captures.get(1)?.as_str().parse().ok();
+        if let Some(id) = diff_id {
+            debug!("Extracted diff ID: {}", id);
+        }
+        diff_id
+    }
+}
+
+/// Parses a git-style unified diff into per-file hunks.
+fn parse_raw_diff(text: &str) -> Vec<RawDiffFile> {
+    let hunk_re = Regex::new(r"^@@ -(\d+)(?:,(\d+))? \+(\d+)(?:,(\d+))? @@").unwrap();
+    let strip_side = |p: &str| -> Option<String> {
+        let p = p.split('\t').next().unwrap_or(p).trim();
+        if p == "/dev/null" {
+            None
+        } else {
+            Some(
+                p.strip_prefix("a/")
+                    .or_else(|| p.strip_prefix("b/"))
+                    .unwrap_or(p)
+                    .to_string(),
+            )
+        }
+    };
+
+    let mut files: Vec<RawDiffFile> = Vec::new();
+    // Lines remaining in the current hunk as (old, new), so that body lines
+    // starting with "---"/"+++" are not mistaken for file headers
+    let mut remaining: (u32, u32) = (0, 0);
+
+    for line in text.lines() {
+        if remaining.0 > 0 || remaining.1 > 0 {
+            if let Some(hunk) = files.last_mut().and_then(|f| f.hunks.last_mut()) {
+                if line.starts_with('\\') {
+                    // "\ No newline at end of file"
+                    continue;
+                }
+                match line.chars().next() {
+                    Some('-') => remaining.0 = remaining.0.saturating_sub(1),
+                    Some('+') => remaining.1 = remaining.1.saturating_sub(1),
+                    _ => {
+                        remaining.0 = remaining.0.saturating_sub(1);
+                        remaining.1 = remaining.1.saturating_sub(1);
+                    }
+                }
+                hunk.lines.push(line.to_string());
+                continue;
+            }
+        }
+
+        if line.starts_with("diff --git ") {
+            files.push(RawDiffFile {
+                old_path: None,
+                new_path: None,
+                hunks: Vec::new(),
+            });
+        } else if let Some(path) = line.strip_prefix("--- ") {
+            // Diffs without "diff --git" headers start a file at "---"
+            if files.last().is_none_or(|f| !f.hunks.is_empty() || f.old_path.is_some()) {
+                files.push(RawDiffFile {
+                    old_path: None,
+                    new_path: None,
+                    hunks: Vec::new(),
+                });
+            }
+            if let Some(file) = files.last_mut() {
+                file.old_path = strip_side(path);
+            }
+        } else if let Some(path) = line.strip_prefix("+++ ") {
+            if let Some(file) = files.last_mut() {
+                file.new_path = strip_side(path);
+            }
+        } else if let Some(caps) = hunk_re.captures(line) {
+            let num = |i: usize, default: u32| {
+                caps.get(i)
+                    .and_then(|m| m.as_str().parse().ok())
+                    .unwrap_or(default)
+            };
+            let hunk = RawDiffHunk {
+                old_start: num(1, 0),
+                old_len: num(2, 1),
+                new_start: num(3, 0),
+                new_len: num(4, 1),
+                lines: Vec::new(),
+            };
+            remaining = (hunk.old_len, hunk.new_len);
+            if let Some(file) = files.last_mut() {
+                file.hunks.push(hunk);
+            }
+        }
+    }
+
+    files
+}
+
+/// Whether a `/differential/changeset/` response carries a changeset rather than an error.
+fn changeset_response_ok(text: &str) -> bool {
+    let json = text.strip_prefix("for (;;);").unwrap_or(text);
+    serde_json::from_str::<serde_json::Value>(json)
+        .map(|value| {
+            value.get("error").is_none_or(|e| e.is_null())
+                && value.get("payload").is_some_and(|p| !p.is_null())
+        })
+        .unwrap_or(false)
+}
+
+/// What `mark-done` changes, worked out before anything is posted.
+#[derive(Debug)]
+struct DoneMarkPlan<'a> {
+    /// Comments whose done state changes, with their revision
+    toggles: Vec<(u32, &'a InlineComment)>,
+    /// Comments already in the requested state
+    unchanged: Vec<&'a InlineComment>,
+    /// Revisions to publish the done marks on, in the order they first appear
+    revisions: Vec<u32>,
+}
+
+impl<'a> DoneMarkPlan<'a> {
+    fn new(extracted: &'a [(u32, CommentsData)], comment_ids: &[String], done: bool) -> Result<Self> {
+        let mut plan = DoneMarkPlan { toggles: Vec::new(), unchanged: Vec::new(), revisions: Vec::new() };
+        for comment_id in comment_ids {
+            let found = extracted.iter().find_map(|(diff_id, data)| {
+                let comment = data.inline_comments.iter().find(|c| c.comment_id == *comment_id)?;
+                Some((*diff_id, comment))
+            });
+            let Some((revision_id, comment)) = found else {
+                anyhow::bail!(
+                    "No inline comment {} found in the extracted revisions (is it hidden by a filter?)",
+                    comment_id
+                );
+            };
+            if comment.is_done == done {
+                plan.unchanged.push(comment);
+                continue;
+            }
+            plan.toggles.push((revision_id, comment));
+            if !plan.revisions.contains(&revision_id) {
+                plan.revisions.push(revision_id);
+            }
+        }
+        Ok(plan)
+    }
+}
+
+/// Publishing with `attach_inlines` submits every draft inline comment the user has
+/// on a revision, not only the ones made here. Warns about it, and refuses to post
+/// unless `--publish-drafts` was given.
+fn check_draft_publishing(revisions: &[u32], confirm: bool, publish_drafts: bool) -> Result<()> {
+    if revisions.is_empty() {
+        return Ok(());
+    }
+    let monograms: Vec<String> = revisions.iter().map(|id| format!("D{}", id)).collect();
+    if confirm && !publish_drafts {
+        anyhow::bail!(
+            "Publishing would also submit your other draft inline comments on {}; pass --publish-drafts to allow it",
+            monograms.join(", ")
+        );
+    }
+    println!("Publishing also submits your other draft inline comments on {}.", monograms.join(", "));
+    if !publish_drafts {
+        println!("Pass --publish-drafts along with --confirm to allow it.");
+    }
+    Ok(())
+}
+
+/// Groups inline comments into reply threads using `replyToCommentPHID`.
+/// Comments whose parent is not in `comments` (e.g. filtered as done), or that are
+/// only reachable through a reply cycle, become roots.
+/// Input order is preserved for roots and for replies within each thread.
+fn build_inline_threads<'a>(comments: &[&'a InlineComment]) -> Vec<InlineThread<'a>> {
+    let known: HashSet<&str> = comments
+        .iter()
+        .map(|c| c.comment_phid.as_str())
+        .filter(|phid| !phid.is_empty())
+        .collect();
+
+    let mut children: HashMap<&str, Vec<&'a InlineComment>> = HashMap::new();
+    let mut roots = Vec::new();
+    for comment in comments {
+        match comment.reply_to_comment_phid.as_deref() {
+            Some(parent) if known.contains(parent) && parent != comment.comment_phid => {
+                children.entry(parent).or_default().push(comment)
+            }
+            _ => roots.push(*comment),
+        }
+    }
+
+    fn build<'a>(
+        comment: &'a InlineComment,
+        children: &HashMap<&str, Vec<&'a InlineComment>>,
+        visited: &mut HashSet<&'a str>,
+    ) -> InlineThread<'a> {
+        visited.insert(comment.comment_phid.as_str());
+        let mut replies = Vec::new();
+        for child in children.get(comment.comment_phid.as_str()).into_iter().flatten() {
+            if !visited.contains(child.comment_phid.as_str()) {
+                replies.push(build(child, children, visited));
+            }
+        }
+        InlineThread { comment, replies }
+    }
+
+    let mut visited = HashSet::new();
+    let mut threads: Vec<InlineThread<'a>> = roots
+        .into_iter()
+        .map(|root| build(root, &children, &mut visited))
+        .collect();
+    // Comments caught in a reply cycle are never reached from a root; start a thread at each
+    for comment in comments {
+        if !visited.contains(comment.comment_phid.as_str()) {
+            threads.push(build(comment, &children, &mut visited));
+        }
+    }
+    let position = |thread: &InlineThread| {
+        comments
+            .iter()
+            .position(|c| std::ptr::eq(*c, thread.comment))
+            .unwrap_or(usize::MAX)
+    };
+    threads.sort_by_key(position);
+    threads
+}
+
+/// Location of the Arcanist config file (`%APPDATA%\.arcrc` on Windows, `~/.arcrc` elsewhere).
+fn arcrc_path() -> Option<PathBuf> {
+    if cfg!(target_os = "windows") {
+        std::env::var_os("APPDATA").map(|dir| PathBuf::from(dir).join(".arcrc"))
+    } else {
+        dirs::home_dir().map(|dir| dir.join(".arcrc"))
+    }
+}
 
-                    md_lines.push(String::new());
-                    md_lines.push("---".to_string());
-                    md_lines.push(String::new());
-                }
-            }
+/// Looks up the Conduit token stored by `arc install-certificate` for `base_url`.
+fn read_arcrc_token(base_url: &str) -> Option<String> {
+    let path = arcrc_path()?;
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) => {
+            debug!("No usable {}: {}", path.display(), e);
+            return None;
         }
+    };
+    let arcrc: ArcRc = match serde_json::from_str(&content) {
+        Ok(arcrc) => arcrc,
+        Err(e) => {
+            warn!("Failed to parse {}: {}", path.display(), e);
+            return None;
+        }
+    };
 
-        md_lines.join("\n")
+    let token = arcrc.token_for(base_url);
+    if token.is_some() {
+        info!("Using API token for {} from {}", base_url, path.display());
+    } else {
+        debug!("No token for {} in {}", base_url, path.display());
     }
+    token
+}
 
-    async fn extract_and_format(&mut self, diff_id: u32, include_done: bool) -> Result<String> {
-        self.current_revision_id = Some(diff_id);
+/// Parses a revision given either as a URL or as an ID, returning the ID and the
+/// base URL it lives on.
+fn parse_revision_input(input: &str, default_base_url: &str) -> Result<(u32, String)> {
+    if !input.contains("://") {
+        let diff_id = parse_diff_id(input)
+            .context(format!("Invalid diff ID format: {}", input))?;
+        return Ok((diff_id, default_base_url.to_string()));
+    }
 
-        // First, get basic info to calculate progress steps
-        let phid = self.get_revision_phid(diff_id).await?;
-        let transactions = self.get_transactions(&phid).await?;
+    let extractor = PhabricatorCommentExtractor::new(String::new(), String::new(), false);
+    let diff_id = extractor
+        .extract_diff_id_from_url(input)
+        .context(format!("Could not extract diff ID from URL: {}", input))?;
+
+    // Extract base URL from the provided URL
+    let parsed_url = Url::parse(input)?;
+    let base_url = format!(
+        "{}://{}",
+        parsed_url.scheme(),
+        parsed_url.host_str().unwrap_or("")
+    );
 
-        // Now create progress bar based on actual transaction count + 1 for formatting
-        let total_steps = transactions.len() as u64 + 1;
-        let pb = ProgressBar::new(total_steps);
-        pb.set_style(
-            ProgressStyle::default_bar()
-                .template(
-                    "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}",
-                )?
-                .progress_chars("#>-"),
-        );
+    Ok((diff_id, base_url))
+}
 
-        // Enable steady tick for animation
-        pb.enable_steady_tick(std::time::Duration::from_millis(100));
+/// Reads revision IDs or URLs from a file, one per line. Blank lines and
+/// `#` comments are ignored.
+fn read_revision_list(path: &str) -> Result<Vec<String>> {
+    let content =
+        fs::read_to_string(path).context(format!("Failed to read input file {}", path))?;
+    Ok(content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_string())
+        .collect())
+}
 
-        pb.set_message(format!("Processing {} transactions...", transactions.len()));
-        let comments_data = self
-            .extract_comments_with_progress(transactions, &pb, include_done)
-            .await;
+/// Compiles `--path`-style globs; `*` stays within one directory, `**` crosses them.
+fn build_globset(patterns: &[String]) -> Result<Option<GlobSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .context(format!("Invalid glob '{}'", pattern))?;
+        builder.add(glob);
+    }
+    Ok(Some(builder.build()?))
+}
 
-        pb.set_message("Formatting as Markdown...");
-        let markdown = self.format_as_markdown(comments_data, diff_id);
-        pb.inc(1);
+/// Parses `--since`: a Unix timestamp, RFC 3339, `YYYY-MM-DD` (UTC midnight) or `diff:<ID>`.
+fn parse_since(value: &str) -> Result<SinceSpec> {
+    let value = value.trim();
+    if let Some(diff_id) = value.strip_prefix("diff:") {
+        let diff_id = diff_id
+            .parse()
+            .context(format!("Invalid diff ID in --since {}", value))?;
+        return Ok(SinceSpec::Diff(diff_id));
+    }
+    if let Ok(timestamp) = value.parse::<u64>() {
+        return Ok(SinceSpec::Timestamp(timestamp));
+    }
+    if let Ok(dt) = DateTime::parse_from_rfc3339(value) {
+        return Ok(SinceSpec::Timestamp(dt.timestamp().max(0) as u64));
+    }
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        let timestamp = date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc().timestamp();
+        return Ok(SinceSpec::Timestamp(timestamp.max(0) as u64));
+    }
+    anyhow::bail!(
+        "Invalid --since value '{}': expected a Unix timestamp, an RFC 3339 date/time, YYYY-MM-DD or diff:<ID>",
+        value
+    )
+}
 
-        pb.finish_with_message("Done!");
+/// File recording, per revision, the newest transaction seen by `--new-only`.
+fn seen_state_path() -> Option<PathBuf> {
+    dirs::data_local_dir().map(|dir| dir.join("phab-comments-to-md").join("seen.json"))
+}
 
-        // Clear the progress bar before outputting results
-        pb.finish_and_clear();
+fn load_seen_state(path: &std::path::Path) -> HashMap<String, u64> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
 
-        Ok(markdown)
+fn save_seen_state(path: &std::path::Path, seen: &HashMap<String, u64>) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .context(format!("Failed to create {}", parent.display()))?;
     }
+    fs::write(path, serde_json::to_string_pretty(seen)?)
+        .context(format!("Failed to write {}", path.display()))
+}
 
-    fn extract_diff_id_from_url(&self, url: &str) -> Option<u32> {
-        debug!("Extracting diff ID from URL: {}", url);
-        let re = Regex::new(r"/D(\d+)(?:\?|$|#)").ok()?;
-        let captures = re.captures(url)?;
-        let diff_id = captures.get(1)?.as_str().parse().ok();
-        if let Some(id) = diff_id {
-            debug!("Extracted diff ID: {}", id);
-        }
-        diff_id
-    }
+/// `to` as a `/`-separated path relative to the directory `from`.
+fn relative_path(from: &std::path::Path, to: &std::path::Path) -> Result<String> {
+    let dot = std::path::Path::new(".");
+    let from = if from.as_os_str().is_empty() { dot } else { from };
+    let to = if to.as_os_str().is_empty() { dot } else { to };
+    let from = std::path::absolute(from).context("Failed to resolve the output directory")?;
+    let to = std::path::absolute(to).context("Failed to resolve the attachment directory")?;
+    let common = from
+        .components()
+        .zip(to.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut parts: Vec<String> = vec!["..".to_string(); from.components().count() - common];
+    parts.extend(
+        to.components()
+            .skip(common)
+            .map(|c| c.as_os_str().to_string_lossy().into_owned()),
+    );
+    Ok(parts.join("/"))
 }
 
 fn parse_diff_id(diff_id_str: &str) -> Option<u32> {
@@ -2221,85 +7229,891 @@ async fn main() -> Result<()> {
 
     let args = Args::parse();
     // reduced debug noise: parsed arguments
+    if matches!(args.command, Some(Command::ApplySuggestions { .. })) && args.repo.is_none() {
+        anyhow::bail!("apply-suggestions needs the local checkout to patch; pass it with --repo");
+    }
+
+    // Get base URL from args or environment variable
+    let env_base_url = std::env::var("PHABRICATOR_BASE_URL").ok();
+
+    // Collect every requested revision
+    let mut inputs: Vec<String> = args.url.clone();
+    inputs.extend(args.diff_id.iter().cloned());
+    if let Some(input_file) = &args.input_file {
+        inputs.extend(read_revision_list(input_file)?);
+    }
+    if inputs.is_empty() {
+        anyhow::bail!(
+            "Either --url, --diff-id or --input-file must be provided. Use --help for more information."
+        );
+    }
+
+    let default_base_url = args
+        .base_url
+        .clone()
+        .or(env_base_url)
+        .unwrap_or_else(|| "https://phabricator.services.mozilla.com".to_string());
+
+    // Determine diff IDs and base URL; one extractor (and session) serves all of them
+    let mut base_url: Option<String> = None;
+    let mut diff_ids: Vec<u32> = Vec::new();
+    for input in &inputs {
+        let (diff_id, input_base_url) = parse_revision_input(input, &default_base_url)?;
+        match &base_url {
+            Some(existing) if *existing != input_base_url => anyhow::bail!(
+                "All revisions must be on the same Phabricator instance ({} vs {})",
+                existing,
+                input_base_url
+            ),
+            _ => base_url = Some(input_base_url),
+        }
+        if !diff_ids.contains(&diff_id) {
+            diff_ids.push(diff_id);
+        }
+    }
+    let base_url = base_url.unwrap_or(default_base_url);
 
-    // Get token from args or environment variable
-    let token = args.token
+    // Get token from args, environment variable or ~/.arcrc (needs the resolved base URL)
+    let token = args
+        .token
         .or_else(|| std::env::var("PHABRICATOR_TOKEN").ok())
+        .or_else(|| read_arcrc_token(&base_url))
         .ok_or_else(|| {
             anyhow::anyhow!(
                 "Phabricator API token required. Either:\n\
                  1. Use --token <TOKEN>\n\
                  2. Set PHABRICATOR_TOKEN environment variable\n\
+                 3. Run `arc install-certificate {}` to store a token in ~/.arcrc\n\
                  \n\
-                 Get your token at: https://phabricator.services.mozilla.com/settings/user/<username>/page/apitokens/"
+                 Get your token at: {}/settings/user/<username>/page/apitokens/",
+                base_url,
+                base_url.trim_end_matches('/')
             )
         })?;
 
-    // Get base URL from args or environment variable
-    let env_base_url = std::env::var("PHABRICATOR_BASE_URL").ok();
+    // Create extractor and process
+    // reduced debug noise
+    let mut extractor = PhabricatorCommentExtractor::new(base_url, token, args.dump_web);
+    extractor.context_lines = args.context;
+    extractor.interleave_actions = args.interleave_actions;
+    extractor.expand_refs = args.expand_refs;
+    if let Some(dir) = &args.download_attachments {
+        fs::create_dir_all(dir).context(format!("Failed to create {}", dir.display()))?;
+        // Links are relative to wherever the Markdown ends up
+        let document_dir = match (&args.output_dir, &args.output) {
+            (Some(output_dir), _) => PathBuf::from(output_dir),
+            (None, Some(output)) => std::path::Path::new(output)
+                .parent()
+                .map(|p| p.to_path_buf())
+                .unwrap_or_default(),
+            (None, None) => PathBuf::new(),
+        };
+        extractor.attachment_link_dir = relative_path(&document_dir, dir)?;
+        extractor.attachment_dir = Some(dir.clone());
+    }
+    extractor.context_source = args.context_source;
+    extractor.cookie_providers = args.browser.providers();
+    extractor.max_concurrency = args.concurrency as usize;
+    if let Some(repo) = &args.repo {
+        extractor.local_repo = Some(LocalRepo::open(repo)?);
+    }
+    if let Some(path) = ResponseCache::default_path() {
+        match ResponseCache::open(&path, args.refresh) {
+            Ok(cache) => extractor.response_cache = Some(cache),
+            Err(e) => warn!("Response cache disabled: {:#}", e),
+        }
+    }
 
-    // Determine diff ID and base URL
-    let (diff_id, base_url) = if let Some(url) = args.url {
-        // reduced debug noise
-        let extractor = PhabricatorCommentExtractor::new(String::new(), token.clone(), false);
-        let diff_id = extractor
-            .extract_diff_id_from_url(&url)
-            .context("Could not extract diff ID from URL")?;
-        // reduced debug noise
-
-        // Extract base URL from the provided URL
-        let parsed_url = Url::parse(&url)?;
-        let base_url = format!(
-            "{}://{}",
-            parsed_url.scheme(),
-            parsed_url.host_str().unwrap_or("")
-        );
+    if args.stack {
+        let mut stack_ids = Vec::new();
+        for diff_id in &diff_ids {
+            if stack_ids.contains(diff_id) {
+                continue;
+            }
+            let stack = extractor
+                .discover_stack(*diff_id)
+                .await
+                .context(format!("Failed to discover the stack of D{}", diff_id))?;
+            info!(
+                "Stack of D{}: {}",
+                diff_id,
+                stack
+                    .iter()
+                    .map(|id| format!("D{}", id))
+                    .collect::<Vec<_>>()
+                    .join(" -> ")
+            );
+            for id in stack {
+                if !stack_ids.contains(&id) {
+                    stack_ids.push(id);
+                }
+            }
+        }
+        diff_ids = stack_ids;
+    }
 
-        (diff_id, base_url)
-    } else if let Some(diff_id_str) = args.diff_id {
-        let diff_id = parse_diff_id(&diff_id_str).context("Invalid diff ID format")?;
+    let filter = CommentFilter {
+        authors: if args.author.is_empty() {
+            None
+        } else {
+            Some(extractor.get_user_phids(&args.author, true).await?)
+        },
+        excluded_authors: if args.exclude_author.is_empty() {
+            HashSet::new()
+        } else {
+            // Excluding a bot that does not exist on this instance is harmless
+            extractor.get_user_phids(&args.exclude_author, false).await?
+        },
+        reviewers_only: args.reviewers_only,
+        latest_diff_only: args.latest_diff_only,
+        paths: build_globset(&args.path)?,
+        excluded_paths: build_globset(&args.exclude_path)?,
+    };
 
-        let base_url = args
-            .base_url
-            .or(env_base_url)
-            .unwrap_or_else(|| "https://phabricator.services.mozilla.com".to_string());
+    let since = match &args.since {
+        Some(value) => {
+            let timestamp = extractor
+                .resolve_since(parse_since(value)?)
+                .await
+                .context(format!("Failed to resolve --since {}", value))?;
+            Some(Since::Timestamp(timestamp))
+        }
+        None => None,
+    };
+    let seen_path = seen_state_path();
+    let mut seen = match (&seen_path, args.new_only) {
+        (Some(path), true) => load_seen_state(path),
+        _ => HashMap::new(),
+    };
 
-        (diff_id, base_url)
-    } else {
-        anyhow::bail!(
-            "Either --url or --diff-id must be provided. Use --help for more information."
+    // Write commands must find comments that are already done, e.g. for `mark-done --undo`
+    let include_done = args.include_done
+        || matches!(args.command, Some(Command::Reply { .. } | Command::MarkDone { .. }));
+    let mut extracted: Vec<(u32, CommentsData)> = Vec::new();
+    let mut failures = 0;
+    for (i, diff_id) in diff_ids.iter().enumerate() {
+        info!(
+            "Starting extraction for diff_id: {} ({}/{}), include_done: {}, format: {:?}",
+            diff_id,
+            i + 1,
+            diff_ids.len(),
+            include_done,
+            args.format
         );
-    };
+        match extractor.extract_revision(*diff_id, include_done).await {
+            Ok(mut comments_data) => {
+                info!("Successfully extracted comments for D{}", diff_id);
+                let last_seen = seen.get(&format!("{}/D{}", extractor.base_url, diff_id));
+                if !filter.is_empty() {
+                    filter.apply(&mut comments_data);
+                }
+                if let Some(since) = since {
+                    comments_data.retain_since(since);
+                } else if let Some(&last_seen) = last_seen.filter(|_| args.new_only) {
+                    comments_data.retain_since(Since::Transaction(last_seen));
+                }
+                extracted.push((*diff_id, comments_data));
+            }
+            Err(e) if diff_ids.len() == 1 => {
+                error!("Failed to extract and format: {:?}", e);
+                return Err(e);
+            }
+            Err(e) => {
+                // Keep going so one bad revision does not sink the whole batch
+                error!("Failed to extract D{}: {:?}", diff_id, e);
+                failures += 1;
+            }
+        }
+    }
 
-    // Create extractor and process
-    // reduced debug noise
-    let mut extractor = PhabricatorCommentExtractor::new(base_url, token, args.dump_web);
+    match &args.command {
+        Some(Command::Reply { comment_id, message, publish_drafts, mode }) => {
+            return extractor
+                .reply_to_comment(&extracted, comment_id, message, mode.confirm, *publish_drafts)
+                .await;
+        }
+        Some(Command::MarkDone { comment_id, undo, message, publish_drafts, mode }) => {
+            return extractor
+                .mark_inlines_done(
+                    &extracted,
+                    comment_id,
+                    !undo,
+                    message.as_deref(),
+                    mode.confirm,
+                    *publish_drafts,
+                )
+                .await;
+        }
+        _ => {}
+    }
 
-    info!(
-        "Starting extraction for diff_id: {}, include_done: {}",
-        diff_id, args.include_done
-    );
-    let markdown = match extractor
-        .extract_and_format(diff_id, args.include_done)
-        .await
-    {
-        Ok(md) => {
-            info!("Successfully extracted and formatted comments");
-            md
+    if let Some(Command::ApplySuggestions { dry_run }) = args.command {
+        let repo = extractor.local_repo.as_ref().context("--repo is required")?;
+        let reports = repo.apply_suggestions(&extracted, dry_run)?;
+        let (mut applied, mut unverified, mut skipped, mut conflicted) = (0, 0, 0, 0);
+        for report in &reports {
+            let result = match &report.outcome {
+                SuggestionOutcome::Applied if dry_run => {
+                    applied += 1;
+                    "would apply".to_string()
+                }
+                SuggestionOutcome::Applied => {
+                    applied += 1;
+                    "applied".to_string()
+                }
+                SuggestionOutcome::Unverified(reason) => {
+                    unverified += 1;
+                    format!("unverified, not applied ({})", reason)
+                }
+                SuggestionOutcome::Skipped(reason) => {
+                    skipped += 1;
+                    format!("skipped ({})", reason)
+                }
+                SuggestionOutcome::Conflicted(reason) => {
+                    conflicted += 1;
+                    format!("conflicted ({})", reason)
+                }
+            };
+            println!(
+                "D{} comment {} at {}: {}",
+                report.diff_id, report.comment_id, report.location, result
+            );
         }
-        Err(e) => {
-            error!("Failed to extract and format: {:?}", e);
-            return Err(e);
+        println!(
+            "{} applied, {} unverified, {} skipped, {} conflicted{}",
+            applied,
+            unverified,
+            skipped,
+            conflicted,
+            if dry_run { " (dry run, no files changed)" } else { "" }
+        );
+        if failures > 0 {
+            anyhow::bail!(
+                "{} of {} revisions could not be extracted",
+                failures,
+                diff_ids.len()
+            );
         }
-    };
+        return Ok(());
+    }
 
     // Output
-    if let Some(output_path) = args.output {
-        fs::write(&output_path, &markdown)?;
-        eprintln!("Comments extracted and saved to {}", output_path);
+    if let Some(output_dir) = args.output_dir {
+        let output_dir = std::path::Path::new(&output_dir);
+        fs::create_dir_all(output_dir)
+            .context(format!("Failed to create {}", output_dir.display()))?;
+        let extension = match args.format {
+            OutputFormat::Markdown => "md",
+            OutputFormat::Json => "json",
+        };
+        for (diff_id, comments_data) in &extracted {
+            let output_path = output_dir.join(format!("D{}.{}", diff_id, extension));
+            fs::write(
+                &output_path,
+                extractor.format_revision(comments_data, *diff_id, args.format)?,
+            )?;
+            eprintln!("Comments for D{} saved to {}", diff_id, output_path.display());
+        }
     } else {
-        println!("{}", markdown);
+        let mut output = extractor.format_combined(&extracted, args.format)?;
+        if args.stack && args.format == OutputFormat::Markdown && extracted.len() > 1 {
+            output = format!("{}\n{}", extractor.format_stack_overview(&extracted), output);
+        }
+        if let Some(output_path) = args.output {
+            fs::write(&output_path, &output)?;
+            eprintln!("Comments extracted and saved to {}", output_path);
+        } else {
+            println!("{}", output);
+        }
+    }
+
+    if let Some(patch_path) = &args.suggestions_patch {
+        let mut patches = Vec::new();
+        for (diff_id, comments_data) in &extracted {
+            let patch = extractor.build_suggestions_patch(comments_data, *diff_id).await;
+            if !patch.is_empty() {
+                patches.push(patch);
+            }
+        }
+        if patches.is_empty() {
+            warn!("No applicable code suggestions found; {} is empty", patch_path);
+        }
+        let mut patch = patches.join("\n");
+        if !patch.is_empty() {
+            patch.push('\n');
+        }
+        fs::write(patch_path, patch).context(format!("Failed to write {}", patch_path))?;
+        eprintln!("Code suggestions saved to {}", patch_path);
+    }
+
+    // Only advance --new-only once the output has been written
+    if args.new_only {
+        for (diff_id, comments_data) in &extracted {
+            if let Some(latest) = comments_data.latest_transaction_id {
+                seen.insert(format!("{}/D{}", extractor.base_url, diff_id), latest);
+            }
+        }
+        match &seen_path {
+            Some(path) => save_seen_state(path, &seen)?,
+            None => warn!("No data directory found; --new-only cannot remember this run"),
+        }
+    }
+
+    if failures > 0 {
+        anyhow::bail!(
+            "{} of {} revisions could not be extracted",
+            failures,
+            diff_ids.len()
+        );
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inline(comment_phid: &str, reply_to: Option<&str>) -> InlineComment {
+        InlineComment {
+            author: "alice".to_string(),
+            author_phid: "PHID-USER-alice".to_string(),
+            date: String::new(),
+            date_timestamp: 0,
+            content: String::new(),
+            file_path: "a.txt".to_string(),
+            line_number: 1,
+            line_length: 1,
+            diff_id: "1".to_string(),
+            is_done: false,
+            transaction_id: String::new(),
+            comment_id: comment_phid.to_string(),
+            comment_phid: comment_phid.to_string(),
+            reply_to_comment_phid: reply_to.map(|p| p.to_string()),
+            code_context: Vec::new(),
+            is_context: false,
+            diff_index: None,
+            is_stale: false,
+            is_suggestion: false,
+            ported_line_number: None,
+            ported_line_length: None,
+            is_orphaned: false,
+            local: None,
+        }
+    }
+
+    #[test]
+    fn code_context_clamps_huge_ranges() {
+        let mut extractor = PhabricatorCommentExtractor::new("https://phab.example.com".to_string(), String::new(), false);
+        extractor.context_lines = u32::MAX;
+        let new_lines: HashMap<u32, String> = (1..=5).map(|n| (n, format!("line {}", n))).collect();
+        let context = extractor.code_context_from_lines(&HashMap::new(), &new_lines, 3, u32::MAX);
+        assert_eq!(context.len(), 5);
+        assert!(context.iter().all(|l| l.commented == (l.line_number >= 3)));
+    }
+
+    #[test]
+    fn inline_threads_nest_replies() {
+        let (a, b, c) = (inline("A", None), inline("B", Some("A")), inline("C", Some("B")));
+        let threads = build_inline_threads(&[&a, &b, &c]);
+        assert_eq!(threads.len(), 1);
+        assert_eq!(threads[0].replies[0].comment.comment_phid, "B");
+        assert_eq!(threads[0].replies[0].replies[0].comment.comment_phid, "C");
+    }
+
+    #[test]
+    fn inline_threads_keep_reply_cycles() {
+        let (a, b, c) = (inline("A", Some("B")), inline("B", Some("A")), inline("C", None));
+        let threads = build_inline_threads(&[&a, &b, &c]);
+        let roots: Vec<&str> = threads.iter().map(|t| t.comment.comment_phid.as_str()).collect();
+        assert_eq!(roots, ["A", "C"]);
+        assert_eq!(threads[0].replies[0].comment.comment_phid, "B");
+        assert!(threads[0].replies[0].replies.is_empty());
+    }
+
+    #[test]
+    fn cache_key_separates_tokens_without_storing_them() {
+        let alice = PhabricatorCommentExtractor::new("https://phab.example.com".to_string(), "api-alice".to_string(), false);
+        let bob = PhabricatorCommentExtractor::new("https://phab.example.com".to_string(), "api-bob".to_string(), false);
+        let key = |e: &PhabricatorCommentExtractor| {
+            e.cache_key("api/user.search", &[("api.token", e.api_token.as_str()), ("limit", "1")])
+        };
+        assert_ne!(key(&alice), key(&bob));
+        assert!(!key(&alice).contains("api-alice"));
+        assert!(key(&alice).ends_with("&limit=1"));
+    }
+
+    #[test]
+    fn cache_keeps_extracts_but_not_pages() {
+        let path = std::env::temp_dir().join(format!("phab-cache-{}.sqlite", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut extractor = PhabricatorCommentExtractor::new("https://phab.example.com".to_string(), "api-alice".to_string(), false);
+        extractor.response_cache = Some(ResponseCache::open(&path, false).unwrap());
+
+        let api = extractor.cache_key("api/user.search", &[("api.token", "api-alice")]);
+        let refs = extractor.extracted_cache_key(12, "refs", &[("diff", "34")]);
+        // Written by versions that stored the revision page whole
+        let page = extractor.cache_key("D12", &[]);
+        for key in [&api, &refs, &page] {
+            extractor.cache_put(key, "body");
+        }
+        assert!(!refs.contains("api-alice"));
+
+        extractor.response_cache = Some(ResponseCache::open(&path, false).unwrap());
+        assert_eq!(extractor.cache_get(&api, USER_CACHE_TTL).as_deref(), Some("body"));
+        assert_eq!(extractor.cache_get(&refs, USER_CACHE_TTL).as_deref(), Some("body"));
+        assert_eq!(extractor.cache_get(&page, USER_CACHE_TTL), None);
+        drop(extractor);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn user_display_name_prefers_both_names() {
+        let fields = |real: Option<&str>, user: Option<&str>| UserFields {
+            real_name: real.map(str::to_string),
+            username: user.map(str::to_string),
+        };
+        assert_eq!(fields(Some("Alice A"), Some("alice")).display_name().as_deref(), Some("Alice A (alice)"));
+        assert_eq!(fields(Some(""), Some("alice")).display_name().as_deref(), Some("alice"));
+        assert_eq!(fields(Some("Alice A"), None).display_name().as_deref(), Some("Alice A"));
+        assert_eq!(fields(None, Some("")).display_name(), None);
+    }
+
+    #[test]
+    fn suggestion_table_keeps_indentation_and_blank_lines() {
+        let extractor = PhabricatorCommentExtractor::new("https://phab.example.com".to_string(), String::new(), false);
+        let html = Html::parse_fragment(concat!(
+            r#"<div class="inline-suggestion-view"><table>"#,
+            r#"<tr><td class="left old">    if (x) {</td></tr>"#,
+            r#"<tr><td class="left old"></td></tr>"#,
+            r#"<tr><td class="left old"><span data-copy-text="&#9;">    </span>foo();</td></tr>"#,
+            r#"<tr><td class="right new">    if (x) {</td></tr>"#,
+            r#"<tr><td class="right new"><span data-copy-text="&#9;">    </span>bar();</td></tr>"#,
+            "</table></div>"
+        ));
+        let view = html.select(&Selector::parse(".inline-suggestion-view").unwrap()).next().unwrap();
+        let content = extractor.extract_suggestion_from_table(&view).unwrap();
+        assert_eq!(content, "-     if (x) {\n- \n- \tfoo();\n+     if (x) {\n+ \tbar();");
+
+        let (old_lines, new_lines) = parse_suggestion(&content).unwrap();
+        assert_eq!(old_lines, ["    if (x) {", "", "\tfoo();"]);
+        assert_eq!(new_lines, ["    if (x) {", "\tbar();"]);
+    }
+
+    #[test]
+    fn parse_suggestion_keeps_whitespace() {
+        let (old_lines, new_lines) = parse_suggestion("-   a\n-\n+   b\n+ \n+ \tc").unwrap();
+        assert_eq!(old_lines, ["  a", ""]);
+        assert_eq!(new_lines, ["  b", "", "\tc"]);
+
+        let text = "**Suggested changes:**\n\n```diff\n    x = 1;\n\n    y = 2;\n```";
+        let (old_lines, new_lines) = parse_suggestion(text).unwrap();
+        assert!(old_lines.is_empty());
+        assert_eq!(new_lines, ["    x = 1;", "", "    y = 2;"]);
+
+        assert_eq!(parse_suggestion("just a comment"), None);
+    }
+
+    #[test]
+    fn old_lines_must_match_exactly() {
+        let old = vec!["    a();".to_string(), String::new()];
+        assert_eq!(compare_old_lines(&["    a();", ""], &old), OldLinesMatch::Exact);
+        assert_eq!(compare_old_lines(&["a();"], &old), OldLinesMatch::WhitespaceOnly);
+        assert_eq!(compare_old_lines(&["\ta();", ""], &old), OldLinesMatch::WhitespaceOnly);
+        assert_eq!(compare_old_lines(&["    b();", ""], &old), OldLinesMatch::Different);
+    }
+
+    #[test]
+    fn file_patch_keeps_indentation_and_blank_lines() {
+        let lines: HashMap<u32, String> = [
+            (1, "fn f() {"),
+            (2, "    let a = 1;"),
+            (3, ""),
+            (4, "    a"),
+            (5, "}"),
+        ]
+        .into_iter()
+        .map(|(n, l)| (n, l.to_string()))
+        .collect();
+        let edit = LineEdit {
+            start: 2,
+            len: 1,
+            new_lines: vec!["    let a = 2;".to_string(), String::new(), "    let b = 3;".to_string()],
+        };
+        assert_eq!(
+            format_file_patch("src/f.rs", &lines, vec![edit]),
+            [
+                "--- a/src/f.rs",
+                "+++ b/src/f.rs",
+                "@@ -1,5 +1,7 @@",
+                " fn f() {",
+                "-    let a = 1;",
+                "+    let a = 2;",
+                "+",
+                "+    let b = 3;",
+                " ",
+                "     a",
+                " }",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn apply_suggestions_writes_only_exact_matches() {
+        let root = std::env::temp_dir().join(format!("phab-apply-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.c"), "int f() {\n\treturn 1;\n}\n").unwrap();
+        let repo = LocalRepo { root: root.clone(), vcs: Vcs::Git, renames: HashMap::new() };
+
+        let suggestion = |id: &str, line: u32, content: &str| {
+            let mut comment = inline(id, None);
+            comment.file_path = "a.c".to_string();
+            comment.line_number = line;
+            comment.content = content.to_string();
+            comment.is_suggestion = true;
+            comment.local = Some(LocalLocation {
+                path: "a.c".to_string(),
+                line,
+                status: LocalStatus::Matches,
+                file_moved: false,
+            });
+            comment
+        };
+        let comments_data = CommentsData {
+            general_comments: Vec::new(),
+            inline_comments: vec![
+                // Indentation lost: must not be written
+                suggestion("A", 2, "- return 1;\n+ return 2;"),
+                suggestion("B", 2, "- \treturn 1;\n+ \treturn 3;"),
+            ],
+            review_actions: Vec::new(),
+            events: Vec::new(),
+            diffs: Vec::new(),
+            latest_transaction_id: None,
+            revision: None,
+            references: BTreeMap::new(),
+            attachments: BTreeMap::new(),
+        };
+        let reports = repo.apply_suggestions(&[(1, comments_data)], false).unwrap();
+        let content = fs::read_to_string(root.join("a.c")).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert!(matches!(reports[0].outcome, SuggestionOutcome::Unverified(_)));
+        assert!(matches!(reports[1].outcome, SuggestionOutcome::Applied));
+        assert_eq!(content, "int f() {\n\treturn 3;\n}\n");
+    }
+
+    #[test]
+    fn output_flags_are_accepted_after_the_subcommand() {
+        let args = Args::try_parse_from([
+            "phab-comments-to-md",
+            "apply-suggestions",
+            "--diff-id",
+            "12345",
+            "--format",
+            "json",
+            "--output",
+            "out.json",
+            "--context",
+            "3",
+            "--dry-run",
+        ])
+        .unwrap();
+        assert_eq!(args.format, OutputFormat::Json);
+        assert_eq!(args.output.as_deref(), Some("out.json"));
+        assert_eq!(args.context, 3);
+        assert!(matches!(args.command, Some(Command::ApplySuggestions { dry_run: true })));
+    }
+
+    fn remarkup(text: &str) -> String {
+        Remarkup::new("https://phab.example.com/").to_markdown(text)
+    }
+
+    #[test]
+    fn remarkup_code_blocks() {
+        assert_eq!(remarkup("```lang=cpp\nint x;\n```"), "```cpp\nint x;\n```");
+        // Options on the first line inside the fence
+        assert_eq!(remarkup("```\nlang=rust, name=a.rs\nfn f() {}\n```"), "```rust\nfn f() {}\n```");
+        assert_eq!(remarkup("```one line```"), "```\none line\n```");
+        // Backticks inside the code get a longer fence
+        assert_eq!(remarkup("```\n```nested\n```"), "````\n```nested\n````");
+        // Markup inside code is left alone
+        assert_eq!(remarkup("```\n//not italic// @nobody\n```"), "```\n//not italic// @nobody\n```");
+    }
+
+    #[test]
+    fn remarkup_literals_are_escaped() {
+        assert_eq!(remarkup("%%%**not bold** [[x]]%%%"), r"\*\*not bold\*\* \[\[x\]\]");
+        assert_eq!(remarkup("%%%first\nsecond%%%"), "first\nsecond");
+    }
+
+    #[test]
+    fn remarkup_indented_code() {
+        assert_eq!(
+            remarkup("text\n\n  indented\n    more\n\nafter"),
+            "text\n\n```\nindented\n  more\n```\n\nafter"
+        );
+        assert_eq!(remarkup("  lang=py\n  x = 1"), "```py\nx = 1\n```\n");
+        // Only after a blank line
+        assert_eq!(remarkup("text\n  continued"), "text\n  continued");
+    }
+
+    #[test]
+    fn remarkup_headers_and_lists() {
+        assert_eq!(remarkup("= Title ="), "#### Title");
+        assert_eq!(remarkup("== Sub =="), "##### Sub");
+        assert_eq!(remarkup("====== Deep"), "###### Deep");
+        assert_eq!(
+            remarkup("- a\n-- b\n* c\n# one\n## two"),
+            "- a\n  - b\n- c\n1. one\n   1. two"
+        );
+    }
+
+    #[test]
+    fn remarkup_mono_and_code_spans() {
+        assert_eq!(remarkup("use ##mono## and `code //x//`"), "use `mono` and `code //x//`");
+    }
+
+    #[test]
+    fn remarkup_links() {
+        assert_eq!(remarkup("[[https://example.com | Example]]"), "[Example](https://example.com)");
+        assert_eq!(remarkup("[[https://example.com]]"), "<https://example.com>");
+        assert_eq!(remarkup("[[/D123 | rev]]"), "[rev](https://phab.example.com/D123)");
+        assert_eq!(remarkup("[[SomePage]]"), "[SomePage](https://phab.example.com/w/SomePage/)");
+        assert_eq!(remarkup("[[mailto:a@b.c]]"), "<mailto:a@b.c>");
+    }
+
+    #[test]
+    fn remarkup_embeds() {
+        assert_eq!(remarkup("{F123}"), "[F123](https://phab.example.com/F123)");
+        assert_eq!(remarkup("{D45, layout=link}"), "[D45](https://phab.example.com/D45)");
+    }
+
+    #[test]
+    fn remarkup_italics_skip_urls_and_comments() {
+        assert_eq!(remarkup("//italic// text"), "*italic* text");
+        assert_eq!(remarkup("see https://example.com/a//b// c"), "see https://example.com/a//b// c");
+        assert_eq!(remarkup("x // y // z"), "x // y // z");
+        assert_eq!(remarkup("path //foo/bar// x"), "path //foo/bar// x");
+        assert_eq!(remarkup("!!loud!!"), "**loud**");
+    }
+
+    #[test]
+    fn remarkup_mentions_notices_and_quotes() {
+        assert_eq!(
+            remarkup("@alice and a@b.com and (@bob)"),
+            "[@alice](https://phab.example.com/p/alice/) and a@b.com and ([@bob](https://phab.example.com/p/bob/))"
+        );
+        assert_eq!(remarkup("NOTE: careful"), "**NOTE:** careful");
+        assert_eq!(remarkup("(WARNING) hot"), "**WARNING:** hot");
+        assert_eq!(
+            remarkup(">>! In D1#2, @carol wrote:\n> quoted"),
+            "> *In D1#2, [@carol](https://phab.example.com/p/carol/) wrote:*\n> quoted"
+        );
+    }
+
+    #[test]
+    fn raw_diff_parses_files_and_hunks() {
+        let diff = [
+            "diff --git a/src/a.rs b/src/a.rs",
+            "--- a/src/a.rs",
+            "+++ b/src/a.rs",
+            "@@ -1,3 +1,3 @@",
+            " one",
+            "--- two",
+            "+++ two",
+            " three",
+            "diff --git a/new.txt b/new.txt",
+            "--- /dev/null",
+            "+++ b/new.txt\t(date)",
+            "@@ -0,0 +1 @@",
+            "+hello",
+            "\\ No newline at end of file",
+        ]
+        .join("\n");
+        let files = parse_raw_diff(&diff);
+        assert_eq!(files.len(), 2);
+
+        // Body lines starting with ---/+++ are not file headers
+        assert_eq!(files[0].old_path.as_deref(), Some("src/a.rs"));
+        assert_eq!(files[0].hunks[0].lines, [" one", "--- two", "+++ two", " three"]);
+        let (old_lines, new_lines) = files[0].line_maps();
+        assert_eq!(old_lines[&2], "-- two");
+        assert_eq!(new_lines[&2], "++ two");
+        assert_eq!(new_lines[&3], "three");
+
+        assert_eq!(files[1].old_path, None);
+        assert_eq!(files[1].new_path.as_deref(), Some("new.txt"));
+        assert!(files[1].matches_path("new.txt"));
+        let hunk = &files[1].hunks[0];
+        assert_eq!((hunk.old_start, hunk.old_len, hunk.new_start, hunk.new_len), (0, 0, 1, 1));
+        assert_eq!(hunk.lines, ["+hello"]);
+    }
+
+    #[test]
+    fn since_accepts_every_format() {
+        assert!(matches!(parse_since("1700000000"), Ok(SinceSpec::Timestamp(1700000000))));
+        assert!(matches!(parse_since("2024-01-02"), Ok(SinceSpec::Timestamp(1704153600))));
+        assert!(matches!(parse_since("2024-01-02T01:00:00+01:00"), Ok(SinceSpec::Timestamp(1704153600))));
+        assert!(matches!(parse_since(" diff:42 "), Ok(SinceSpec::Diff(42))));
+        assert!(parse_since("diff:abc").is_err());
+        assert!(parse_since("yesterday").is_err());
+    }
+
+    #[test]
+    fn arcrc_hosts_match_on_the_api_endpoint() {
+        let arcrc = |json: &str| serde_json::from_str::<ArcRc>(json).unwrap();
+        let hosts = r#"{"hosts": {"https://Phab.Example.com/api/": {"token": "api-1"}, "https://other.example.com/api/": {"token": ""}}}"#;
+        assert_eq!(arcrc(hosts).token_for("https://phab.example.com").as_deref(), Some("api-1"));
+        assert_eq!(arcrc(hosts).token_for("https://phab.example.com/").as_deref(), Some("api-1"));
+        assert_eq!(arcrc(hosts).token_for("https://phab.example.com/api").as_deref(), Some("api-1"));
+        // Empty tokens and other hosts do not count
+        assert_eq!(arcrc(hosts).token_for("https://other.example.com"), None);
+        assert_eq!(arcrc(hosts).token_for("https://phab.example.org"), None);
+        assert_eq!(arcrc("{}").token_for("https://phab.example.com"), None);
+    }
+
+    #[test]
+    fn line_alignment_ports_ranges() {
+        let lines = |text: &[(u32, &str)]| -> HashMap<u32, String> {
+            text.iter().map(|(n, t)| (*n, t.to_string())).collect()
+        };
+        // Two lines inserted above, "c" removed
+        let from = lines(&[(1, "a"), (2, "b"), (3, "c"), (4, "d")]);
+        let to = lines(&[(1, "x"), (2, "y"), (3, "a"), (4, "b"), (5, "d")]);
+        let alignment = LineAlignment::new(&from, &to);
+
+        assert_eq!(alignment.port(2, 1), PortedRange::Moved { line_number: 4, line_length: 1 });
+        assert_eq!(alignment.port(1, 2), PortedRange::Moved { line_number: 3, line_length: 2 });
+        assert_eq!(alignment.port(3, 1), PortedRange::Deleted);
+        // Outside what the diffs show
+        assert_eq!(alignment.port(40, 1), PortedRange::Unknown);
+        // Bogus server values must not overflow
+        assert_eq!(alignment.port(u32::MAX, u32::MAX), PortedRange::Unknown);
+    }
+
+    #[test]
+    fn remarkup_never_nests_links() {
+        let mut r = Remarkup::new("https://phab.example.com");
+        let reference = |uri: &str, title: &str| ObjectReference {
+            uri: uri.to_string(),
+            type_name: "Differential Revision".to_string(),
+            title: Some(title.to_string()),
+        };
+        r.references.insert("D123".to_string(), reference("https://phab.example.com/D123", "Fix [bug] (again)"));
+        r.references.insert("D45".to_string(), reference("https://phab.example.com/D45", "Back out D123"));
+
+        // Labels of explicit links stay plain text
+        assert_eq!(r.to_markdown("[[/foo | see D123]]"), "[see D123](https://phab.example.com/foo)");
+        assert_eq!(r.to_markdown("[[/foo | ask @alice]]"), "[ask @alice](https://phab.example.com/foo)");
+        // Titles are escaped, and the monograms in them are not expanded again
+        assert_eq!(
+            r.to_markdown("D123#4"),
+            r"[D123#4](https://phab.example.com/D123#4) (Fix \[bug\] \(again\))"
+        );
+        assert_eq!(
+            r.to_markdown("{D45} and D45"),
+            "[D45](https://phab.example.com/D45) (Back out D123) and [D45](https://phab.example.com/D45) (Back out D123)"
+        );
+    }
+
+    #[test]
+    fn attachment_names_are_escaped() {
+        let attachment = |name: &str, link: &str| Attachment {
+            name: name.to_string(),
+            path: PathBuf::from(link),
+            link: link.to_string(),
+        };
+        assert_eq!(
+            attachment("shot [1].png", "files/shot.png").to_markdown("F7"),
+            r"![F7: shot \[1\].png](files/shot.png)"
+        );
+        assert_eq!(
+            attachment("log (old).txt", "my files/log.txt").to_markdown("F8"),
+            r"[F8: log \(old\).txt](<my files/log.txt>)"
+        );
+    }
+
+    #[test]
+    fn changeset_refs_fall_back_per_path() {
+        let state = RevisionChangesets {
+            revision_id: 1,
+            refs: vec!["10".to_string(), "11".to_string(), "12".to_string()],
+            paths: HashMap::from([
+                ("10".to_string(), vec!["a.rs".to_string()]),
+                ("11".to_string(), vec!["b.rs".to_string()]),
+                ("12".to_string(), vec!["renamed.rs".to_string()]),
+            ]),
+            payloads: HashMap::new(),
+        };
+        assert_eq!(state.wanted_refs(&["b.rs"]), ["11"]);
+        assert_eq!(state.wanted_refs(&["a.rs", "b.rs"]), ["10", "11"]);
+        // One path matches, the other was renamed: every ref is needed
+        assert_eq!(state.wanted_refs(&["a.rs", "old.rs"]), ["10", "11", "12"]);
+    }
+
+    #[test]
+    fn reviewer_events_name_projects_and_users() {
+        let fields = serde_json::json!({
+            "operations": [
+                {"operation": "add", "phid": "PHID-PROJ-abc", "isBlocking": true},
+                {"operation": "add", "phid": "PHID-USER-alice", "isBlocking": false},
+                {"operation": "remove", "phid": "PHID-PROJ-unknown"},
+            ]
+        });
+        assert_eq!(
+            EventKind::reviewer_phids(&fields),
+            ["PHID-PROJ-abc", "PHID-USER-alice", "PHID-PROJ-unknown"]
+        );
+        let names = HashMap::from([
+            ("PHID-PROJ-abc".to_string(), "dom-core-reviewers".to_string()),
+            ("PHID-USER-alice".to_string(), "Alice A (alice)".to_string()),
+        ]);
+        let Some(EventKind::Reviewers { added, removed }) = EventKind::from_reviewer_operations(&fields, &names) else {
+            panic!("expected a reviewers event");
+        };
+        assert_eq!(added, ["dom-core-reviewers (blocking)", "Alice A (alice)"]);
+        assert_eq!(removed, ["PHID-PROJ-unknown"]);
+        assert!(EventKind::from_reviewer_operations(&serde_json::Value::Null, &names).is_none());
+    }
+
+    #[test]
+    fn done_marks_are_planned_before_posting() {
+        let data = |comments: Vec<InlineComment>| CommentsData {
+            general_comments: Vec::new(),
+            inline_comments: comments,
+            review_actions: Vec::new(),
+            events: Vec::new(),
+            diffs: Vec::new(),
+            latest_transaction_id: None,
+            revision: None,
+            references: BTreeMap::new(),
+            attachments: BTreeMap::new(),
+        };
+        let mut resolved = inline("3", None);
+        resolved.is_done = true;
+        let extracted = [
+            (12, data(vec![inline("1", None), inline("2", None)])),
+            (34, data(vec![resolved])),
+        ];
+        let ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+
+        let plan = DoneMarkPlan::new(&extracted, &ids(&["2", "3", "1"]), true).unwrap();
+        let toggled: Vec<(u32, &str)> =
+            plan.toggles.iter().map(|(revision, c)| (*revision, c.comment_id.as_str())).collect();
+        assert_eq!(toggled, [(12, "2"), (12, "1")]);
+        assert_eq!(plan.unchanged.len(), 1);
+        assert_eq!(plan.revisions, [12]);
+
+        let plan = DoneMarkPlan::new(&extracted, &ids(&["3"]), false).unwrap();
+        assert_eq!(plan.revisions, [34]);
+        assert!(DoneMarkPlan::new(&extracted, &ids(&["9"]), true).is_err());
+
+        // Other drafts get published too: only with explicit consent
+        assert!(check_draft_publishing(&[12], false, false).is_ok());
+        assert!(check_draft_publishing(&[12], true, false).is_err());
+        assert!(check_draft_publishing(&[12], true, true).is_ok());
+        assert!(check_draft_publishing(&[], true, false).is_ok());
+    }
+}